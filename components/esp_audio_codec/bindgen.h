@@ -0,0 +1,5 @@
+#include "esp_audio_types.h"
+#include "esp_audio_enc.h"
+#include "esp_opus_enc.h"
+#include "esp_audio_dec.h"
+#include "esp_opus_dec.h"
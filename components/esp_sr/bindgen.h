@@ -1,3 +1,6 @@
 #include "esp_afe_sr_models.h"
+#include "esp_mn_iface.h"
+#include "esp_mn_models.h"
+#include "esp_mn_speech_commands.h"
 
 // void esp_afe_sr_init();
\ No newline at end of file
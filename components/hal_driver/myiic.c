@@ -42,4 +42,26 @@ esp_err_t myiic_init(void)
     ESP_ERROR_CHECK(i2c_new_master_bus(&i2c_bus_config, &bus_handle));
 
     return ESP_OK;
-}
\ No newline at end of file
+}
+
+/**
+ * @brief       探测总线上是否有指定地址的设备
+ * @param       address: 从机7位的地址
+ * @retval      ESP_OK:设备存在
+ */
+esp_err_t myiic_probe(uint16_t address)
+{
+    return i2c_master_probe(bus_handle, address, 50);
+}
+
+/**
+ * @brief       释放MYIIC总线
+ * @param       无
+ * @retval      ESP_OK:释放成功
+ */
+esp_err_t myiic_deinit(void)
+{
+    esp_err_t ret = i2c_del_master_bus(bus_handle);
+    bus_handle = NULL;
+    return ret;
+}
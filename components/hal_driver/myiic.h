@@ -36,5 +36,7 @@ extern i2c_master_bus_handle_t bus_handle; /* 总线句柄 */
 
 /* 函数声明 */
 esp_err_t myiic_init(void); /* 初始化MYIIC */
+esp_err_t myiic_probe(uint16_t address); /* 探测设备 */
+esp_err_t myiic_deinit(void); /* 释放MYIIC */
 
 #endif
@@ -4,6 +4,7 @@ use std::sync::Arc;
 use esp_idf_svc::hal::i2s::{config, I2sDriver};
 use esp_idf_svc::io::asynch::Read;
 
+use echokit::aec::EchoReference;
 use echokit::audio::{AFEResult, AFE};
 const SAMPLE_RATE: u32 = 16000;
 const PORT_TICK_PERIOD_MS: u32 = 1000 / esp_idf_svc::sys::configTICK_RATE_HZ;
@@ -20,7 +21,8 @@ fn main() -> anyhow::Result<()> {
     log::info!(
         ".....................................VAD EXAMPLE....................................."
     );
-    let afe_handle = Arc::new(AFE::new());
+    // 空字符串表示使用 model 分区里的第一个唤醒词模型
+    let afe_handle = Arc::new(AFE::new(""));
     let afe_handle_1 = afe_handle.clone();
     let i2s_record_task = async move {
         log::info!("record task started");
@@ -45,6 +47,9 @@ fn main() -> anyhow::Result<()> {
         rx_driver.rx_enable().unwrap();
         // prepare a buffer for voice data - 5s under the sample rate
         let mut buffer = vec![0u8; 5 * SAMPLE_RATE as usize * 2];
+        // AFE 的输入是 "MR" 交织格式, 这个例子没有播放, 参考信号全部为 0
+        let mut echo = EchoReference::new(0);
+        let mut feed_buffer = Vec::with_capacity(buffer.len() * 2);
 
         loop {
             // rx_driver.read_async(&mut buffer).await.unwrap();
@@ -56,7 +61,8 @@ fn main() -> anyhow::Result<()> {
                     "................AFE FEED {i}.... buffer empty = {}",
                     buffer.is_empty()
                 );
-                afe_handle.feed(&buffer[..n]);
+                echo.interleave(&buffer[..n], &mut feed_buffer);
+                afe_handle.feed(&feed_buffer);
             }
             log::info!("................AFE FEED DONE................");
             // afe_handle.feed(&buffer);
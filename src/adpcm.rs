@@ -0,0 +1,136 @@
+// IMA ADPCM 解码, 用于 server 下发的 ADPCM 音频
+// 每个 chunk 是一个完整的 block, 与 WAV 的 IMA ADPCM mono block 格式一致:
+// - 4 字节 header: 初始采样值(i16 le), step index(u8), 保留(u8)
+// - 之后每个字节包含两个 4bit 采样, 低 4 位在前
+
+const INDEX_TABLE: [i32; 16] = [-1, -1, -1, -1, 2, 4, 6, 8, -1, -1, -1, -1, 2, 4, 6, 8];
+
+const STEP_TABLE: [i32; 89] = [
+    7, 8, 9, 10, 11, 12, 13, 14, 16, 17, 19, 21, 23, 25, 28, 31, 34, 37, 41, 45, 50, 55, 60, 66,
+    73, 80, 88, 97, 107, 118, 130, 143, 157, 173, 190, 209, 230, 253, 279, 307, 337, 371, 408, 449,
+    494, 544, 598, 658, 724, 796, 876, 963, 1060, 1166, 1282, 1411, 1552, 1707, 1878, 2066, 2272,
+    2499, 2749, 3024, 3327, 3660, 4026, 4428, 4871, 5358, 5894, 6484, 7132, 7845, 8630, 9493,
+    10442, 11487, 12635, 13899, 15289, 16818, 18500, 20350, 22385, 24623, 27086, 29794, 32767,
+];
+
+pub const HEADER_SIZE: usize = 4;
+
+struct State {
+    predictor: i32,
+    index: i32,
+}
+
+impl State {
+    fn decode(&mut self, nibble: u8) -> i16 {
+        let step = STEP_TABLE[self.index as usize];
+        let mut diff = step >> 3;
+        if nibble & 4 != 0 {
+            diff += step;
+        }
+        if nibble & 2 != 0 {
+            diff += step >> 1;
+        }
+        if nibble & 1 != 0 {
+            diff += step >> 2;
+        }
+        if nibble & 8 != 0 {
+            self.predictor -= diff;
+        } else {
+            self.predictor += diff;
+        }
+        self.predictor = self.predictor.clamp(i16::MIN as i32, i16::MAX as i32);
+        self.index = (self.index + INDEX_TABLE[nibble as usize]).clamp(0, 88);
+        self.predictor as i16
+    }
+}
+
+// 解码一个 block, 返回 16bit le PCM
+pub fn decode_block(block: &[u8]) -> anyhow::Result<Vec<u8>> {
+    if block.len() < HEADER_SIZE {
+        anyhow::bail!("ADPCM block too short: {} bytes", block.len());
+    }
+    let predictor = i16::from_le_bytes([block[0], block[1]]);
+    let index = block[2];
+    if index > 88 {
+        anyhow::bail!("Invalid ADPCM step index: {}", index);
+    }
+
+    let mut state = State {
+        predictor: predictor as i32,
+        index: index as i32,
+    };
+    let data = &block[HEADER_SIZE..];
+    let mut pcm = Vec::with_capacity((1 + data.len() * 2) * 2);
+    pcm.extend_from_slice(&predictor.to_le_bytes());
+    for b in data {
+        pcm.extend_from_slice(&state.decode(b & 0x0f).to_le_bytes());
+        pcm.extend_from_slice(&state.decode(b >> 4).to_le_bytes());
+    }
+    Ok(pcm)
+}
+
+#[cfg(test)]
+fn encode_block(samples: &[i16]) -> Vec<u8> {
+    let mut state = State {
+        predictor: samples[0] as i32,
+        index: 0,
+    };
+    let mut block = vec![];
+    block.extend_from_slice(&samples[0].to_le_bytes());
+    block.push(0);
+    block.push(0);
+
+    let mut nibbles = vec![];
+    for &sample in &samples[1..] {
+        let step = STEP_TABLE[state.index as usize];
+        let mut diff = sample as i32 - state.predictor;
+        let mut nibble = 0;
+        if diff < 0 {
+            nibble = 8;
+            diff = -diff;
+        }
+        if diff >= step {
+            nibble |= 4;
+            diff -= step;
+        }
+        if diff >= step >> 1 {
+            nibble |= 2;
+            diff -= step >> 1;
+        }
+        if diff >= step >> 2 {
+            nibble |= 1;
+        }
+        // 与解码器保持相同的状态
+        state.decode(nibble);
+        nibbles.push(nibble);
+    }
+    for pair in nibbles.chunks(2) {
+        block.push(pair[0] | (pair.get(1).unwrap_or(&0) << 4));
+    }
+    block
+}
+
+#[test]
+fn test_adpcm_decode() {
+    // 1kHz 正弦波, 16kHz 采样
+    let samples: Vec<i16> = (0..321)
+        .map(|i| ((i as f32 * 2.0 * std::f32::consts::PI / 16.0).sin() * 10000.0) as i16)
+        .collect();
+    let block = encode_block(&samples);
+    assert_eq!(block.len(), HEADER_SIZE + 160);
+
+    let pcm = decode_block(&block).unwrap();
+    assert_eq!(pcm.len(), samples.len() * 2);
+    let decoded: Vec<i16> = pcm
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect();
+    assert_eq!(decoded[0], samples[0]);
+    // 跳过 step 自适应的前几个采样
+    for (a, b) in decoded.iter().zip(samples.iter()).skip(32) {
+        assert!((*a as i32 - *b as i32).abs() < 1000, "{} vs {}", a, b);
+    }
+
+    assert!(decode_block(&[0, 0]).is_err());
+    assert!(decode_block(&[0, 0, 89, 0]).is_err());
+}
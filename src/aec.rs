@@ -0,0 +1,81 @@
+use std::collections::VecDeque;
+
+// AFE 回声消除的参考信号
+// 播放器写入 i2s 的每个采样同时记录到 queued 里, 它模拟 i2s 发送队列(DMA)里还没有播出的数据
+// 每次读取麦克风时, 按照读到的采样数从 queued 取出同样多的参考采样, 即这段时间里扬声器播出的数据
+// 发送队列为空时扬声器输出静音(auto_clear), 参考信号也补 0
+// 麦克风和扬声器的硬件延迟不同, 参考信号再经过一个固定长度的延迟线对齐
+pub struct EchoReference {
+    queued: VecDeque<i16>,
+    delay: VecDeque<i16>,
+}
+
+impl EchoReference {
+    pub fn new(delay_samples: usize) -> Self {
+        Self {
+            queued: VecDeque::new(),
+            delay: VecDeque::from(vec![0; delay_samples]),
+        }
+    }
+
+    // 发送队列里还没有播出的采样数
+    pub fn queued(&self) -> usize {
+        self.queued.len()
+    }
+
+    // 记录写入 i2s 的 16bit le PCM
+    pub fn push(&mut self, pcm: &[u8]) {
+        self.queued.extend(
+            pcm.chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]])),
+        );
+    }
+
+    // 将麦克风数据和对应的参考信号交织成 AFE "MR" 格式的输入
+    pub fn interleave(&mut self, mic: &[u8], out: &mut Vec<u8>) {
+        out.clear();
+        for b in mic.chunks_exact(2) {
+            let played = self.queued.pop_front().unwrap_or(0);
+            self.delay.push_back(played);
+            let reference = self.delay.pop_front().unwrap_or(0);
+            out.extend_from_slice(b);
+            out.extend_from_slice(&reference.to_le_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+fn pcm(samples: &[i16]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+}
+
+#[test]
+fn test_echo_reference() {
+    let mut echo = EchoReference::new(0);
+    let mut out = vec![];
+
+    // 没有播放时参考信号为 0
+    echo.interleave(&pcm(&[1, 2]), &mut out);
+    assert_eq!(out, pcm(&[1, 0, 2, 0]));
+
+    // 参考信号按照播出的顺序与麦克风采样一一对应, 不足时补 0
+    echo.push(&pcm(&[10, 20, 30]));
+    assert_eq!(echo.queued(), 3);
+    echo.interleave(&pcm(&[1, 2]), &mut out);
+    assert_eq!(out, pcm(&[1, 10, 2, 20]));
+    echo.interleave(&pcm(&[3, 4]), &mut out);
+    assert_eq!(out, pcm(&[3, 30, 4, 0]));
+    assert_eq!(echo.queued(), 0);
+}
+
+#[test]
+fn test_echo_reference_delay() {
+    let mut echo = EchoReference::new(2);
+    let mut out = vec![];
+
+    echo.push(&pcm(&[10, 20, 30]));
+    echo.interleave(&pcm(&[1, 2, 3]), &mut out);
+    assert_eq!(out, pcm(&[1, 0, 2, 0, 3, 10]));
+    echo.interleave(&pcm(&[4, 5, 6]), &mut out);
+    assert_eq!(out, pcm(&[4, 20, 5, 30, 6, 0]));
+}
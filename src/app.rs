@@ -1,413 +1,221 @@
 use tokio::sync::mpsc;
-use tokio_websockets::Message;
 
 use crate::{
-    audio::{self, AudioData},
-    protocol::ServerEvent,
-    ws::Server,
+    assets::AssetStore,
+    audio,
+    codec::EspCodecs,
+    conversation::{self, Conversation, State},
+    executor::{Executor, Rejected},
+    jitter::JitterConfig,
+    ws::ReconnectingServer,
 };
 
-#[derive(Debug)]
-pub enum Event {
-    Event(&'static str),
-    ServerEvent(ServerEvent),
-    MicAudioChunk(Vec<u8>),
-    MicAudioEnd,
-}
+pub use crate::conversation::Event;
 
-#[allow(dead_code)]
-impl Event {
-    pub const GAIA: &'static str = "gaia";
-    pub const NO: &'static str = "no";
-    pub const YES: &'static str = "yes";
-    pub const NOISE: &'static str = "noise";
-    pub const RESET: &'static str = "reset";
-    pub const UNKNOWN: &'static str = "unknown";
-    pub const K0: &'static str = "k0";
-    pub const K0_: &'static str = "k0_";
+// 断线期间麦克风音频的处理策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MicPolicy {
+    // 直接丢弃, 重连后回到 Idle
+    Discard,
+    // 最多缓存 n 字节, 重连后补发给 server, 超出的部分丢弃
+    Buffer(usize),
+}
 
-    pub const K1: &'static str = "k1";
-    pub const K2: &'static str = "k2";
+#[derive(Debug, Clone)]
+pub struct Config {
+    // 断线期间麦克风音频的处理策略
+    pub mic_policy: MicPolicy,
+    // 上行 Opus 的帧长(ms), 可选 10/20/40/60
+    pub opus_frame_ms: u32,
+    // 上行 Opus 的码率(bps)
+    pub opus_bitrate: i32,
+    // 对话状态机的参数
+    pub conversation: conversation::Config,
 }
-// 监听 evt_rx(from 麦克风) 和 server(from服务器) 的事件
-async fn select_evt(evt_rx: &mut mpsc::Receiver<Event>, server: &mut Server) -> Option<Event> {
-    tokio::select! {
-        Some(evt) = evt_rx.recv() => {
-            match &evt {
-                Event::Event(_)=>{
-                    log::info!("Received event: {:?}", evt);
-                },
-                Event::MicAudioEnd=>{
-                    log::info!("Received MicAudioEnd");
-                },
-                Event::MicAudioChunk(data)=>{
-                    log::debug!("Received MicAudioChunk with {} bytes", data.len());
-                },
-                Event::ServerEvent(_)=>{
-                    log::info!("Received ServerEvent: {:?}", evt);
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            // 断线期间最多缓存 10s 的麦克风音频, 重连后补发
+            mic_policy: MicPolicy::Buffer(10 * 32000),
+            opus_frame_ms: 20,
+            opus_bitrate: 24000,
+            conversation: conversation::Config {
+                jitter: JitterConfig {
+                    sample_rate: audio::SAMPLE_RATE,
+                    ..Default::default()
                 },
-            }
-            Some(evt)
-        }
-        Ok(msg) = server.recv() => {
-            match msg {
-                Event::ServerEvent(ServerEvent::AudioChunk { .. })=>{
-                    log::info!("Received AudioChunk");
-                }
-                Event::ServerEvent(ServerEvent::HelloChunk { .. })=>{
-                    log::info!("Received HelloChunk");
-                }
-                Event::ServerEvent(ServerEvent::BGChunk { .. })=>{
-                    log::info!("Received BGChunk");
-                }
-                _=> {
-                    log::info!("Received message: {:?}", msg);
-                }
-            }
-            Some(msg)
-        }
-        else => {
-            log::info!("No events");
-            None
+                ..Default::default()
+            },
         }
     }
 }
-// 每次 mic 采集完成后, 判断是否超过 30s, 如果超过, 则更新need_compute为 true
-// 当server 发送本轮 StartAudio 时, 如果 need_compute 为 true, reset metrics
-// 在 server 发送 AudioChunk 过程中, 如果 need_compute为 true, metrics 统计数据长度
-//  - 如果当前speed < 1, 直接播放音频片段
-//  - 如果当前speed > 1, 缓存音频片段到 audio_buffer 里
-// 当server 发送 EndAudio 时, 如果 need_compute为 true, metrics 计算 speed, 重置 need_compute
-//  - 如果 speed > 1, 则播放缓存的 audio_buffer
-struct DownloadMetrics {
-    start_time: std::time::Instant,
-    data_size: usize,
-    timeout_sec: u64,
+
+// 断线期间缓存的麦克风音频
+struct PendingMic {
+    policy: MicPolicy,
+    // 断线时是否正在收音, 只有正在收音时才需要缓存
+    active: bool,
+    data: Vec<u8>,
+    // 断线期间是否已经收到 MicAudioEnd
+    ended: bool,
 }
 
-impl DownloadMetrics {
-    fn new() -> Self {
+impl PendingMic {
+    fn new(policy: MicPolicy) -> Self {
         Self {
-            start_time: std::time::Instant::now() - std::time::Duration::from_secs(300),
-            data_size: 0,
-            timeout_sec: 30,
+            policy,
+            active: false,
+            data: Vec::new(),
+            ended: false,
         }
     }
 
-    fn is_timeout(&self) -> bool {
-        self.start_time.elapsed().as_secs() > self.timeout_sec
+    fn start(&mut self, active: bool, data: Vec<u8>) {
+        self.active = active && self.policy != MicPolicy::Discard;
+        self.data.clear();
+        self.ended = false;
+        if self.active {
+            self.push(&data);
+        }
     }
-    fn reset(&mut self) {
-        self.start_time = std::time::Instant::now();
-        self.data_size = 0;
+
+    fn push(&mut self, data: &[u8]) {
+        if let MicPolicy::Buffer(max) = self.policy {
+            if self.active && !self.ended {
+                let n = data.len().min(max.saturating_sub(self.data.len()));
+                self.data.extend_from_slice(&data[..n]);
+            }
+        }
     }
 
-    fn add_data(&mut self, size: usize) {
-        self.data_size += size;
+    // 重连期间收到的事件, 只处理麦克风相关的, 其他事件直接丢弃
+    fn on_event(&mut self, evt: Event) {
+        match evt {
+            Event::MicAudioChunk(data) => self.push(&data),
+            Event::MicAudioEnd => self.ended = self.active,
+            evt => log::warn!("Drop event while reconnecting: {:?}", evt),
+        }
     }
+}
 
-    fn elapsed(&self) -> std::time::Duration {
-        self.start_time.elapsed()
+// 按照退避策略不断尝试重连, 直到成功或超过最大次数
+// 重连期间继续消费 evt_rx, 避免 afe_worker 阻塞
+async fn reconnect(
+    server: &mut ReconnectingServer,
+    evt_rx: &mut mpsc::Receiver<Event>,
+    gui: &mut crate::ui::UI,
+    pending: &mut PendingMic,
+) -> anyhow::Result<()> {
+    loop {
+        let attempt = server.backoff.attempt() + 1;
+        if attempt > server.max_attempts {
+            anyhow::bail!("Failed to reconnect after {} attempts", server.max_attempts);
+        }
+        gui.state = format!("Reconnecting ({})...", attempt);
+        gui.display_flush().unwrap();
+
+        let delay = server.backoff.next_delay();
+        log::info!("Reconnecting ({}) in {:?}", attempt, delay);
+        let r = {
+            let fut = async {
+                tokio::time::sleep(delay).await;
+                server.reconnect().await
+            };
+            tokio::pin!(fut);
+            loop {
+                tokio::select! {
+                    r = &mut fut => break r,
+                    Some(evt) = evt_rx.recv() => pending.on_event(evt),
+                }
+            }
+        };
+        match r {
+            Ok(()) => {
+                log::info!("Reconnected after {} attempts", attempt);
+                return Ok(());
+            }
+            Err(e) => log::warn!("Reconnect failed: {:?}", e),
+        }
     }
+}
 
-    // 如果 speed > 1.0, 表示从接收数据开始到现在的耗时, 大于音频的长度
-    // 如果 speed < 1.0, 表示从接收数据开始到现在的耗时, 小于音频的长度
-    fn speed(&self) -> f64 {
-        // 先取出自从上次重置计时(即开始接收server audio时), 到本次调用时的秒数
-        // data_size / 32k 代表本次音频的时间长度
-        self.elapsed().as_secs_f64() / ((self.data_size as f64) / 32000.0)
+// 等待 K0 按下, 期间丢弃麦克风等其他事件
+async fn wait_k0(evt_rx: &mut mpsc::Receiver<Event>) {
+    while let Some(evt) = evt_rx.recv().await {
+        if matches!(evt, Event::Event(Event::K0)) {
+            break;
+        }
     }
 }
 
-// TODO: 按键打断
-// TODO: 超时不监听
 pub async fn main_work<'d>(
-    mut server: Server,
+    server: ReconnectingServer,
     player_tx: audio::PlayerTx,
     mut evt_rx: mpsc::Receiver<Event>,
     backgroud_buffer: Option<&'d [u8]>,
+    config: Config,
+    save_volume: impl FnMut(u8) + 'static,
+    // assets 分区挂载失败时为 None, 不保存 server 下发的背景图
+    assets: Option<AssetStore>,
 ) -> anyhow::Result<()> {
-    #[derive(PartialEq, Eq)]
-    enum State {
-        Listening,
-        Recording,
-        Wait,
-        Speaking,
-        Idle,
-    }
     // 创建新的 gui 实例, 并刷新背景图
     let mut gui = crate::ui::UI::new(backgroud_buffer)?;
     gui.state = "Idle".to_string();
     gui.display_flush().unwrap();
 
-    let mut new_gui_bg = vec![];
-
-    let mut state = State::Idle;
-
-    let mut submit_audio = 0.0;
-
-    let mut audio_buffer = Vec::with_capacity(8192);
+    let mut conv = Conversation::new(config.conversation.clone());
+    let mut pending = PendingMic::new(config.mic_policy);
+    let codecs = EspCodecs {
+        opus_frame_ms: config.opus_frame_ms,
+        opus_bitrate: config.opus_bitrate,
+    };
+    let mut exec = Executor::new(server, player_tx, gui, codecs, save_volume, assets)?;
 
-    let mut metrics = DownloadMetrics::new();
-    let mut need_compute = true;
-    let mut speed = 0.8;
     //循环监听 evt_rx 和 server
-    while let Some(evt) = select_evt(&mut evt_rx, &mut server).await {
-        match evt {
-            // 如果是 gaia 或 k0 事件,
-            Event::Event(Event::GAIA | Event::K0) => {
-                log::info!("Received event: gaia");
-                // gui.state = "gaia".to_string();
-                // gui.display_flush().unwrap();
-                // 如果状态是Listening
-                if state == State::Listening {
-                    state = State::Idle; //切换至Idle
-                    gui.state = "Idle".to_string();
-                    gui.display_flush().unwrap(); //刷新 gui
-                } else {
-                    // 创建 oneshot 的channel
-                    let (tx, rx) = tokio::sync::oneshot::channel();
-                    // 将这个 tx channel, 使用 AudioData::Hello封装, 然后通过player_tx发送出去
-                    player_tx
-                        .send(AudioData::Hello(tx))
-                        .map_err(|e| anyhow::anyhow!("Error sending hello: {e:?}"))?;
-                    log::info!("Waiting for hello response");
-                    // 接收到 tx 的代码, 需要回复点什么, 当作 ack
-                    let _ = rx.await;
-                    log::info!("Hello response received");
-                    // 更新为 Listening 状态
-                    state = State::Listening;
-                    gui.state = "Listening...".to_string();
-                    gui.display_flush().unwrap();
-                }
-            }
-            // 如果是按键松开事件
-            Event::Event(Event::K0_) => {
-                // 如果是Idle 或 Listening 状态
-                if state == State::Idle || state == State::Listening {
-                    log::info!("Received event: K0_");
-                    state = State::Recording; //更新为Recording状态
-                    gui.state = "Recording...".to_string();
-                    gui.text = String::new();
-                    gui.display_flush().unwrap(); //刷新 gui
+    loop {
+        let idle_deadline = conv
+            .idle_deadline(std::time::Instant::now())
+            .map(tokio::time::Instant::from_std);
+        let evt = match exec.next_event(&mut evt_rx, idle_deadline).await {
+            Ok(Some(evt)) => evt,
+            Ok(None) => break,
+            Err(e) => {
+                if let Some(dead) = e.downcast_ref::<crate::ws::LinkDead>() {
+                    log::error!("Server link dead: {}, rtt: {:?}", dead, exec.server.rtt());
+                    exec.gui.state = "Server not responding".to_string();
+                    exec.gui.display_flush().unwrap();
                 } else {
-                    log::warn!("Received K0_ while not idle");
-                }
-            }
-            // 这几个 Event 类型暂不作任何处理
-            Event::Event(Event::RESET | Event::K2) => {}
-            Event::Event(Event::YES | Event::K1) => {}
-            Event::Event(Event::NO) => {}
-            Event::Event(evt) => {
-                log::info!("Received event: {:?}", evt);
-            }
-            // 如果是 mic 的音频数据段
-            Event::MicAudioChunk(data) => {
-                // 确保是 Listening 或 Recording 状态
-                if state == State::Listening || state == State::Recording {
-                    // 这里的比例 32000.0 是基于音频采样率和格式计算得出的：
-                    // 采样率为 16000Hz（每秒16000个样本）
-                    // 每个样本占用2字节（16位音频）
-                    // 因此，每秒的音频数据大小为：16000 × 2 = 32000字节
-                    // 所以， data.len() as f32 / 32000.0 计算的是当前音频数据块代表的时间长度（秒）。
-                    submit_audio += data.len() as f32 / 32000.0;
-                    // 将收到的数据填充到 audio_buffer 中
-                    audio_buffer.extend_from_slice(&data);
-                    // 如果 buffer 长度足够
-                    if audio_buffer.len() >= 8192 {
-                        // 将数据封装进 Message::binary 后发送给 sever
-                        server
-                            .send(Message::binary(bytes::Bytes::from(audio_buffer)))
-                            .await?;
-                        // 然后清空 buffer
-                        audio_buffer = Vec::with_capacity(8192);
-                    }
-                } else {
-                    log::debug!("Received MicAudioChunk while not listening");
-                }
-            }
-            // 如果是 mic 音频结束事件
-            Event::MicAudioEnd => {
-                // 确保是 Listening 或 Recording 状态, 且submit_audio 已经累计超过 1.0s
-                // 则认为是一个有效的 mic 结束事件
-                if (state == State::Listening || state == State::Recording) && submit_audio > 1.0 {
-                    // 如果 buffer 不为空, 发送给 server
-                    if !audio_buffer.is_empty() {
-                        server
-                            .send(Message::binary(bytes::Bytes::from(audio_buffer)))
-                            .await?;
-                        audio_buffer = Vec::with_capacity(8192);
-                    }
-                    // 如果当前状态是 Listening, 则向 srever 发送End:Normal
-                    if state == State::Listening {
-                        server.send(Message::text("End:Normal")).await?;
-                    } else {
-                        // 如果是其他状态, 则向 server 发送 End:Recording
-                        server.send(Message::text("End:Recording")).await?;
-                    }
-                    // 记录是否超时30s
-                    // 如果本次 mic 采集已经超过 30s, 则认为是超时
-                    // 这将导致 audio 播放时重新计算 speed
-                    need_compute = metrics.is_timeout();
-                }
-                submit_audio = 0.0;
-            }
-            // 收到 server 的 ASR, 刷新到 gui
-            Event::ServerEvent(ServerEvent::ASR { text }) => {
-                log::info!("Received ASR: {:?}", text);
-                gui.state = "ASR".to_string();
-                gui.text = text.trim().to_string();
-                gui.display_flush().unwrap();
-            }
-            // 收到 server 的 Action(预留给语音指令?), 刷新到 gui
-            Event::ServerEvent(ServerEvent::Action { action }) => {
-                log::info!("Received action");
-                gui.state = format!("Action: {}", action);
-                gui.display_flush().unwrap();
-            }
-            // 收到 server 的 StartAudio, 刷新到 gui
-            Event::ServerEvent(ServerEvent::StartAudio { text }) => {
-                // 第一次运行一定是 true
-                if need_compute {
-                    //重置计时和数据长度
-                    metrics.reset();
-                }
-                log::info!("Received audio start: {:?}", text);
-                state = State::Speaking; //更新为 Speaking
-                gui.state = format!("[{:.2}x]|Speaking...", speed);
-                gui.text = text.trim().to_string();
-                gui.display_flush().unwrap();
-                // 通过 player_tx 发送 Start 事件
-                player_tx
-                    .send(AudioData::Start)
-                    .map_err(|e| anyhow::anyhow!("Error sending start: {e:?}"))?;
-            }
-            // 收到 server 的 AudioChunk, 刷新到 gui
-            Event::ServerEvent(ServerEvent::AudioChunk { data }) => {
-                log::info!("Received audio chunk");
-                // 确保是 Speaking 状态, 否则进入下一轮
-                if state != State::Speaking {
-                    log::warn!("Received audio chunk while not speaking");
-                    continue;
-                }
-                // 记录本次数据的长度到metrics
-                if need_compute {
-                    metrics.add_data(data.len());
-                }
-                // 如果当前 speed 小于1.0, 则直接发送音频数据(给扬声器播放)
-                // 否则将数据缓存到 audio_buffer 里
-                if speed < 1.0 {
-                    // 如果失败, 要刷新 gui 提示
-                    if let Err(e) = player_tx.send(AudioData::Chunk(data)) {
-                        log::error!("Error sending audio chunk: {:?}", e);
-                        gui.state = "Error on audio chunk".to_string();
-                        gui.display_flush().unwrap();
-                    }
-                } else {
-                    audio_buffer.extend_from_slice(&data);
-                }
-            }
-            // 收到 server 的 EndAudio, 刷新到 gui
-            Event::ServerEvent(ServerEvent::EndAudio) => {
-                log::info!("Received audio end");
-
-                if need_compute {
-                    // 本次 server 传过来的音频数据已经结束, 计算 speed
-                    // 即从 server 传输的 audio 开始到此刻的耗时, 和本次数据长度(秒数)
-                    // 作一个比值, 即是所谓的 speed
-                    speed = metrics.speed();
-                    // 计算完成后, 更新 need_compute 为 false
-                    // 直至下一次mic采集超过 30s
-                    need_compute = false;
-                }
-
-                log::info!("Audio speed: {:.2}x", speed);
-                // 如果当前 speed > 1.0, 通过之前缓存的 audio_buffer 播放
-                if speed > 1.0 && audio_buffer.len() > 0 {
-                    if let Err(e) = player_tx.send(AudioData::Chunk(audio_buffer)) {
-                        log::error!("Error sending audio chunk: {:?}", e);
-                        gui.state = "Error on audio chunk".to_string();
-                        gui.display_flush().unwrap();
-                    }
-                    // 清空 audio_buffer
-                    audio_buffer = Vec::with_capacity(8192);
-                }
-                // 如法炮制, 发送 End 事件, 等待扬声器线程回复一个 ack(播放完成)
-                let (tx, rx) = tokio::sync::oneshot::channel();
-                if let Err(e) = player_tx.send(AudioData::End(tx)) {
-                    log::error!("Error sending audio chunk: {:?}", e);
-                    gui.state = "Error on audio chunk".to_string();
-                    gui.display_flush().unwrap();
-                }
-                //等待 ack
-                let _ = rx.await;
-                gui.display_flush().unwrap();
-            }
-            // 收到 server 的 EndResponse, 刷新到 gui
-            Event::ServerEvent(ServerEvent::EndResponse) => {
-                log::info!("Received request end");
-                state = State::Listening;
-                gui.state = "Listening...".to_string();
-                gui.display_flush().unwrap();
-            }
-            // 以下是 hello 相关的分支
-            Event::ServerEvent(ServerEvent::HelloStart) => {
-                if let Err(_) = player_tx.send(AudioData::SetHelloStart) {
-                    log::error!("Error sending hello start");
-                    gui.state = "Error on hello start".to_string();
-                    gui.display_flush().unwrap();
-                }
-            }
-            Event::ServerEvent(ServerEvent::HelloChunk { data }) => {
-                log::info!("Received hello chunk");
-                if let Err(_) = player_tx.send(AudioData::SetHelloChunk(data.to_vec())) {
-                    log::error!("Error sending hello chunk");
-                    gui.state = "Error on hello chunk".to_string();
-                    gui.display_flush().unwrap();
-                }
-            }
-            Event::ServerEvent(ServerEvent::HelloEnd) => {
-                log::info!("Received hello end");
-                if let Err(_) = player_tx.send(AudioData::SetHelloEnd) {
-                    log::error!("Error sending hello end");
-                    gui.state = "Error on hello end".to_string();
-                    gui.display_flush().unwrap();
-                } else {
-                    gui.state = "Hello set".to_string();
-                    gui.display_flush().unwrap();
-                }
-            }
-            // 以下是背景图片相关的分支
-            Event::ServerEvent(ServerEvent::BGStart) => {
-                new_gui_bg = vec![];
-            }
-            Event::ServerEvent(ServerEvent::BGChunk { data }) => {
-                log::info!("Received background chunk");
-                new_gui_bg.extend(data);
-            }
-            Event::ServerEvent(ServerEvent::BGEnd) => {
-                log::info!("Received background end");
-                if !new_gui_bg.is_empty() {
-                    let gui_ = crate::ui::UI::new(Some(&new_gui_bg));
-                    new_gui_bg.clear();
-                    match gui_ {
-                        Ok(new_gui) => {
-                            gui = new_gui;
-                            gui.state = "Background data loaded".to_string();
-                            gui.display_flush().unwrap();
-                        }
-                        Err(e) => {
-                            log::error!("Error creating GUI from background data: {:?}", e);
-                            gui.state = "Error on background data".to_string();
-                            gui.display_flush().unwrap();
-                        }
-                    }
-                } else {
-                    log::warn!("Received empty background data");
-                }
-            }
-            // 预留给video
-            Event::ServerEvent(ServerEvent::StartVideo | ServerEvent::EndVideo) => {}
+                    log::error!("Server link lost: {:?}", e);
+                }
+                let (effects, audio) = conv.link_lost();
+                exec.run(effects).await?;
+                exec.playback = None;
+                // 正在收音时, 按照策略缓存断线期间的音频
+                pending.start(conv.is_capturing(), audio);
+                reconnect(&mut exec.server, &mut evt_rx, &mut exec.gui, &mut pending).await?;
+
+                // 重连成功, 新的连接可能协商出不同的 codec
+                exec.reconnected();
+                let resend = (pending.active && !pending.data.is_empty())
+                    .then(|| (std::mem::take(&mut pending.data), pending.ended));
+                pending.start(false, vec![]);
+                let effects = conv.reconnected(resend);
+                exec.run(effects).await?;
+                continue;
+            }
+        };
+        // 下行音频先解码为 PCM, 再交给状态机
+        let Some(evt) = exec.decode(evt, conv.state() == State::Speaking) else {
+            continue;
+        };
+        let effects = conv.handle(evt, std::time::Instant::now());
+        if let Err(e) = exec.run(effects).await {
+            // 与握手失败一样, 等待按键后再重启, 避免被拒绝后不断重启
+            if e.is::<Rejected>() {
+                wait_k0(&mut evt_rx).await;
+            }
+            return Err(e);
         }
     }
 
@@ -0,0 +1,397 @@
+// 背景图, 音效, 字体等资源文件, 保存在单独的 assets 分区(SPIFFS)里
+// 每个资源是分区根目录下的一个文件, manifest.json 记录资源的名字, 类型, 长度和 CRC32
+// 宿主机上的测试直接使用普通目录
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::player::MAX_HELLO_SIZE;
+use crate::store::{crc32, BlobStore, BACKGROUND_KEY, HELLO_KEY};
+
+// partitions.csv 里的分区名和挂载点
+pub const PARTITION_LABEL: &str = "assets";
+pub const MOUNT_POINT: &str = "/assets";
+
+// 当前使用的背景图
+pub const BACKGROUND_ASSET: &str = "background.gif";
+// server 下发的 hello 音效, 已经转换为播放器的 PCM
+pub const HELLO_ASSET: &str = "hello.pcm";
+
+// SPIFFS 的文件名(包括开头的 '/')最长 31 字节, 还要留出临时文件的后缀
+pub const MAX_NAME_LEN: usize = 24;
+
+const MANIFEST: &str = "manifest.json";
+const MANIFEST_VERSION: u32 = 1;
+// 写入时先写到临时文件, 完成后再替换
+const TMP_SUFFIX: &str = ".tmp";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AssetKind {
+    Background,
+    Sound,
+    Font,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AssetInfo {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: AssetKind,
+    pub size: u32,
+    // 文件内容的 CRC32, 读取时校验
+    pub hash: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+    assets: Vec<AssetInfo>,
+}
+
+// clone 出来的副本共享同一份 manifest, 可以在 BLE 配置和 main_work 里同时使用
+#[derive(Clone)]
+pub struct AssetStore {
+    root: PathBuf,
+    manifest: Arc<Mutex<Vec<AssetInfo>>>,
+}
+
+impl AssetStore {
+    // 读取 root 下的 manifest, 丢弃文件已经不存在或者长度不对的记录
+    pub fn open(root: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let root = root.into();
+        let path = root.join(MANIFEST);
+        let tmp = root.join(format!("{MANIFEST}{TMP_SUFFIX}"));
+        // 替换 manifest 时先删除旧文件再改名, 中间断电只剩下临时文件
+        let data = match std::fs::read(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => match std::fs::read(&tmp) {
+                Err(e) if e.kind() == ErrorKind::NotFound => None,
+                r => Some(r?),
+            },
+            r => Some(r?),
+        };
+
+        let mut assets = vec![];
+        if let Some(data) = data {
+            match serde_json::from_slice::<Manifest>(&data) {
+                Ok(manifest) if manifest.version == MANIFEST_VERSION => {
+                    assets = manifest.assets;
+                }
+                Ok(manifest) => {
+                    log::warn!("Unsupported asset manifest version {}", manifest.version);
+                }
+                Err(e) => log::error!("Invalid asset manifest: {:?}", e),
+            }
+        }
+
+        let count = assets.len();
+        assets.retain(|info| {
+            let ok = std::fs::metadata(root.join(&info.name))
+                .is_ok_and(|meta| meta.len() == info.size as u64);
+            if !ok {
+                log::warn!("Asset {} is missing or truncated", info.name);
+            }
+            ok
+        });
+
+        let store = Self {
+            root,
+            manifest: Arc::new(Mutex::new(assets)),
+        };
+        if store.manifest.lock().unwrap().len() != count {
+            store.write_manifest(&store.manifest.lock().unwrap())?;
+        }
+        Ok(store)
+    }
+
+    pub fn list(&self) -> Vec<AssetInfo> {
+        self.manifest.lock().unwrap().clone()
+    }
+
+    pub fn info(&self, name: &str) -> Option<AssetInfo> {
+        let manifest = self.manifest.lock().unwrap();
+        manifest.iter().find(|info| info.name == name).cloned()
+    }
+
+    // 读取资源并检查长度和 CRC32, 不存在时返回 None
+    pub fn get(&self, name: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(info) = self.info(name) else {
+            return Ok(None);
+        };
+        let data = std::fs::read(self.root.join(name))?;
+        if data.len() != info.size as usize {
+            anyhow::bail!(
+                "Asset {} is truncated: {} of {} bytes",
+                name,
+                data.len(),
+                info.size
+            );
+        }
+        if crc32(&data) != info.hash {
+            anyhow::bail!("Asset {} checksum mismatch", name);
+        }
+        Ok(Some(data))
+    }
+
+    // 添加或替换资源, 写入完成后才更新 manifest
+    pub fn put(&self, name: &str, kind: AssetKind, data: &[u8]) -> anyhow::Result<()> {
+        check_name(name)?;
+        let mut manifest = self.manifest.lock().unwrap();
+
+        let path = self.root.join(name);
+        let tmp = self.root.join(format!("{name}{TMP_SUFFIX}"));
+        if let Err(e) = std::fs::write(&tmp, data) {
+            let _ = std::fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        replace(&tmp, &path)?;
+
+        let info = AssetInfo {
+            name: name.to_string(),
+            kind,
+            size: data.len() as u32,
+            hash: crc32(data),
+        };
+        match manifest.iter_mut().find(|old| old.name == name) {
+            Some(old) => *old = info,
+            None => manifest.push(info),
+        }
+        self.write_manifest(&manifest)
+    }
+
+    // 删除资源, 返回资源是否存在
+    pub fn delete(&self, name: &str) -> anyhow::Result<bool> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let Some(i) = manifest.iter().position(|info| info.name == name) else {
+            return Ok(false);
+        };
+        manifest.remove(i);
+        self.write_manifest(&manifest)?;
+        match std::fs::remove_file(self.root.join(name)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(true),
+        }
+    }
+
+    fn write_manifest(&self, assets: &[AssetInfo]) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(&Manifest {
+            version: MANIFEST_VERSION,
+            assets: assets.to_vec(),
+        })?;
+        let tmp = self.root.join(format!("{MANIFEST}{TMP_SUFFIX}"));
+        std::fs::write(&tmp, data)?;
+        replace(&tmp, &self.root.join(MANIFEST))
+    }
+}
+
+// SPIFFS 的 rename 不能覆盖已经存在的文件, 只能先删除
+fn replace(tmp: &Path, path: &Path) -> anyhow::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+// SPIFFS 没有目录, 名字只能是一层的文件名
+fn check_name(name: &str) -> anyhow::Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('.')
+        && !name.ends_with(TMP_SUFFIX)
+        && name != MANIFEST
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b));
+    if !valid {
+        anyhow::bail!("Invalid asset name: {:?}", name);
+    }
+    Ok(())
+}
+
+// 旧版本把背景图保存在 NVS 里, 第一次启动时移动到 assets 分区
+// 写入 assets 成功后才删除 NVS 里的数据, 返回是否迁移了背景图
+pub fn migrate_background(nvs: &mut impl BlobStore, assets: &AssetStore) -> anyhow::Result<bool> {
+    let Some(data) = nvs.get(BACKGROUND_KEY)? else {
+        return Ok(false);
+    };
+    if !data.is_empty() && assets.info(BACKGROUND_ASSET).is_none() {
+        assets.put(BACKGROUND_ASSET, AssetKind::Background, &data)?;
+    }
+    nvs.remove(BACKGROUND_KEY)?;
+    Ok(!data.is_empty())
+}
+
+// 旧版本把 hello 音效(带校验头)保存在 NVS 里, 第一次启动时移动到 assets 分区
+// 校验失败的数据不会再被使用, 直接删除, 返回是否迁移了 hello 音效
+pub fn migrate_hello(nvs: &mut impl BlobStore, assets: &AssetStore) -> anyhow::Result<bool> {
+    let data = match nvs.load(HELLO_KEY, MAX_HELLO_SIZE) {
+        Ok(Some(data)) => Some(data),
+        Ok(None) => return Ok(false),
+        Err(e) => {
+            log::warn!("Drop invalid hello audio in NVS: {:?}", e);
+            None
+        }
+    };
+    if let Some(data) = &data {
+        if assets.info(HELLO_ASSET).is_none() {
+            assets.put(HELLO_ASSET, AssetKind::Sound, data)?;
+        }
+    }
+    nvs.remove(HELLO_KEY)?;
+    Ok(data.is_some())
+}
+
+// 挂载 assets 分区, 第一次使用(或者分区损坏)时会格式化, 需要几十秒
+// 格式化之前调用 on_format, 用来在屏幕上提示用户
+#[cfg(target_os = "espidf")]
+pub fn mount(on_format: impl FnOnce()) -> anyhow::Result<()> {
+    use esp_idf_svc::sys;
+
+    let base_path = std::ffi::CString::new(MOUNT_POINT)?;
+    let partition_label = std::ffi::CString::new(PARTITION_LABEL)?;
+    let mut conf = sys::esp_vfs_spiffs_conf_t {
+        base_path: base_path.as_ptr(),
+        partition_label: partition_label.as_ptr(),
+        max_files: 4,
+        format_if_mount_failed: false,
+    };
+    if let Err(e) = sys::esp!(unsafe { sys::esp_vfs_spiffs_register(&conf) }) {
+        log::warn!("Failed to mount assets partition, formatting: {:?}", e);
+        on_format();
+        conf.format_if_mount_failed = true;
+        sys::esp!(unsafe { sys::esp_vfs_spiffs_register(&conf) })?;
+    }
+
+    let (mut total, mut used) = (0, 0);
+    sys::esp!(unsafe { sys::esp_spiffs_info(conf.partition_label, &mut total, &mut used) })?;
+    log::info!("Assets partition: {} of {} bytes used", used, total);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("echokit-assets-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn test_assets_put_get_delete() {
+    let dir = test_dir("put");
+    let assets = AssetStore::open(&dir).unwrap();
+    assert!(assets.list().is_empty());
+    assert_eq!(assets.get("hello.wav").unwrap(), None);
+
+    assets.put("hello.wav", AssetKind::Sound, b"RIFF").unwrap();
+    assets
+        .put("theme-1.gif", AssetKind::Background, b"GIF89a")
+        .unwrap();
+    assets.put("hello.wav", AssetKind::Sound, b"RIFF1").unwrap();
+    assert_eq!(assets.get("hello.wav").unwrap(), Some(b"RIFF1".to_vec()));
+
+    // 重新打开后 manifest 不变
+    let assets = AssetStore::open(&dir).unwrap();
+    let list = assets.list();
+    assert_eq!(list.len(), 2);
+    assert_eq!(
+        list[0],
+        AssetInfo {
+            name: "hello.wav".to_string(),
+            kind: AssetKind::Sound,
+            size: 5,
+            hash: crc32(b"RIFF1"),
+        }
+    );
+
+    assert!(assets.delete("hello.wav").unwrap());
+    assert!(!assets.delete("hello.wav").unwrap());
+    assert!(!dir.join("hello.wav").exists());
+    assert_eq!(AssetStore::open(&dir).unwrap().list().len(), 1);
+
+    for name in [
+        "",
+        "a/b",
+        ".tmp",
+        "a.gif.tmp",
+        "manifest.json",
+        "x".repeat(25).as_str(),
+    ] {
+        assert!(assets.put(name, AssetKind::Font, b"").is_err(), "{name:?}");
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_assets_corrupted() {
+    let dir = test_dir("corrupted");
+    let assets = AssetStore::open(&dir).unwrap();
+    assets
+        .put("a.gif", AssetKind::Background, b"GIF89a")
+        .unwrap();
+    assets
+        .put("b.gif", AssetKind::Background, b"GIF89a")
+        .unwrap();
+
+    // 内容损坏时 get 返回错误, 文件丢失时打开时丢弃记录
+    std::fs::write(dir.join("a.gif"), b"GIF87a").unwrap();
+    std::fs::remove_file(dir.join("b.gif")).unwrap();
+    let assets = AssetStore::open(&dir).unwrap();
+    assert!(assets.get("a.gif").is_err());
+    assert_eq!(assets.info("b.gif"), None);
+
+    // 替换 manifest 时断电, 只剩下临时文件
+    std::fs::rename(dir.join(MANIFEST), dir.join("manifest.json.tmp")).unwrap();
+    assert_eq!(AssetStore::open(&dir).unwrap().list().len(), 1);
+
+    // manifest 损坏时当作空的
+    std::fs::write(dir.join(MANIFEST), b"{").unwrap();
+    assert!(AssetStore::open(&dir).unwrap().list().is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_assets_migrate_background() {
+    let dir = test_dir("migrate");
+    let assets = AssetStore::open(&dir).unwrap();
+    let mut nvs = crate::store::MemoryStore::default();
+    assert!(!migrate_background(&mut nvs, &assets).unwrap());
+
+    nvs.set(BACKGROUND_KEY, b"GIF89a").unwrap();
+    assert!(migrate_background(&mut nvs, &assets).unwrap());
+    assert_eq!(nvs.get(BACKGROUND_KEY).unwrap(), None);
+    assert_eq!(
+        assets.get(BACKGROUND_ASSET).unwrap(),
+        Some(b"GIF89a".to_vec())
+    );
+    assert_eq!(
+        assets.info(BACKGROUND_ASSET).unwrap().kind,
+        AssetKind::Background
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_assets_migrate_hello() {
+    let dir = test_dir("migrate-hello");
+    let assets = AssetStore::open(&dir).unwrap();
+    let mut nvs = crate::store::MemoryStore::default();
+    assert!(!migrate_hello(&mut nvs, &assets).unwrap());
+
+    nvs.save(HELLO_KEY, &[1; 100]).unwrap();
+    assert!(migrate_hello(&mut nvs, &assets).unwrap());
+    assert_eq!(nvs.get(HELLO_KEY).unwrap(), None);
+    assert_eq!(assets.get(HELLO_ASSET).unwrap(), Some(vec![1; 100]));
+    assert_eq!(assets.info(HELLO_ASSET).unwrap().kind, AssetKind::Sound);
+
+    // 损坏的数据只删除, 不覆盖已有的音效
+    nvs.set(HELLO_KEY, b"EKB1 corrupted").unwrap();
+    assert!(!migrate_hello(&mut nvs, &assets).unwrap());
+    assert_eq!(nvs.get(HELLO_KEY).unwrap(), None);
+    assert_eq!(assets.get(HELLO_ASSET).unwrap(), Some(vec![1; 100]));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
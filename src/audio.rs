@@ -1,28 +1,64 @@
 use std::sync::Arc;
 
 use esp_idf_svc::hal::gpio::AnyIOPin;
-use esp_idf_svc::hal::i2s::{config, I2sDriver, I2S0, I2S1};
+use esp_idf_svc::hal::i2s::{config, I2sBiDir, I2sDriver, I2sRx, I2sTx, I2S0, I2S1};
 
 use esp_idf_svc::sys::esp_sr;
 
-const SAMPLE_RATE: u32 = 16000;
+use crate::board::{AudioPins, Codec};
+use crate::hal::{pin, AudioDevice};
+use crate::volume;
+
 const PORT_TICK_PERIOD_MS: u32 = 1000 / esp_idf_svc::sys::configTICK_RATE_HZ;
 
-unsafe fn afe_init() -> (
+// 从 model 分区里查找唤醒词模型, wake_word 为空时使用分区里的第一个 WakeNet 模型
+unsafe fn find_wakenet(
+    models: *mut esp_sr::srmodel_list_t,
+    wake_word: &str,
+) -> Option<*mut std::ffi::c_char> {
+    let c_keyword = std::ffi::CString::new(wake_word).ok()?;
+    let keyword = if wake_word.is_empty() {
+        std::ptr::null()
+    } else {
+        c_keyword.as_ptr()
+    };
+    let name = esp_sr::esp_srmodel_filter(models, "wn\0".as_ptr() as *const _, keyword);
+    if name.is_null() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+unsafe fn afe_init(
+    models: *mut esp_sr::srmodel_list_t,
+    wake_word: &str,
+) -> (
     *mut esp_sr::esp_afe_sr_iface_t,
     *mut esp_sr::esp_afe_sr_data_t,
 ) {
-    let models = esp_sr::esp_srmodel_init("model\0".as_ptr() as *const _);
+    // 有唤醒词模型时使用 SR 类型的 AFE, VC 类型不支持 WakeNet
+    let wakenet = find_wakenet(models, wake_word);
+    let afe_type = if wakenet.is_some() {
+        esp_sr::afe_type_t_AFE_TYPE_SR
+    } else {
+        log::warn!(
+            "WakeNet model {:?} not found, wake word disabled",
+            wake_word
+        );
+        esp_sr::afe_type_t_AFE_TYPE_VC
+    };
     let afe_config = esp_sr::afe_config_init(
-        "M\0".as_ptr() as _,
+        "MR\0".as_ptr() as _,
         models,
-        esp_sr::afe_type_t_AFE_TYPE_VC,
+        afe_type,
         esp_sr::afe_mode_t_AFE_MODE_HIGH_PERF,
     );
     let afe_config = afe_config.as_mut().unwrap();
-    afe_config.pcm_config.total_ch_num = 1;
+    // 一路麦克风, 一路播放器的参考信号, 用于回声消除
+    afe_config.pcm_config.total_ch_num = 2;
     afe_config.pcm_config.mic_num = 1;
-    afe_config.pcm_config.ref_num = 0;
+    afe_config.pcm_config.ref_num = 1;
     afe_config.pcm_config.sample_rate = 16000;
     afe_config.afe_ringbuf_size = 25;
 
@@ -37,6 +73,19 @@ unsafe fn afe_init() -> (
     // 模式值越大，语音触发概率越高
     afe_config.vad_mode = esp_sr::vad_mode_t_VAD_MODE_1;
     afe_config.agc_init = true;
+    afe_config.aec_init = true;
+
+    if let Some(name) = wakenet {
+        log::info!(
+            "WakeNet model: {:?}",
+            std::ffi::CStr::from_ptr(name).to_string_lossy()
+        );
+        afe_config.wakenet_init = true;
+        afe_config.wakenet_model_name = name;
+        afe_config.wakenet_mode = esp_sr::det_mode_t_DET_MODE_95;
+    } else {
+        afe_config.wakenet_init = false;
+    }
 
     log::info!("{afe_config:?}");
 
@@ -56,6 +105,8 @@ unsafe fn afe_init() -> (
 pub struct AFE {
     handle: *mut esp_sr::esp_afe_sr_iface_t,
     data: *mut esp_sr::esp_afe_sr_data_t,
+    // model 分区里的模型列表, MultiNet 也从这里加载
+    models: *mut esp_sr::srmodel_list_t,
     #[allow(unused)]
     feed_chunksize: usize,
 }
@@ -65,23 +116,36 @@ unsafe impl Sync for AFE {}
 
 pub struct AFEResult {
     pub data: Vec<u8>,
+    // data 开头来自 vad 缓存的字节数, 这部分之前已经 fetch 过
+    pub cache_len: usize,
     pub speech: bool,
+    // WakeNet 检测到了唤醒词
+    pub wakeup: bool,
 }
 
 impl AFE {
-    pub fn new() -> Self {
+    pub fn new(wake_word: &str) -> Self {
         unsafe {
-            let (handle, data) = afe_init();
+            let models = esp_sr::esp_srmodel_init("model\0".as_ptr() as *const _);
+            let (handle, data) = afe_init(models, wake_word);
             let feed_chunksize =
                 (handle.as_mut().unwrap().get_feed_chunksize.unwrap())(data) as usize;
 
             AFE {
                 handle,
                 data,
+                models,
                 feed_chunksize,
             }
         }
     }
+
+    // 创建离线命令词识别, model 分区里没有 MultiNet 模型时返回 None
+    pub fn command_recognizer(&self) -> Option<CommandRecognizer> {
+        unsafe { CommandRecognizer::new(self.models) }
+            .map_err(|e| log::warn!("MultiNet disabled: {:?}", e))
+            .ok()
+    }
     // returns the number of bytes fed
 
     // 禁用AFE的vad状态
@@ -114,7 +178,7 @@ impl AFE {
         }
     }
 
-    // 通过ffi操作, 向afe输入音频数据
+    // 通过ffi操作, 向afe输入音频数据, 格式为麦克风和参考信号交织("MR")
     pub fn feed(&self, data: &[u8]) -> i32 {
         let afe_handle = self.handle;
         let afe_data = self.data;
@@ -161,73 +225,132 @@ impl AFE {
             };
             // 判断vad状态是否为语音中
             let speech = vad_state == esp_sr::vad_state_t_VAD_SPEECH;
-            // 返回数据和vad状态
-            Ok(AFEResult { data, speech })
+            // 判断是否检测到唤醒词
+            let wakeup = result.wakeup_state == esp_sr::wakenet_state_t_WAKENET_DETECTED;
+            // 返回数据, vad状态和唤醒状态
+            Ok(AFEResult {
+                data,
+                cache_len: result.vad_cache_size.max(0) as usize,
+                speech,
+                wakeup,
+            })
         }
     }
 }
 
-pub static WAKE_WAV: &[u8] = include_bytes!("../assets/hello_beep.wav");
-
-pub enum AudioData {
-    Hello(tokio::sync::oneshot::Sender<()>),
-    SetHelloStart,
-    SetHelloChunk(Vec<u8>),
-    SetHelloEnd,
-    Start,
-    Chunk(Vec<u8>),
-    End(tokio::sync::oneshot::Sender<()>),
+// 离线命令词, command_id 从 1 开始, 与 COMMANDS 的下标对应
+const COMMANDS: &[(&str, &str)] = &[
+    ("yes", crate::app::Event::YES),
+    ("no", crate::app::Event::NO),
+    ("reset", crate::app::Event::RESET),
+    ("volume up", crate::app::Event::VOLUME_UP),
+    ("volume down", crate::app::Event::VOLUME_DOWN),
+];
+
+// 通过 esp-sr MultiNet 识别 COMMANDS 里的命令词, 输入是 AFE 处理后的音频
+pub struct CommandRecognizer {
+    handle: *const esp_sr::esp_mn_iface_t,
+    data: *mut esp_sr::model_iface_data_t,
+    chunksize: usize,
+    // 不足一个 chunk 的数据, 留到下一次识别
+    pending: Vec<u8>,
 }
 
-pub type PlayerTx = tokio::sync::mpsc::UnboundedSender<AudioData>;
-pub type PlayerRx = tokio::sync::mpsc::UnboundedReceiver<AudioData>;
-pub type MicTx = tokio::sync::mpsc::Sender<crate::app::Event>;
+unsafe impl Send for CommandRecognizer {}
+
+impl CommandRecognizer {
+    unsafe fn new(models: *mut esp_sr::srmodel_list_t) -> anyhow::Result<Self> {
+        let name = esp_sr::esp_srmodel_filter(
+            models,
+            "mn\0".as_ptr() as *const _,
+            "en\0".as_ptr() as *const _,
+        );
+        if name.is_null() {
+            anyhow::bail!("MultiNet model not found");
+        }
+        let handle = esp_sr::esp_mn_handle_from_name(name);
+        let handle_ref = handle
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Failed to get MultiNet handle"))?;
+        // 超时时间(ms), 超时后重新开始识别
+        let data = (handle_ref.create.unwrap())(name, 6000);
+        if data.is_null() {
+            anyhow::bail!("Failed to create MultiNet");
+        }
+        let chunksize = (handle_ref.get_samp_chunksize.unwrap())(data) as usize * 2;
 
-pub async fn i2s_task_(
-    i2s: I2S0,
-    ws: AnyIOPin,
-    sck: AnyIOPin,
-    din: AnyIOPin,
-    i2s1: I2S1,
-    bclk: AnyIOPin,
-    lrclk: AnyIOPin,
-    dout: AnyIOPin,
-    (tx, rx): (MicTx, PlayerRx),
-) {
-    // 使用arc封装AFE数据结构(通过ffi)
-    let afe_handle = Arc::new(AFE::new());
-    // clone 一个供线程使用
-    let afe_handle_ = afe_handle.clone();
-    // 启动一个线程, 该线程负责接收处理过的语音数据和vad状态, 并通过channel发送出去
-    let afe_r = std::thread::spawn(|| afe_worker(afe_handle_, tx));
-    // i2s player也是一个死循环, 通过i2s采集音频数据, 并喂给AFE处理, 并接收AFE的处理结果
-    let r = i2s_player_(i2s, ws, sck, din, i2s1, bclk, lrclk, dout, afe_handle, rx).await;
-    if let Err(e) = r {
-        log::error!("Error: {}", e);
-    } else {
-        log::info!("I2S test completed successfully");
+        esp_sr::esp_mn_commands_alloc(handle, data);
+        esp_sr::esp_mn_commands_clear();
+        for (i, (phrase, _)) in COMMANDS.iter().enumerate() {
+            let phrase = std::ffi::CString::new(*phrase)?;
+            esp_sr::esp_mn_commands_add(i as i32 + 1, phrase.as_ptr() as *mut _);
+        }
+        let err = esp_sr::esp_mn_commands_update();
+        if !err.is_null() {
+            log::warn!("Some MultiNet commands are invalid");
+        }
+        log::info!(
+            "MultiNet model: {:?}, chunksize: {}",
+            std::ffi::CStr::from_ptr(name).to_string_lossy(),
+            chunksize
+        );
+
+        Ok(Self {
+            handle,
+            data,
+            chunksize,
+            pending: Vec::with_capacity(chunksize),
+        })
     }
-    let r = afe_r.join().unwrap();
-    if let Err(e) = r {
-        log::error!("Error: {}", e);
-    } else {
-        log::info!("AFE worker completed successfully");
+
+    // 输入 AFE 的输出, 识别到命令词时返回对应的 Event
+    pub fn detect(&mut self, data: &[u8]) -> Option<&'static str> {
+        self.pending.extend_from_slice(data);
+        let mut command = None;
+        let mut offset = 0;
+        while self.pending.len() - offset >= self.chunksize {
+            let chunk = &self.pending[offset..offset + self.chunksize];
+            offset += self.chunksize;
+            unsafe {
+                let handle = self.handle.as_ref().unwrap();
+                let state = (handle.detect.unwrap())(self.data, chunk.as_ptr() as *mut i16);
+                if state == esp_sr::esp_mn_state_t_ESP_MN_STATE_DETECTED {
+                    let results = (handle.get_results.unwrap())(self.data).as_ref().unwrap();
+                    if results.num > 0 {
+                        let id = results.command_id[0] as usize;
+                        log::info!(
+                            "MultiNet command {} detected, prob: {}",
+                            id,
+                            results.prob[0]
+                        );
+                        command = COMMANDS.get(id.wrapping_sub(1)).map(|(_, evt)| *evt);
+                    }
+                    (handle.clean.unwrap())(self.data);
+                } else if state == esp_sr::esp_mn_state_t_ESP_MN_STATE_TIMEOUT {
+                    (handle.clean.unwrap())(self.data);
+                }
+            }
+        }
+        self.pending.drain(..offset);
+        command
     }
 }
 
-async fn i2s_player_(
-    i2s: I2S0,
-    ws: AnyIOPin,
-    sck: AnyIOPin,
-    din: AnyIOPin,
-    i2s1: I2S1,
-    bclk: AnyIOPin,
-    lrclk: AnyIOPin,
-    dout: AnyIOPin,
-    afe_handle: Arc<AFE>,
-    mut rx: PlayerRx,
-) -> anyhow::Result<()> {
-    let i2s_config = config::StdConfig::new(
+impl Drop for CommandRecognizer {
+    fn drop(&mut self) {
+        unsafe {
+            let handle = self.handle.as_ref().unwrap();
+            (handle.destroy.unwrap())(self.data);
+        }
+    }
+}
+
+pub use crate::player::{AudioData, PlayerRx, PlayerTx, SAMPLE_RATE};
+
+pub type MicTx = tokio::sync::mpsc::Sender<crate::app::Event>;
+
+fn i2s_config() -> config::StdConfig {
+    config::StdConfig::new(
         config::Config::default().auto_clear(true),
         config::StdClkConfig::from_sample_rate_hz(SAMPLE_RATE),
         config::StdSlotConfig::philips_slot_default(
@@ -235,123 +358,156 @@ async fn i2s_player_(
             config::SlotMode::Mono,
         ),
         config::StdGpioConfig::default(),
-    );
-    // 创建i2s RX TX
-    let mclk: Option<esp_idf_svc::hal::gpio::AnyIOPin> = None;
-    let mut rx_driver = I2sDriver::new_std_rx(i2s, &i2s_config, sck, din, mclk, ws).unwrap();
-    rx_driver.rx_enable()?;
-
-    let mclk: Option<esp_idf_svc::hal::gpio::AnyIOPin> = None;
-    let mut tx_driver = I2sDriver::new_std_tx(i2s1, &i2s_config, bclk, dout, mclk, lrclk).unwrap();
-    tx_driver.tx_enable()?;
-
-    // 10ms 的buffer
-    let mut buf = [0u8; 2 * 160];
-    let mut speaking = false;
-    // 播放hello音效
-    let mut hello_audio = WAKE_WAV.to_vec();
-    tx_driver.write_all(&hello_audio, 100 / PORT_TICK_PERIOD_MS)?;
-    log::info!("Playing hello audio, waiting for response...");
-    // 创建一个死循环
-    loop {
-        // 如果监测到speaking
-        let data = if speaking {
-            // 直接通过 rx channel 提取数据
-            rx.recv().await
-        } else {
-            // 否则通过 select!
-            tokio::select! {
-                Some(data) = rx.recv() =>{  // 通过 rx channel 接收数据
-                    Some(data) // 将数据返回给data
-                }
-                _ = async {} => {
-                    // 否则通过 i2s 读取数据, 并将数据喂给afe
-                    for _ in 0..10{
-                        let n = rx_driver.read(&mut buf, 100 / PORT_TICK_PERIOD_MS)?;
-                        afe_handle.feed(&buf[..n]);
-                    }
-                    None
-                }
+    )
+}
+
+// 按 BoardProfile 里的引脚创建的 I2S 音频设备
+enum I2sPort {
+    // 麦克风和扬声器分别接在 I2S0(RX) 和 I2S1(TX) 上
+    Split {
+        rx: I2sDriver<'static, I2sRx>,
+        tx: I2sDriver<'static, I2sTx>,
+    },
+    // 麦克风和扬声器共用一个双向的 I2S0
+    Duplex(I2sDriver<'static, I2sBiDir>),
+}
+
+const ES8311_MAX_VOLUME: i32 = 90;
+
+// I2S 上的音频设备
+// 有 codec 时使用 codec 的硬件音量, 否则在写入 I2S 前调整 PCM
+pub struct I2sAudio {
+    port: I2sPort,
+    codec: Codec,
+    volume: u8,
+    // 软件音量调整后的播放数据
+    scaled: Vec<u8>,
+}
+
+impl I2sAudio {
+    pub fn new(pins: AudioPins, codec: Codec, i2s0: I2S0, i2s1: I2S1) -> anyhow::Result<Self> {
+        log::info!("PORT_TICK_PERIOD_MS = {}", PORT_TICK_PERIOD_MS);
+        let i2s_config = i2s_config();
+        let port = match pins {
+            AudioPins::Split {
+                mic_ws,
+                mic_sck,
+                mic_din,
+                spk_bclk,
+                spk_lrclk,
+                spk_dout,
+            } => {
+                // 创建i2s RX TX
+                let mclk: Option<AnyIOPin> = None;
+                let mut rx = I2sDriver::new_std_rx(
+                    i2s0,
+                    &i2s_config,
+                    pin(mic_sck),
+                    pin(mic_din),
+                    mclk,
+                    pin(mic_ws),
+                )?;
+                rx.rx_enable()?;
+
+                let mclk: Option<AnyIOPin> = None;
+                let mut tx = I2sDriver::new_std_tx(
+                    i2s1,
+                    &i2s_config,
+                    pin(spk_bclk),
+                    pin(spk_dout),
+                    mclk,
+                    pin(spk_lrclk),
+                )?;
+                tx.tx_enable()?;
+                I2sPort::Split { rx, tx }
             }
-        };
-        // 如果本轮循环接收到数据, 则进行处理
-        // 否则小睡一下进入下一轮循环
-        if let Some(data) = data {
-            match data {
-                // 如果是Hello
-                AudioData::Hello(tx) => {
-                    log::info!("Received hello");
-                    // 通过 i2s 播放 hello 音效
-                    tx_driver
-                        .write_all_async(&hello_audio)
-                        .await
-                        .map_err(|e| anyhow::anyhow!("Error play hello: {:?}", e))?;
-                    // 通过 tx channel 通知播放完成
-                    let _ = tx.send(()); //使用提供的 tx 进行 ack
-                    speaking = false; // 更新no speaking
-                }
-                // 如果是设置hello音效
-                AudioData::SetHelloStart => {
-                    log::info!("Received set hello start");
-                    hello_audio.clear(); // 清空hello音效
-                }
-                AudioData::SetHelloChunk(data) => {
-                    log::info!("Received set hello chunk");
-                    hello_audio.extend(data); // 追加音频数据
-                }
-                AudioData::SetHelloEnd => {
-                    log::info!("Received set hello end");
-                    // 通过 i2s 播放 hello 音效
-                    tx_driver
-                        .write_all_async(&hello_audio)
-                        .await
-                        .map_err(|e| anyhow::anyhow!("Error play set hello: {:?}", e))?;
-                }
-                // 如果是开始(接收语音)
-                AudioData::Start => {
-                    log::info!("Received start");
-                    speaking = true; // 更新speaking
-                }
-                // 如果是语音数据(段)
-                AudioData::Chunk(data) => {
-                    log::info!("Received audio chunk");
-                    // 如果当前是speaking状态
-                    if speaking {
-                        // 通过i2s播放语音数据
-                        tx_driver
-                            .write_all_async(&data)
-                            .await
-                            .map_err(|e| anyhow::anyhow!("Error play audio data: {:?}", e))?;
-                    }
-                }
-                // 如果是结束(接收完毕)
-                AudioData::End(tx) => {
-                    log::info!("Received end");
-                    let _ = tx.send(()); //ack play done
-                    speaking = false; // 更新no speaking
-                    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
-                }
+            AudioPins::Duplex {
+                bclk,
+                din,
+                dout,
+                ws,
+            } => {
+                let mclk: Option<AnyIOPin> = None;
+                let mut driver = I2sDriver::new_std_bidir(
+                    i2s0,
+                    &i2s_config,
+                    pin(bclk),
+                    pin(din),
+                    pin(dout),
+                    mclk,
+                    pin(ws),
+                )?;
+                driver.tx_enable()?;
+                driver.rx_enable()?;
+                I2sPort::Duplex(driver)
             }
+        };
+        Ok(Self {
+            port,
+            codec,
+            volume: volume::MAX,
+            scaled: Vec::new(),
+        })
+    }
+}
+
+impl AudioDevice for I2sAudio {
+    async fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let data = if self.codec == Codec::None && self.volume < volume::MAX {
+            self.scaled.clear();
+            self.scaled.extend_from_slice(data);
+            volume::apply_gain(&mut self.scaled, self.volume);
+            &self.scaled[..]
         } else {
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            data
+        };
+        match &mut self.port {
+            I2sPort::Split { tx, .. } => tx.write_all_async(data).await?,
+            I2sPort::Duplex(driver) => driver.write_all_async(data).await?,
         }
+        Ok(())
     }
 
-    // Ok(())
+    fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let n = match &mut self.port {
+            I2sPort::Split { rx, .. } => rx.read(buf, 100 / PORT_TICK_PERIOD_MS)?,
+            I2sPort::Duplex(driver) => driver.read(buf, 100 / PORT_TICK_PERIOD_MS)?,
+        };
+        Ok(n)
+    }
+
+    fn set_volume(&mut self, volume: u8) -> anyhow::Result<()> {
+        self.volume = volume.min(volume::MAX);
+        if self.codec == Codec::Es8311 {
+            // es8311_set_voice_volume 把超过 90 的音量当作 70, 这里把 0..=MAX 映射到 0..=90
+            let codec_volume = self.volume as i32 * ES8311_MAX_VOLUME / volume::MAX as i32;
+            let ret =
+                unsafe { esp_idf_svc::sys::hal_driver::es8311_set_voice_volume(codec_volume) };
+            if ret != 0 {
+                return Err(anyhow::anyhow!("es8311_set_voice_volume failed: {}", ret));
+            }
+        }
+        Ok(())
+    }
 }
 
-pub async fn i2s_task(
-    i2s: I2S0,
-    bclk: AnyIOPin,
-    din: AnyIOPin,
-    dout: AnyIOPin,
-    ws: AnyIOPin,
+pub async fn audio_task(
+    device: impl AudioDevice,
+    wake_word: String,
+    assets: Option<crate::assets::AssetStore>,
     (tx, rx): (MicTx, PlayerRx),
 ) {
-    let afe_handle = Arc::new(AFE::new());
+    // 使用arc封装AFE数据结构(通过ffi)
+    let afe_handle = Arc::new(AFE::new(&wake_word));
+    // clone 一个供线程使用
     let afe_handle_ = afe_handle.clone();
+    // 启动一个线程, 该线程负责接收处理过的语音数据和vad状态, 并通过channel发送出去
     let afe_r = std::thread::spawn(|| afe_worker(afe_handle_, tx));
-    let r = i2s_player(i2s, bclk, din, dout, ws, afe_handle, rx).await;
+    // player也是一个死循环, 通过音频设备采集音频数据, 并喂给AFE处理
+    let r = crate::player::run(device, rx, assets, |data| {
+        afe_handle.feed(data);
+    })
+    .await;
     if let Err(e) = r {
         log::error!("Error: {}", e);
     } else {
@@ -365,113 +521,10 @@ pub async fn i2s_task(
     }
 }
 
-async fn i2s_player(
-    i2s: I2S0,
-    bclk: AnyIOPin,
-    din: AnyIOPin,
-    dout: AnyIOPin,
-    ws: AnyIOPin,
-    afe_handle: Arc<AFE>,
-    mut rx: PlayerRx,
-) -> anyhow::Result<()> {
-    log::info!("PORT_TICK_PERIOD_MS = {}", PORT_TICK_PERIOD_MS);
-    let i2s_config = config::StdConfig::new(
-        config::Config::default().auto_clear(true),
-        config::StdClkConfig::from_sample_rate_hz(SAMPLE_RATE),
-        config::StdSlotConfig::philips_slot_default(
-            config::DataBitWidth::Bits16,
-            config::SlotMode::Mono,
-        ),
-        config::StdGpioConfig::default(),
-    );
-
-    let mclk: Option<esp_idf_svc::hal::gpio::AnyIOPin> = None;
-
-    let mut driver = I2sDriver::new_std_bidir(i2s, &i2s_config, bclk, din, dout, mclk, ws).unwrap();
-    driver.tx_enable()?;
-    driver.rx_enable()?;
-
-    let mut buf = [0u8; 2 * 160];
-    let mut speaking = false;
-
-    let mut hello_audio = WAKE_WAV.to_vec();
-
-    driver.write_all(&hello_audio, 100 / PORT_TICK_PERIOD_MS)?;
-    log::info!("Playing hello audio, waiting for response...");
-
-    loop {
-        let data = if speaking {
-            rx.recv().await
-        } else {
-            tokio::select! {
-                Some(data) = rx.recv() =>{
-                    Some(data)
-                }
-                _ = async {} => {
-                    let n = driver.read(&mut buf, 100 / PORT_TICK_PERIOD_MS)?;
-                    afe_handle.feed(&buf[..n]);
-                    None
-                }
-            }
-        };
-        if let Some(data) = data {
-            match data {
-                AudioData::Hello(tx) => {
-                    log::info!("Received hello");
-                    driver
-                        .write_all_async(&hello_audio)
-                        .await
-                        .map_err(|e| anyhow::anyhow!("Error play hello: {:?}", e))?;
-                    log::info!("Hello audio sent, notifying");
-                    let _ = tx.send(());
-                    log::info!("Hello audio sent, notifying done");
-                    speaking = false;
-                }
-                AudioData::SetHelloStart => {
-                    log::info!("Received set hello start");
-                    hello_audio.clear();
-                }
-                AudioData::SetHelloChunk(data) => {
-                    log::info!("Received set hello chunk");
-                    hello_audio.extend(data);
-                }
-                AudioData::SetHelloEnd => {
-                    log::info!("Received set hello end");
-                    driver
-                        .write_all_async(&hello_audio)
-                        .await
-                        .map_err(|e| anyhow::anyhow!("Error play set hello: {:?}", e))?;
-                }
-                AudioData::Start => {
-                    log::info!("Received start");
-                    speaking = true;
-                }
-                AudioData::Chunk(data) => {
-                    log::info!("Received audio chunk");
-                    if speaking {
-                        driver
-                            .write_all_async(&data)
-                            .await
-                            .map_err(|e| anyhow::anyhow!("Error play audio data: {:?}", e))?;
-                    }
-                }
-                AudioData::End(tx) => {
-                    log::info!("Received end");
-                    let _ = tx.send(());
-                    speaking = false;
-                    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
-                }
-            }
-        } else {
-            tokio::task::yield_now().await;
-        }
-    }
-
-    // Ok(())
-}
-
 fn afe_worker(afe_handle: Arc<AFE>, tx: MicTx) -> anyhow::Result<()> {
     let mut speech = false;
+    // 离线命令词识别, 与 VAD 无关, 处理所有 AFE 的输出
+    let mut commands = afe_handle.command_recognizer();
     // 死循环
     loop {
         // 通过fetch获取本轮语音的数据和vad状态
@@ -480,10 +533,23 @@ fn afe_worker(afe_handle: Arc<AFE>, tx: MicTx) -> anyhow::Result<()> {
             continue;
         }
         let result = result.unwrap();
+        // 检测到唤醒词, 通知 app 开始监听
+        if result.wakeup {
+            log::info!("Wake word detected");
+            tx.blocking_send(crate::app::Event::Event(crate::app::Event::GAIA))
+                .map_err(|_| anyhow::anyhow!("Failed to send data"))?;
+        }
         // 如果没有数据, 则继续下一轮fetch
         if result.data.is_empty() {
             continue;
         }
+        if let Some(evt) = commands
+            .as_mut()
+            .and_then(|c| c.detect(&result.data[result.cache_len..]))
+        {
+            tx.blocking_send(crate::app::Event::Event(evt))
+                .map_err(|_| anyhow::anyhow!("Failed to send data"))?;
+        }
         // 运行到这里, 首先可以说明, 有语音数据
         // 然后, 如果vad状态为true, 则说明语音仍然在进行
         // 先将已采集到的数据通过channel发送出去
@@ -509,6 +575,7 @@ fn afe_worker(afe_handle: Arc<AFE>, tx: MicTx) -> anyhow::Result<()> {
 
 const WELCOME_WAV: &[u8] = include_bytes!("../assets/welcome.wav");
 
+// 播放欢迎语音, data 为 WAV 文件, None 时播放内置的 WELCOME_WAV
 pub fn player_welcome(
     i2s: I2S0,
     bclk: AnyIOPin,
@@ -517,23 +584,14 @@ pub fn player_welcome(
     mclk: Option<AnyIOPin>,
     data: Option<&[u8]>,
 ) {
-    let i2s_config = config::StdConfig::new(
-        config::Config::default().auto_clear(true),
-        config::StdClkConfig::from_sample_rate_hz(SAMPLE_RATE),
-        config::StdSlotConfig::philips_slot_default(
-            config::DataBitWidth::Bits16,
-            config::SlotMode::Mono,
-        ),
-        config::StdGpioConfig::default(),
-    );
+    let i2s_config = i2s_config();
 
     let mut tx_driver = I2sDriver::new_std_tx(i2s, &i2s_config, bclk, dout, mclk, lrclk).unwrap();
 
     tx_driver.tx_enable().unwrap();
 
-    if let Some(data) = data {
-        tx_driver.write_all(data, 1000).unwrap();
-    } else {
-        tx_driver.write_all(WELCOME_WAV, 1000).unwrap();
+    match crate::wav::decode(data.unwrap_or(WELCOME_WAV), SAMPLE_RATE) {
+        Ok(pcm) => tx_driver.write_all(&pcm, 1000).unwrap(),
+        Err(e) => log::error!("Failed to decode welcome audio: {:?}", e),
     }
 }
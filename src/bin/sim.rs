@@ -0,0 +1,315 @@
+// 在 Linux 上运行 echokit 的对话流程, 不需要烧录硬件
+// - 对话状态机使用 conversation::Conversation, Effect 与设备一样由 executor::Executor 执行
+// - 麦克风: 从 WAV 文件读取(转换为 16kHz mono 16bit), 或者从 stdin 读取 raw PCM
+// - 扬声器: 收到的 TTS 音频写入 WAV 文件
+// - 屏幕: ui::UI 每次刷新保存为一张 PNG
+//
+// 流程与设备上按一下 k0 相同: 唤醒 -> 发送整段输入语音 -> 等待回复播放完成后退出
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use echokit::{
+    assets::AssetStore,
+    board::{self, BoardProfile},
+    conversation::{self, Conversation, Effect, Event, State},
+    executor::{Executor, NoOpus},
+    hal::{DisplaySink, MemoryDisplay},
+    player::{AudioData, PlayerRx},
+    protocol::{AudioCodec, ClientEvent, PROTOCOL_VERSION},
+    ui, wav, ws,
+};
+
+const SAMPLE_RATE: u32 = 16000;
+// 每次送给状态机的麦克风数据(20ms)
+const MIC_CHUNK: usize = 2 * 320;
+
+const USAGE: &str = "usage: echokit-sim <server_url> [--input <file.wav|->] [--output <tts.wav>] [--frames <dir>] [--board <boards|box>] [--assets <dir>] [--timeout <secs>]";
+
+struct Args {
+    server_url: String,
+    // WAV 文件的路径, "-" 表示从 stdin 读取 16kHz mono s16le 的 raw PCM
+    input: String,
+    output: Option<PathBuf>,
+    frames: Option<PathBuf>,
+    // 保存的屏幕帧使用这个开发板的分辨率
+    board: &'static BoardProfile,
+    // 保存 server 下发的背景图的目录, 与设备上的 assets 分区相同
+    assets: Option<PathBuf>,
+    timeout: Duration,
+}
+
+fn parse_args() -> anyhow::Result<Args> {
+    let mut args = std::env::args().skip(1);
+    let mut server_url = None;
+    let mut input = "-".to_string();
+    let mut output = None;
+    let mut frames = None;
+    let mut board = &board::BOARDS;
+    let mut assets = None;
+    let mut timeout = Duration::from_secs(60);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| anyhow::anyhow!("Missing value for {}\n{}", arg, USAGE))
+        };
+        match arg.as_str() {
+            "--input" => input = value()?,
+            "--output" => output = Some(value()?.into()),
+            "--frames" => frames = Some(value()?.into()),
+            "--board" => {
+                let name = value()?;
+                board = board::by_name(&name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown board: {}\n{}", name, USAGE))?;
+            }
+            "--assets" => assets = Some(value()?.into()),
+            "--timeout" => timeout = Duration::from_secs(value()?.parse()?),
+            _ if !arg.starts_with("--") && server_url.is_none() => server_url = Some(arg),
+            _ => anyhow::bail!("Unknown argument: {}\n{}", arg, USAGE),
+        }
+    }
+    Ok(Args {
+        server_url: server_url.ok_or_else(|| anyhow::anyhow!(USAGE))?,
+        input,
+        output,
+        frames,
+        board,
+        assets,
+        timeout,
+    })
+}
+
+fn read_input(input: &str) -> anyhow::Result<Vec<u8>> {
+    if input == "-" {
+        let mut pcm = vec![];
+        std::io::stdin().read_to_end(&mut pcm)?;
+        pcm.truncate(pcm.len() & !1);
+        Ok(pcm)
+    } else {
+        // 任意 PCM WAV 都转换为设备麦克风的格式: 16kHz mono 16bit
+        wav::decode(&std::fs::read(input)?, SAMPLE_RATE)
+    }
+}
+
+fn write_wav(path: &Path, pcm: &[u8]) -> anyhow::Result<()> {
+    let mut wav = Vec::with_capacity(44 + pcm.len());
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + pcm.len() as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+    wav.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+    wav.extend_from_slice(&2u16.to_le_bytes());
+    wav.extend_from_slice(&16u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(pcm.len() as u32).to_le_bytes());
+    wav.extend_from_slice(pcm);
+    std::fs::write(path, wav)?;
+    Ok(())
+}
+
+// 模拟器的屏幕, 每次刷新后把整帧保存为 dir 下的一张 PNG
+struct PngFrames {
+    display: MemoryDisplay,
+    dir: PathBuf,
+}
+
+impl DisplaySink for PngFrames {
+    fn size(&self) -> (usize, usize) {
+        self.display.size()
+    }
+
+    fn flush(
+        &mut self,
+        data: &[u8],
+        x_start: i32,
+        y_start: i32,
+        x_end: i32,
+        y_end: i32,
+    ) -> anyhow::Result<()> {
+        self.display.flush(data, x_start, y_start, x_end, y_end)?;
+        let path = self
+            .dir
+            .join(format!("frame-{:04}.png", self.display.flushes));
+        save_png(
+            &path,
+            &self.display.frame,
+            self.display.width,
+            self.display.height,
+        )
+    }
+}
+
+// 将 RGB565 little endian 的帧保存为 RGB8 的 PNG
+fn save_png(path: &Path, frame: &[u8], width: usize, height: usize) -> anyhow::Result<()> {
+    let rgb: Vec<u8> = frame
+        .chunks_exact(2)
+        .flat_map(|b| {
+            let v = u16::from_le_bytes([b[0], b[1]]);
+            let r = ((v >> 11) & 0x1f) as u8;
+            let g = ((v >> 5) & 0x3f) as u8;
+            let b = (v & 0x1f) as u8;
+            [
+                (r << 3) | (r >> 2),
+                (g << 2) | (g >> 4),
+                (b << 3) | (b >> 2),
+            ]
+        })
+        .collect();
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&rgb)?;
+    Ok(())
+}
+
+// 模拟麦克风: 先按 k0 唤醒, 然后按实时的速度送出输入的语音, 最后结束这段语音
+async fn feed_mic(tx: tokio::sync::mpsc::Sender<Event>, pcm: Vec<u8>) -> anyhow::Result<()> {
+    tx.send(Event::Event(Event::K0)).await?;
+    let mut interval = tokio::time::interval(Duration::from_millis(20));
+    for chunk in pcm.chunks(MIC_CHUNK) {
+        interval.tick().await;
+        tx.send(Event::MicAudioChunk(chunk.to_vec())).await?;
+    }
+    tx.send(Event::MicAudioEnd).await?;
+    // 保持 tx 不被关闭, 由主循环决定什么时候退出
+    std::future::pending::<()>().await;
+    Ok(())
+}
+
+// 模拟扬声器: 收到的 TTS 音频保存在内存里, 播放立即完成
+// 所有 PlayerTx 关闭后返回保存的音频
+async fn fake_speaker(mut rx: PlayerRx) -> Vec<u8> {
+    let mut speaking = false;
+    let mut tts = vec![];
+    while let Some(data) = rx.recv().await {
+        match data {
+            AudioData::Hello(tx) => {
+                let _ = tx.send(());
+            }
+            AudioData::Start => speaking = true,
+            AudioData::End(tx) => {
+                speaking = false;
+                let _ = tx.send(());
+            }
+            AudioData::Chunk(data) if speaking => tts.extend_from_slice(&data),
+            AudioData::Interrupt => speaking = false,
+            // 输出文件里保存的是原始的 TTS 音频, 音量只显示在 gui 上
+            AudioData::Volume(volume) => log::info!("Volume: {}", volume),
+            _ => {}
+        }
+    }
+    tts
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+    let pcm = read_input(&args.input)?;
+    log::info!(
+        "Input {}: {:.2}s",
+        args.input,
+        pcm.len() as f32 / (SAMPLE_RATE * 2) as f32
+    );
+
+    if let Some(dir) = args.frames.clone() {
+        std::fs::create_dir_all(&dir)?;
+        ui::set_display(PngFrames {
+            display: MemoryDisplay::new(args.board.display.width, args.board.display.height),
+            dir,
+        });
+    }
+    let mut gui = ui::UI::new(None)?;
+    gui.state = "Idle".to_string();
+    gui.display_flush()?;
+
+    // 不指定 --assets 时不保存 server 下发的背景图
+    let assets = match &args.assets {
+        Some(dir) => {
+            std::fs::create_dir_all(dir)?;
+            Some(AssetStore::open(dir)?)
+        }
+        None => None,
+    };
+
+    let device_info = ClientEvent::DeviceInfo {
+        protocol_version: PROTOCOL_VERSION,
+        firmware_version: env!("CARGO_PKG_VERSION").to_string(),
+        board: "sim".to_string(),
+        sample_rate: SAMPLE_RATE,
+        codecs: vec![AudioCodec::Adpcm, AudioCodec::Pcm16],
+    };
+    let mut server = ws::Server::new(args.server_url.clone()).await?;
+    server.handshake(device_info.clone()).await?;
+    let server = ws::ReconnectingServer::new(server, device_info);
+
+    let (evt_tx, mut evt_rx) = tokio::sync::mpsc::channel(64);
+    let mic = tokio::spawn(feed_mic(evt_tx, pcm));
+    let (player_tx, player_rx) = tokio::sync::mpsc::unbounded_channel();
+    let speaker = tokio::spawn(fake_speaker(player_rx));
+
+    let mut conv = Conversation::new(conversation::Config {
+        idle_timeout: None,
+        ..Default::default()
+    });
+    let mut exec = Executor::new(server, player_tx, gui, NoOpus, |_| {}, assets)?;
+    let mut submitted = false;
+    let deadline = tokio::time::Instant::now() + args.timeout;
+
+    loop {
+        // 模拟器不重连, server 断开时直接退出
+        let evt = match tokio::time::timeout_at(deadline, exec.next_event(&mut evt_rx, None)).await
+        {
+            Ok(evt) => evt?,
+            Err(_) => anyhow::bail!("Timeout waiting for the response"),
+        };
+        let Some(evt) = evt else {
+            break;
+        };
+        let end_of_input = matches!(evt, Event::MicAudioEnd);
+        // 下行音频先解码为 PCM, 再交给状态机
+        let Some(evt) = exec.decode(evt, conv.state() == State::Speaking) else {
+            continue;
+        };
+        let effects = conv.handle(evt, std::time::Instant::now());
+        submitted |= effects
+            .iter()
+            .any(|e| matches!(e, Effect::Send(ClientEvent::EndOfUtterance { .. })));
+        exec.run(effects).await?;
+
+        if end_of_input && !submitted {
+            anyhow::bail!("Input is too short to be submitted (at least 1s of speech)");
+        }
+        // 回复已经播放完成, 回到收音状态
+        if submitted && conv.state() == State::Listening {
+            break;
+        }
+    }
+    mic.abort();
+    // 关闭 PlayerTx, 让 fake_speaker 返回保存的音频
+    drop(exec);
+    let tts = speaker.await?;
+
+    log::info!(
+        "Received {:.2}s of TTS audio",
+        tts.len() as f32 / (SAMPLE_RATE * 2) as f32
+    );
+    if let Some(path) = &args.output {
+        write_wav(path, &tts)?;
+        log::info!("TTS audio saved to {}", path.display());
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = parse_args()?;
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args))
+}
@@ -0,0 +1,216 @@
+// 开发板的硬件描述
+// 同一个固件在启动时根据 NVS 里的 board 选择(或自动检测)一个 BoardProfile, 再按它初始化音频/屏幕/按键
+// 引脚都是 GPIO 编号
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioPins {
+    // 麦克风和扬声器分别接在 I2S0(RX) 和 I2S1(TX) 上
+    Split {
+        mic_ws: i32,
+        mic_sck: i32,
+        mic_din: i32,
+        spk_bclk: i32,
+        spk_lrclk: i32,
+        spk_dout: i32,
+    },
+    // 麦克风和扬声器共用一个双向的 I2S0
+    Duplex {
+        bclk: i32,
+        din: i32,
+        dout: i32,
+        ws: i32,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    // I2S 直接接数字麦克风和功放, 不需要初始化
+    None,
+    // ES8311, 通过 hal_driver 里的 I2C(SDA 48, SCL 45) 和 XL9555 扩展 IO 初始化
+    Es8311,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisplayController {
+    // SPI 接口的 ST7789
+    St7789 {
+        mosi: i32,
+        clk: i32,
+        cs: i32,
+        dc: i32,
+        rst: i32,
+        invert_color: bool,
+    },
+    // hal_driver 里的 8080 并口 LCD, 引脚定义在 lcd.h
+    HalDriver,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Display {
+    pub controller: DisplayController,
+    pub width: usize,
+    pub height: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buttons {
+    pub k0: i32,
+    // K1/K2 接在 XL9555 扩展 IO 上(只有 Codec::Es8311 的板子才会初始化 XL9555)
+    pub expander_keys: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardProfile {
+    // 保存在 NVS 里的名字, 也会在握手时发送给 server
+    pub name: &'static str,
+    pub audio: AudioPins,
+    pub codec: Codec,
+    pub display: Display,
+    pub buttons: Buttons,
+}
+
+pub const BOARDS: BoardProfile = BoardProfile {
+    name: "boards",
+    audio: AudioPins::Split {
+        mic_ws: 4,
+        mic_sck: 5,
+        mic_din: 6,
+        spk_bclk: 15,
+        spk_lrclk: 16,
+        spk_dout: 7,
+    },
+    codec: Codec::None,
+    display: Display {
+        controller: DisplayController::St7789 {
+            mosi: 47,
+            clk: 21,
+            cs: 41,
+            dc: 40,
+            rst: 45,
+            invert_color: true,
+        },
+        width: 240,
+        height: 240,
+    },
+    buttons: Buttons {
+        k0: 0,
+        expander_keys: false,
+    },
+};
+
+pub const BOX: BoardProfile = BoardProfile {
+    name: "box",
+    audio: AudioPins::Duplex {
+        bclk: 21,
+        din: 47,
+        dout: 14,
+        ws: 13,
+    },
+    codec: Codec::Es8311,
+    display: Display {
+        controller: DisplayController::HalDriver,
+        width: 320,
+        height: 240,
+    },
+    buttons: Buttons {
+        k0: 0,
+        expander_keys: true,
+    },
+};
+
+pub const PROFILES: &[&BoardProfile] = &[&BOARDS, &BOX];
+
+// hal_driver 里 ES8311/XL9555 的 I2C 引脚(SDA, SCL), 与 myiic.h 一致
+// 自动检测时在这两个引脚上探测 XL9555, 其他板子上它们只能用作 LCD 的复位线
+pub const CODEC_I2C_PINS: [i32; 2] = [48, 45];
+
+pub fn by_name(name: &str) -> Option<&'static BoardProfile> {
+    PROFILES.iter().copied().find(|p| p.name == name)
+}
+
+impl BoardProfile {
+    // 由这个 profile 在 Rust 里直接使用的所有 GPIO
+    // hal_driver 内部使用的引脚(I2C, 并口 LCD)不在其中
+    pub fn pins(&self) -> Vec<i32> {
+        let mut pins = match self.audio {
+            AudioPins::Split {
+                mic_ws,
+                mic_sck,
+                mic_din,
+                spk_bclk,
+                spk_lrclk,
+                spk_dout,
+            } => vec![mic_ws, mic_sck, mic_din, spk_bclk, spk_lrclk, spk_dout],
+            AudioPins::Duplex {
+                bclk,
+                din,
+                dout,
+                ws,
+            } => vec![bclk, din, dout, ws],
+        };
+        if let DisplayController::St7789 {
+            mosi,
+            clk,
+            cs,
+            dc,
+            rst,
+            ..
+        } = self.display.controller
+        {
+            pins.extend([mosi, clk, cs, dc, rst]);
+        }
+        pins.push(self.buttons.k0);
+        pins
+    }
+}
+
+#[test]
+fn test_by_name() {
+    assert_eq!(by_name("boards"), Some(&BOARDS));
+    assert_eq!(by_name("box"), Some(&BOX));
+    assert_eq!(by_name(""), None);
+    assert_eq!(by_name("Box"), None);
+}
+
+#[test]
+fn test_profile_pins_unique() {
+    // 每个引脚只能分配给一个外设, 否则按 GPIO 编号创建驱动时会互相覆盖
+    for profile in PROFILES {
+        let mut pins = profile.pins();
+        let n = pins.len();
+        pins.sort();
+        pins.dedup();
+        assert_eq!(pins.len(), n, "duplicate pin in {}", profile.name);
+    }
+}
+
+#[test]
+fn test_probe_pins() {
+    // 没有 ES8311 的板子上, 探测用的引脚最多只是 LCD 的复位线
+    // 探测在 lcd_init 之前进行, 复位线上的电平变化不会影响之后的初始化
+    for profile in PROFILES {
+        if profile.codec == Codec::Es8311 {
+            continue;
+        }
+        for pin in profile.pins() {
+            if CODEC_I2C_PINS.contains(&pin) {
+                assert!(
+                    matches!(profile.display.controller, DisplayController::St7789 { rst, .. } if rst == pin),
+                    "{} uses probe pin {}",
+                    profile.name,
+                    pin
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_expander_keys_need_codec() {
+    // XL9555 只在初始化 ES8311 时一起初始化
+    for profile in PROFILES {
+        if profile.buttons.expander_keys {
+            assert_eq!(profile.codec, Codec::Es8311, "{}", profile.name);
+        }
+    }
+}
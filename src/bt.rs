@@ -7,6 +7,10 @@ const SSID_ID: BleUuid = uuid128!("1fda4d6e-2f14-42b0-96fa-453bed238375");
 const PASS_ID: BleUuid = uuid128!("a987ab18-a940-421a-a1d7-b94ee22bccbe");
 const SERVER_URL_ID: BleUuid = uuid128!("cef520a9-bcb5-4fc6-87f7-82804eee2b20");
 const BACKGROUND_GIF_ID: BleUuid = uuid128!("d1f3b2c4-5e6f-4a7b-8c9d-0e1f2a3b4c5d");
+const BACKGROUND_PROGRESS_ID: BleUuid = uuid128!("ae93f04b-05e2-413a-b386-51d559a09b96");
+const BACKGROUND_VERSION_ID: BleUuid = uuid128!("3c6d2a8e-91f4-4b07-8e5a-d4f1b6c09e73");
+const WAKE_WORD_ID: BleUuid = uuid128!("5b3e8f0a-7c2d-4e61-9a4b-2f8d6c1e3a70");
+const BOARD_ID: BleUuid = uuid128!("9e2c7d41-3f8a-4b6e-a1d5-0c7b3e9f5a28");
 
 pub fn bt(
     setting: Arc<Mutex<(crate::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
@@ -104,6 +108,10 @@ pub fn bt(
     let setting = setting.clone();
     let setting_ = setting.clone();
     let setting_gif = setting.clone();
+    let setting_wake = setting.clone();
+    let setting_wake_ = setting.clone();
+    let setting_board = setting.clone();
+    let setting_board_ = setting.clone();
     // 从 service 创建 characteristic, 支持读写(收发) server URL
     let server_url_characteristic = service.lock().create_characteristic(
         SERVER_URL_ID,
@@ -137,27 +145,106 @@ pub fn bt(
                 log::error!("Failed to parse new server URL from bytes.");
             }
         });
-    // 从 service 创建 characteristic, 支持读(收) background GIF
+    // 从 service 创建 characteristic, 支持读写(收发) 唤醒词模型名
+    let wake_word_characteristic = service.lock().create_characteristic(
+        WAKE_WORD_ID,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    );
+    wake_word_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from wake word characteristic");
+            let setting = setting_wake.lock().unwrap();
+            c.set_value(setting.0.wake_word.as_bytes());
+        })
+        .on_write(move |args| {
+            log::info!(
+                "Wrote to wake word characteristic: {:?} -> {:?}",
+                args.current_data(),
+                args.recv_data()
+            );
+            if let Ok(new_wake_word) = String::from_utf8(args.recv_data().to_vec()) {
+                log::info!("New wake word: {}", new_wake_word);
+                let mut setting = setting_wake_.lock().unwrap();
+                if let Err(e) = setting.1.set_str("wake_word", &new_wake_word) {
+                    log::error!("Failed to save wake word to NVS: {:?}", e);
+                } else {
+                    setting.0.wake_word = new_wake_word;
+                }
+            } else {
+                log::error!("Failed to parse new wake word from bytes.");
+            }
+        });
+    // 从 service 创建 characteristic, 支持读写(收发) 开发板型号, 为空时自动检测
+    let board_characteristic = service
+        .lock()
+        .create_characteristic(BOARD_ID, NimbleProperties::READ | NimbleProperties::WRITE);
+    board_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from board characteristic");
+            let setting = setting_board.lock().unwrap();
+            c.set_value(setting.0.board.as_bytes());
+        })
+        .on_write(move |args| {
+            log::info!(
+                "Wrote to board characteristic: {:?} -> {:?}",
+                args.current_data(),
+                args.recv_data()
+            );
+            match String::from_utf8(args.recv_data().to_vec()) {
+                Ok(new_board)
+                    if new_board.is_empty() || crate::board::by_name(&new_board).is_some() =>
+                {
+                    log::info!("New board: {}", new_board);
+                    let mut setting = setting_board_.lock().unwrap();
+                    if let Err(e) = setting.1.set_str("board", &new_board) {
+                        log::error!("Failed to save board to NVS: {:?}", e);
+                    } else {
+                        setting.0.board = new_board;
+                    }
+                }
+                Ok(new_board) => log::error!("Unknown board: {}", new_board),
+                Err(_) => log::error!("Failed to parse new board from bytes."),
+            }
+        });
+    // 从 service 创建 characteristic, 支持写(收) background GIF, 分段上传的协议见 upload.rs
     let background_gif_characteristic = service
         .lock()
         .create_characteristic(BACKGROUND_GIF_ID, NimbleProperties::WRITE);
+    // 从 service 创建 characteristic, 每次写入 background GIF 后通知上传进度
+    let background_progress_characteristic = service.lock().create_characteristic(
+        BACKGROUND_PROGRESS_ID,
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+    );
+    // 从 service 创建 characteristic, 只读, 分段上传的协议版本, 旧固件没有这个 characteristic
+    let background_version_characteristic = service
+        .lock()
+        .create_characteristic(BACKGROUND_VERSION_ID, NimbleProperties::READ);
+    background_version_characteristic
+        .lock()
+        .set_value(&[crate::upload::PROTOCOL_VERSION]);
+    let mut upload = crate::upload::Upload::new(crate::conversation::MAX_BACKGROUND_SIZE);
+    background_progress_characteristic
+        .lock()
+        .set_value(&upload.progress());
+    let background_progress = background_progress_characteristic.clone();
     background_gif_characteristic.lock().on_write(move |args| {
-        let gif_chunk = args.recv_data();
-
-        if gif_chunk.len() <= 1024 * 1024 && gif_chunk.len() > 0 {
-            log::info!("New background GIF received, size: {}", gif_chunk.len());
-            // 取出 settings
-            let mut setting = setting_gif.lock().unwrap();
-            // settings.gif 使用 extend 追加数据
-            // 因为数据比较大, 可能会分多次接收
-            // 需要注意的是, 这里只是接收数据到变量中, 并不像上面的 ssid, pass 那样, 直接写入到 nvs
-            setting.0.background_gif.0.extend_from_slice(gif_chunk);
-            if gif_chunk.len() < 512 {
-                setting.0.background_gif.1 = true; // Mark as valid
+        match upload.write(args.recv_data()) {
+            Ok(Some(gif)) => {
+                log::info!("New background GIF received, size: {}", gif.len());
+                // 需要注意的是, 这里只是接收数据到变量中, 并不像上面的 ssid, pass 那样, 直接写入到 nvs
+                // 等按下 K0 后由 main 检查并保存
+                let mut setting = setting_gif.lock().unwrap();
+                setting.0.background_gif = (gif, true);
             }
-        } else {
-            log::error!("Failed to parse new background GIF from bytes.");
+            Ok(None) => {}
+            Err(e) => log::error!("Failed to receive background GIF: {}", e),
         }
+        background_progress
+            .lock()
+            .set_value(&upload.progress())
+            .notify();
     });
     // 发布广播, 供客户端发现
     ble_advertising.lock().set_data(
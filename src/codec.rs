@@ -0,0 +1,251 @@
+use crate::executor::{Codecs, Decode, Encode};
+use esp_idf_svc::sys::esp_audio_codec;
+
+// Opus 编码只支持这几种帧长
+fn opus_frame_duration(
+    frame_ms: u32,
+) -> anyhow::Result<esp_audio_codec::esp_opus_enc_frame_duration_t> {
+    let duration = match frame_ms {
+        10 => esp_audio_codec::esp_opus_enc_frame_duration_t_ESP_OPUS_ENC_FRAME_DURATION_10_MS,
+        20 => esp_audio_codec::esp_opus_enc_frame_duration_t_ESP_OPUS_ENC_FRAME_DURATION_20_MS,
+        40 => esp_audio_codec::esp_opus_enc_frame_duration_t_ESP_OPUS_ENC_FRAME_DURATION_40_MS,
+        60 => esp_audio_codec::esp_opus_enc_frame_duration_t_ESP_OPUS_ENC_FRAME_DURATION_60_MS,
+        _ => anyhow::bail!("Unsupported opus frame duration: {}ms", frame_ms),
+    };
+    Ok(duration)
+}
+
+// 通过 esp_audio_codec 的 ffi, 将 16bit mono PCM 编码为 Opus 帧
+pub struct OpusEncoder {
+    handle: *mut std::ffi::c_void,
+    // 每一帧输入的 PCM 字节数
+    in_size: usize,
+    // 每一帧输出的最大字节数
+    out_size: usize,
+    // 不足一帧的 PCM, 留到下一次编码
+    pending: Vec<u8>,
+}
+
+unsafe impl Send for OpusEncoder {}
+
+impl OpusEncoder {
+    pub fn new(sample_rate: u32, frame_ms: u32, bitrate: i32) -> anyhow::Result<Self> {
+        let mut config = esp_audio_codec::esp_opus_enc_config_t {
+            sample_rate: sample_rate as _,
+            channel: 1,
+            bits_per_sample: 16,
+            bitrate,
+            frame_duration: opus_frame_duration(frame_ms)?,
+            application_mode:
+                esp_audio_codec::esp_opus_enc_application_t_ESP_OPUS_ENC_APPLICATION_VOIP,
+            complexity: 0,
+            enable_fec: false,
+            enable_dtx: false,
+            enable_vbr: false,
+        };
+
+        let mut handle = std::ptr::null_mut();
+        let mut in_size = 0;
+        let mut out_size = 0;
+        unsafe {
+            let ret = esp_audio_codec::esp_opus_enc_open(
+                &mut config as *mut _ as *mut _,
+                std::mem::size_of_val(&config) as _,
+                &mut handle,
+            );
+            if ret != esp_audio_codec::esp_audio_err_t_ESP_AUDIO_ERR_OK || handle.is_null() {
+                anyhow::bail!("Failed to open opus encoder: {}", ret);
+            }
+            esp_audio_codec::esp_opus_enc_get_frame_size(handle, &mut in_size, &mut out_size);
+            if in_size <= 0 || out_size <= 0 {
+                esp_audio_codec::esp_opus_enc_close(handle);
+                anyhow::bail!("Invalid opus frame size: {} -> {}", in_size, out_size);
+            }
+        }
+        log::info!(
+            "Opus encoder opened, frame: {}ms, in: {} bytes, out: {} bytes",
+            frame_ms,
+            in_size,
+            out_size
+        );
+
+        Ok(Self {
+            handle,
+            in_size: in_size as usize,
+            out_size: out_size as usize,
+            pending: Vec::with_capacity(in_size as usize),
+        })
+    }
+
+    fn encode_frame(&mut self, frame: &mut [u8]) -> anyhow::Result<Vec<u8>> {
+        let mut out = vec![0u8; self.out_size];
+        let mut in_frame = esp_audio_codec::esp_audio_enc_in_frame_t {
+            buffer: frame.as_mut_ptr(),
+            len: frame.len() as _,
+        };
+        let mut out_frame = esp_audio_codec::esp_audio_enc_out_frame_t {
+            buffer: out.as_mut_ptr(),
+            len: out.len() as _,
+            encoded_bytes: 0,
+            pts: 0,
+        };
+        let ret = unsafe {
+            esp_audio_codec::esp_opus_enc_process(self.handle, &mut in_frame, &mut out_frame)
+        };
+        if ret != esp_audio_codec::esp_audio_err_t_ESP_AUDIO_ERR_OK {
+            anyhow::bail!("Failed to encode opus frame: {}", ret);
+        }
+        out.truncate(out_frame.encoded_bytes as usize);
+        Ok(out)
+    }
+
+    // 输入任意长度的 PCM, 返回编码好的完整帧, 不足一帧的数据留到下一次
+    pub fn encode(&mut self, pcm: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut frames = vec![];
+        let mut pcm = pcm;
+        while self.pending.len() + pcm.len() >= self.in_size {
+            let n = self.in_size - self.pending.len();
+            let mut frame = std::mem::take(&mut self.pending);
+            frame.extend_from_slice(&pcm[..n]);
+            pcm = &pcm[n..];
+            frames.push(self.encode_frame(&mut frame)?);
+            frame.clear();
+            self.pending = frame;
+        }
+        self.pending.extend_from_slice(pcm);
+        Ok(frames)
+    }
+
+    // 一段语音结束时调用, 用静音补齐最后不足一帧的数据
+    pub fn flush(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.pending.is_empty() {
+            return Ok(None);
+        }
+        let mut frame = std::mem::take(&mut self.pending);
+        frame.resize(self.in_size, 0);
+        let r = self.encode_frame(&mut frame);
+        frame.clear();
+        self.pending = frame;
+        r.map(Some)
+    }
+}
+
+impl Drop for OpusEncoder {
+    fn drop(&mut self) {
+        unsafe { esp_audio_codec::esp_opus_enc_close(self.handle) };
+    }
+}
+
+// 通过 esp_audio_codec 的 ffi, 将 Opus 包解码为 16bit mono PCM
+// server 下发的每个 AudioChunk 是一个完整的 Opus 包
+pub struct OpusDecoder {
+    handle: *mut std::ffi::c_void,
+    out: Vec<u8>,
+}
+
+unsafe impl Send for OpusDecoder {}
+
+impl OpusDecoder {
+    pub fn new(sample_rate: u32) -> anyhow::Result<Self> {
+        let mut config = esp_audio_codec::esp_opus_dec_cfg_t::default();
+        config.sample_rate = sample_rate as _;
+        config.channel = 1;
+        // 不限定帧长, 由每个包的 TOC 决定
+        config.frame_duration =
+            esp_audio_codec::esp_opus_dec_frame_duration_t_ESP_OPUS_DEC_FRAME_DURATION_INVALID;
+        config.self_delimited = false;
+
+        let mut handle = std::ptr::null_mut();
+        let ret = unsafe {
+            esp_audio_codec::esp_opus_dec_open(
+                &mut config as *mut _ as *mut _,
+                std::mem::size_of_val(&config) as _,
+                &mut handle,
+            )
+        };
+        if ret != esp_audio_codec::esp_audio_err_t_ESP_AUDIO_ERR_OK || handle.is_null() {
+            anyhow::bail!("Failed to open opus decoder: {}", ret);
+        }
+
+        // 最长的 Opus 帧是 120ms
+        let out = vec![0u8; (sample_rate as usize * 120 / 1000) * 2];
+        Ok(Self { handle, out })
+    }
+
+    pub fn decode(&mut self, packet: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let mut pcm = Vec::with_capacity(self.out.len());
+        let mut offset = 0;
+        while offset < packet.len() {
+            let mut raw = esp_audio_codec::esp_audio_dec_in_raw_t::default();
+            raw.buffer = packet[offset..].as_ptr() as *mut _;
+            raw.len = (packet.len() - offset) as _;
+
+            let mut frame = esp_audio_codec::esp_audio_dec_out_frame_t::default();
+            frame.buffer = self.out.as_mut_ptr();
+            frame.len = self.out.len() as _;
+
+            let mut info = esp_audio_codec::esp_audio_dec_info_t::default();
+            let ret = unsafe {
+                esp_audio_codec::esp_opus_dec_decode(self.handle, &mut raw, &mut frame, &mut info)
+            };
+            // 输出 buffer 不够时, 按照需要的大小扩容后重试
+            if ret == esp_audio_codec::esp_audio_err_t_ESP_AUDIO_ERR_BUFF_NOT_ENOUGH
+                && frame.needed_size as usize > self.out.len()
+            {
+                self.out.resize(frame.needed_size as usize, 0);
+                continue;
+            }
+            if ret != esp_audio_codec::esp_audio_err_t_ESP_AUDIO_ERR_OK {
+                anyhow::bail!("Failed to decode opus packet: {}", ret);
+            }
+            if raw.consumed == 0 {
+                break;
+            }
+            offset += raw.consumed as usize;
+            pcm.extend_from_slice(&self.out[..frame.decoded_size as usize]);
+        }
+        Ok(pcm)
+    }
+}
+
+impl Drop for OpusDecoder {
+    fn drop(&mut self) {
+        unsafe { esp_audio_codec::esp_opus_dec_close(self.handle) };
+    }
+}
+
+impl Decode for OpusDecoder {
+    fn decode(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        OpusDecoder::decode(self, data)
+    }
+}
+
+impl Encode for OpusEncoder {
+    fn encode(&mut self, pcm: &[u8]) -> anyhow::Result<Vec<Vec<u8>>> {
+        OpusEncoder::encode(self, pcm)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        OpusEncoder::flush(self)
+    }
+}
+
+// 设备上的 Opus 编解码, 上行编码的帧长和码率见 app::Config
+pub struct EspCodecs {
+    pub opus_frame_ms: u32,
+    pub opus_bitrate: i32,
+}
+
+impl Codecs for EspCodecs {
+    fn opus_decoder(&self, sample_rate: u32) -> anyhow::Result<Box<dyn Decode>> {
+        Ok(Box::new(OpusDecoder::new(sample_rate)?))
+    }
+
+    fn opus_encoder(&self, sample_rate: u32) -> anyhow::Result<Box<dyn Encode>> {
+        Ok(Box::new(OpusEncoder::new(
+            sample_rate,
+            self.opus_frame_ms,
+            self.opus_bitrate,
+        )?))
+    }
+}
@@ -0,0 +1,1075 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    jitter::{JitterBuffer, JitterConfig},
+    player::MAX_HELLO_SIZE,
+    protocol::{AudioCodec, ClientEvent, EndReason, ServerEvent},
+    volume,
+    wav::WavParser,
+};
+
+// 对话状态机, 不依赖 esp-idf, 可以在 host 上用 cargo test 测试
+// Conversation 只根据收到的 Event 更新自己的状态, 并返回需要执行的 Effect
+// 发送给 server、送给扬声器和刷新 gui 都由 executor::Executor 执行
+
+#[derive(Debug)]
+pub enum Event {
+    Event(&'static str),
+    ServerEvent(ServerEvent),
+    MicAudioChunk(Vec<u8>),
+    MicAudioEnd,
+    // 扬声器播放完一段回复(AudioData::End 的 ack)
+    PlaybackEnd,
+    // Listening 状态下超过 Config::idle_timeout 没有交互
+    IdleTimeout,
+}
+
+#[allow(dead_code)]
+impl Event {
+    pub const GAIA: &'static str = "gaia";
+    pub const NO: &'static str = "no";
+    pub const YES: &'static str = "yes";
+    pub const NOISE: &'static str = "noise";
+    pub const RESET: &'static str = "reset";
+    pub const UNKNOWN: &'static str = "unknown";
+    pub const K0: &'static str = "k0";
+    pub const K0_: &'static str = "k0_";
+
+    // k1 减小音量, k2 增大音量
+    pub const K1: &'static str = "k1";
+    pub const K2: &'static str = "k2";
+
+    // 离线命令词调整音量
+    pub const VOLUME_UP: &'static str = "volume_up";
+    pub const VOLUME_DOWN: &'static str = "volume_down";
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Listening,
+    Recording,
+    Wait,
+    Speaking,
+    Idle,
+}
+
+impl State {
+    // gui 上显示的状态
+    pub fn label(&self) -> &'static str {
+        match self {
+            State::Listening => "Listening...",
+            State::Recording => "Recording...",
+            State::Wait => "Waiting...",
+            State::Speaking => "Speaking...",
+            State::Idle => "Idle",
+        }
+    }
+}
+
+// 送给扬声器线程的指令, 与 audio::AudioData 一一对应, 但不带 ack 的 channel
+#[derive(Debug, PartialEq, Eq)]
+pub enum Playback {
+    // 播放 hello 提示音, 执行者等播放完成后再处理下一个 Effect
+    Hello,
+    Start,
+    Chunk(Vec<u8>),
+    // 一段回复的音频结束, 播放完成后执行者产生 Event::PlaybackEnd
+    End,
+    // 停止播放, 丢弃扬声器队列里还没有播放的数据
+    Interrupt,
+    SetHelloStart,
+    // 已经从 WAV 转换为 PCM
+    SetHelloChunk(Vec<u8>),
+    SetHelloEnd,
+    // 新的 hello 不是支持的 WAV, 丢弃已经收到的部分
+    SetHelloAbort,
+    // 恢复内置的 hello 音效
+    ResetHello,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Effect {
+    // 发送给 server
+    Send(ClientEvent),
+    // 麦克风 PCM, 按协商好的 codec 编码后发送给 server
+    // end 表示本段语音已经结束, 需要把编码器里剩余的数据也发出去
+    SendAudio {
+        data: Vec<u8>,
+        end: bool,
+    },
+    // 送给扬声器
+    Play(Playback),
+    // 按照 codec 重新创建下行音频的解码器, 之后的 AudioChunk 先解码
+    // 再从 sample_rate/channels 重采样为播放器的格式, 然后交给状态机
+    Decoder {
+        codec: AudioCodec,
+        sample_rate: u32,
+        channels: u8,
+    },
+    // 刷新 gui 的状态和文本
+    SetState(String),
+    SetText(String),
+    // 用新的背景图重新创建 gui, 成功后保存, 并回复 server SettingsAck { key: BACKGROUND_ACK }
+    Background(Vec<u8>),
+    // server 拒绝了设备, 结束 main_work
+    Rejected(String),
+    // 设置扬声器音量, 执行者同时保存到 NVS 并在 gui 上显示音量条
+    SetVolume(u8),
+}
+
+// server 下发的背景图的最大长度, 背景图保存在 assets 分区, 启动时整个读入内存
+pub const MAX_BACKGROUND_SIZE: usize = 1024 * 1024;
+// 回复背景图设置结果的 SettingsAck 的 key
+pub const BACKGROUND_ACK: &str = "background";
+// 回复 hello 音效设置结果的 SettingsAck 的 key
+pub const HELLO_ACK: &str = "hello";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // 播放回复时, 检测到用户持续说话超过这个时长就打断播放, None 表示不允许打断
+    pub barge_in: Option<Duration>,
+    // Listening 状态下超过这个时长没有交互就回到 Idle, 需要唤醒词或 k0 重新唤醒
+    // None 表示一直监听
+    pub idle_timeout: Option<Duration>,
+    // Idle 状态下保留最近这么长的麦克风语音, 唤醒词触发后作为这一轮语音的开头
+    // 避免 WakeNet 检测延迟导致唤醒词后面的第一个字丢失
+    pub wake_preroll: Duration,
+    // 下行 TTS 音频的 jitter buffer 参数
+    pub jitter: JitterConfig,
+    // 启动时的扬声器音量, 一般是 NVS 里保存的值
+    pub volume: u8,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            barge_in: Some(Duration::from_millis(400)),
+            idle_timeout: Some(Duration::from_secs(30)),
+            wake_preroll: Duration::from_millis(500),
+            jitter: JitterConfig::default(),
+            volume: volume::DEFAULT,
+        }
+    }
+}
+
+pub struct Conversation {
+    config: Config,
+    state: State,
+    // 这一轮已经提交的语音时长(秒)
+    submit_audio: f32,
+    audio_buffer: Vec<u8>,
+    jitter: JitterBuffer,
+    // 已经发送 Playback::End, 还没有收到 PlaybackEnd
+    playing: bool,
+    // 收到 EndResponse 时如果还在播放, 等播放完成后再切换到 Listening
+    response_ended: bool,
+    // 被用户打断的回复, 丢弃它剩余的音频, 直到 EndResponse 或者提交新的语音
+    cancelled: bool,
+    // 播放期间检测到的用户语音, 打断后作为新一轮语音的开头发送给 server
+    barge_in_audio: Vec<u8>,
+    // 空闲超时的截止时间, 进入 Listening 时开始计时, 离开 Listening 时取消
+    idle_deadline: Option<Instant>,
+    // Idle 状态下最近的麦克风语音, 见 Config::wake_preroll
+    preroll: Vec<u8>,
+    preroll_size: usize,
+    // 正在接收的背景图, 没有在接收或者已经超过大小限制时为 None
+    new_gui_bg: Option<Vec<u8>>,
+    // 正在接收的 hello 音效, 格式错误时为 None, 忽略剩余的 HelloChunk
+    new_hello: Option<WavParser>,
+    // 已经转换的 hello 音效长度, 不能超过 player::MAX_HELLO_SIZE
+    new_hello_size: usize,
+    // 当前的扬声器音量
+    volume: u8,
+}
+
+impl Conversation {
+    pub fn new(config: Config) -> Self {
+        Self {
+            state: State::Idle,
+            submit_audio: 0.0,
+            audio_buffer: Vec::with_capacity(8192),
+            jitter: JitterBuffer::new(config.jitter),
+            playing: false,
+            response_ended: false,
+            cancelled: false,
+            barge_in_audio: Vec::new(),
+            idle_deadline: None,
+            preroll: Vec::new(),
+            preroll_size: (config.wake_preroll.as_secs_f32() * 32000.0) as usize & !1,
+            new_gui_bg: None,
+            new_hello: None,
+            new_hello_size: 0,
+            volume: config.volume.min(volume::MAX),
+            config,
+        }
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    // 调整音量, 不影响对话状态
+    fn set_volume(&mut self, volume: u8, effects: &mut Vec<Effect>) {
+        let volume = volume.min(volume::MAX);
+        log::info!("Set volume: {} -> {}", self.volume, volume);
+        self.volume = volume;
+        effects.push(Effect::SetVolume(volume));
+    }
+
+    // 返回空闲超时的截止时间, 每次等待事件前调用
+    pub fn idle_deadline(&mut self, now: Instant) -> Option<Instant> {
+        if self.state != State::Listening {
+            self.idle_deadline = None;
+        } else if self.idle_deadline.is_none() {
+            self.idle_deadline = self.config.idle_timeout.map(|timeout| now + timeout);
+        }
+        self.idle_deadline
+    }
+
+    // 是否正在把麦克风的语音发送给 server
+    pub fn is_capturing(&self) -> bool {
+        self.state == State::Listening || self.state == State::Recording
+    }
+
+    // server 连接断开, 正在播放的回复已经不完整了, 直接结束播放
+    // 返回还没有发送给 server 的麦克风音频
+    pub fn link_lost(&mut self) -> (Vec<Effect>, Vec<u8>) {
+        let mut effects = vec![];
+        if self.state == State::Speaking {
+            effects.push(Effect::Play(Playback::Interrupt));
+        }
+        self.playing = false;
+        self.response_ended = false;
+        self.cancelled = false;
+        self.barge_in_audio.clear();
+        let audio = std::mem::replace(&mut self.audio_buffer, Vec::with_capacity(8192));
+        (effects, audio)
+    }
+
+    // 重连成功, 重置状态机
+    // pending 是断线期间缓存的麦克风音频和期间是否已经收到 MicAudioEnd
+    // 补发这些音频后保持原来的收音状态, 没有需要补发的音频时回到 Idle
+    pub fn reconnected(&mut self, pending: Option<(Vec<u8>, bool)>) -> Vec<Effect> {
+        let mut effects = vec![];
+        match pending {
+            Some((data, ended)) if !data.is_empty() => {
+                self.submit_audio += data.len() as f32 / 32000.0;
+                effects.push(Effect::SendAudio { data, end: ended });
+                if ended {
+                    effects.push(Effect::Send(ClientEvent::EndOfUtterance {
+                        reason: self.end_reason(),
+                    }));
+                    self.submit_audio = 0.0;
+                    self.state = State::Wait;
+                }
+            }
+            _ => {
+                self.state = State::Idle;
+                self.submit_audio = 0.0;
+            }
+        }
+        effects.push(Effect::SetState(self.state.label().to_string()));
+        effects
+    }
+
+    fn end_reason(&self) -> EndReason {
+        if self.state == State::Listening {
+            EndReason::Normal
+        } else {
+            EndReason::Recording
+        }
+    }
+
+    // 停止播放当前的回复, 丢弃它剩余的 AudioChunk
+    fn cancel_response(&mut self, effects: &mut Vec<Effect>, now: Instant) {
+        effects.push(Effect::Play(Playback::Interrupt));
+        self.jitter.start(now);
+        self.playing = false;
+        self.response_ended = false;
+        self.cancelled = true;
+    }
+
+    // 处理一个事件, 返回需要按顺序执行的 Effect
+    // ServerEvent::AudioChunk 的 data 需要由调用者先用 Effect::Decoder 创建的解码器解码为 PCM
+    pub fn handle(&mut self, evt: Event, now: Instant) -> Vec<Effect> {
+        let mut effects = vec![];
+        match evt {
+            // 等待回复或者播放回复时按下 k0, 取消这次回复
+            Event::Event(Event::K0)
+                if self.state == State::Wait || self.state == State::Speaking =>
+            {
+                log::info!("Cancel current response by button");
+                self.cancel_response(&mut effects, now);
+                effects.push(Effect::Send(ClientEvent::Cancel));
+                self.barge_in_audio.clear();
+
+                self.state = State::Listening;
+                effects.push(Effect::SetState(self.state.label().to_string()));
+                effects.push(Effect::SetText(String::new()));
+            }
+            // 如果是 gaia 或 k0 事件,
+            Event::Event(key @ (Event::GAIA | Event::K0)) => {
+                log::info!("Received event: {}", key);
+                // 如果状态是Listening
+                if self.state == State::Listening {
+                    self.state = State::Idle; //切换至Idle
+                } else {
+                    // 先播放 hello 提示音, 播放完成后再开始收音
+                    effects.push(Effect::Play(Playback::Hello));
+                    // 更新为 Listening 状态
+                    self.state = State::Listening;
+                    // 唤醒词之前缓存的语音, 作为这一轮语音的开头
+                    if key == Event::GAIA && !self.preroll.is_empty() {
+                        self.submit_audio = self.preroll.len() as f32 / 32000.0;
+                        self.audio_buffer = std::mem::take(&mut self.preroll);
+                    }
+                }
+                effects.push(Effect::SetState(self.state.label().to_string()));
+                self.preroll.clear();
+            }
+            // 如果是按键松开事件
+            Event::Event(Event::K0_) => {
+                // 如果是Idle 或 Listening 状态
+                if self.state == State::Idle || self.state == State::Listening {
+                    log::info!("Received event: K0_");
+                    self.state = State::Recording; //更新为Recording状态
+                    effects.push(Effect::SetState(self.state.label().to_string()));
+                    effects.push(Effect::SetText(String::new()));
+                } else {
+                    log::warn!("Received K0_ while not idle");
+                }
+            }
+            // 离线命令词 reset, 任何状态都回到 Idle
+            Event::Event(Event::RESET) => {
+                log::info!("Received event: reset");
+                if self.state == State::Wait || self.state == State::Speaking {
+                    // 与按键打断一样, 停止播放并通知 server 取消这次回复
+                    self.cancel_response(&mut effects, now);
+                    effects.push(Effect::Send(ClientEvent::Cancel));
+                }
+                self.state = State::Idle;
+                self.audio_buffer.clear();
+                self.barge_in_audio.clear();
+                self.preroll.clear();
+                self.submit_audio = 0.0;
+                effects.push(Effect::SetState(self.state.label().to_string()));
+                effects.push(Effect::SetText(String::new()));
+            }
+            // 离线命令词 yes/no, 唤醒后作为确认结果发送给 server
+            Event::Event(key @ (Event::YES | Event::NO)) => {
+                if self.state == State::Idle {
+                    log::debug!("Ignore {} while idle", key);
+                } else {
+                    log::info!("Received event: {}", key);
+                    effects.push(Effect::Send(ClientEvent::Confirmation {
+                        accepted: key == Event::YES,
+                    }));
+                }
+            }
+            // 音量按键和命令词, 任何状态都可以调整
+            Event::Event(Event::K1 | Event::VOLUME_DOWN) => {
+                self.set_volume(volume::down(self.volume), &mut effects);
+            }
+            Event::Event(Event::K2 | Event::VOLUME_UP) => {
+                self.set_volume(volume::up(self.volume), &mut effects);
+            }
+            Event::Event(evt) => {
+                log::info!("Received event: {:?}", evt);
+            }
+            // 如果是 mic 的音频数据段
+            Event::MicAudioChunk(data) => self.on_mic_audio(data, now, &mut effects),
+            // 如果是 mic 音频结束事件
+            Event::MicAudioEnd => {
+                // 确保是 Listening 或 Recording 状态, 且submit_audio 已经累计超过 1.0s
+                // 则认为是一个有效的 mic 结束事件
+                if self.is_capturing() && self.submit_audio > 1.0 {
+                    // 将 buffer 里剩余的数据发送给 server
+                    let data = std::mem::replace(&mut self.audio_buffer, Vec::with_capacity(8192));
+                    effects.push(Effect::SendAudio { data, end: true });
+                    // 如果当前状态是 Listening, 则向 srever 发送 EndReason::Normal
+                    // 如果是其他状态, 则向 server 发送 EndReason::Recording
+                    effects.push(Effect::Send(ClientEvent::EndOfUtterance {
+                        reason: self.end_reason(),
+                    }));
+                    // 新的语音已经提交, 之后收到的是新的回复
+                    self.cancelled = false;
+                    // 等待 server 的回复
+                    self.state = State::Wait;
+                }
+                // 播放期间的语音太短, 不算打断
+                self.barge_in_audio.clear();
+                // 这段语音里没有唤醒词
+                self.preroll.clear();
+                self.submit_audio = 0.0;
+            }
+            // Listening 状态下长时间没有交互, 回到 Idle, 不再把麦克风的数据发给 server
+            Event::IdleTimeout => {
+                if self.state != State::Listening {
+                    return effects;
+                }
+                if self.submit_audio > 0.0 {
+                    // 用户正在说话, 重新计时
+                    self.idle_deadline = None;
+                    return effects;
+                }
+                log::info!("No interaction in Listening, back to Idle");
+                self.state = State::Idle;
+                self.audio_buffer.clear();
+                effects.push(Effect::SetState(self.state.label().to_string()));
+            }
+            // 扬声器播放完成, 如果回复也已经结束, 切换到 Listening
+            Event::PlaybackEnd => {
+                self.playing = false;
+                if self.state == State::Speaking && self.response_ended {
+                    self.response_ended = false;
+                    self.state = State::Listening;
+                    effects.push(Effect::SetState(self.state.label().to_string()));
+                }
+            }
+            Event::ServerEvent(evt) => self.on_server_event(evt, now, &mut effects),
+        }
+        effects
+    }
+
+    fn on_mic_audio(&mut self, data: Vec<u8>, now: Instant, effects: &mut Vec<Effect>) {
+        // 确保是 Listening 或 Recording 状态
+        if self.is_capturing() {
+            // 这里的比例 32000.0 是基于音频采样率和格式计算得出的：
+            // 采样率为 16000Hz（每秒16000个样本）
+            // 每个样本占用2字节（16位音频）
+            // 因此，每秒的音频数据大小为：16000 × 2 = 32000字节
+            // 所以， data.len() as f32 / 32000.0 计算的是当前音频数据块代表的时间长度（秒）。
+            self.submit_audio += data.len() as f32 / 32000.0;
+            // 将收到的数据填充到 audio_buffer 中
+            self.audio_buffer.extend_from_slice(&data);
+            // 如果 buffer 长度足够, 按协商好的 codec 编码后发送给 sever, 然后清空 buffer
+            if self.audio_buffer.len() >= 8192 {
+                let data = std::mem::replace(&mut self.audio_buffer, Vec::with_capacity(8192));
+                effects.push(Effect::SendAudio { data, end: false });
+            }
+        } else if let (State::Speaking, Some(barge_in)) = (self.state, self.config.barge_in) {
+            // 播放期间用户持续说话, 打断播放, 开始新一轮收音
+            self.barge_in_audio.extend_from_slice(&data);
+            if (self.barge_in_audio.len() as f32 / 32000.0) < barge_in.as_secs_f32() {
+                return;
+            }
+            log::info!("Barge-in detected, interrupting playback");
+            self.cancel_response(effects, now);
+            effects.push(Effect::Send(ClientEvent::Interrupt));
+
+            self.state = State::Listening;
+            effects.push(Effect::SetState(self.state.label().to_string()));
+            effects.push(Effect::SetText(String::new()));
+
+            self.submit_audio = self.barge_in_audio.len() as f32 / 32000.0;
+            self.audio_buffer = std::mem::take(&mut self.barge_in_audio);
+        } else if self.state == State::Idle {
+            // 只保留最近 wake_preroll 的语音
+            self.preroll.extend_from_slice(&data);
+            if self.preroll.len() > self.preroll_size {
+                self.preroll.drain(..self.preroll.len() - self.preroll_size);
+            }
+        } else {
+            log::debug!("Received MicAudioChunk while not listening");
+        }
+    }
+
+    // server 下发的 hello 不是支持的 WAV, 继续使用原来的音效, 并通知 server
+    fn reject_hello(&mut self, e: anyhow::Error, effects: &mut Vec<Effect>) {
+        log::error!("Invalid hello audio: {:?}", e);
+        self.new_hello = None;
+        effects.push(Effect::Play(Playback::SetHelloAbort));
+        effects.push(Effect::Send(ClientEvent::SettingsAck {
+            key: HELLO_ACK.to_string(),
+            ok: false,
+        }));
+        effects.push(Effect::SetState(format!("Hello rejected: {}", e)));
+    }
+
+    // server 下发的背景图超过大小限制, 继续使用原来的背景图
+    // 背景图是否是有效的 GIF 由执行者创建 gui 时检查, 并回复 SettingsAck
+    fn reject_background(&mut self, e: anyhow::Error, effects: &mut Vec<Effect>) {
+        log::error!("Invalid background: {:?}", e);
+        effects.push(Effect::Send(ClientEvent::SettingsAck {
+            key: BACKGROUND_ACK.to_string(),
+            ok: false,
+        }));
+        effects.push(Effect::SetState(format!("Background rejected: {}", e)));
+    }
+
+    fn on_server_event(&mut self, evt: ServerEvent, now: Instant, effects: &mut Vec<Effect>) {
+        match evt {
+            // 收到 server 的 ASR, 刷新到 gui
+            ServerEvent::ASR { text } => {
+                log::info!("Received ASR: {:?}", text);
+                effects.push(Effect::SetState("ASR".to_string()));
+                effects.push(Effect::SetText(text.trim().to_string()));
+            }
+            // 收到 server 的 Action(预留给语音指令?), 刷新到 gui
+            ServerEvent::Action { action } => {
+                log::info!("Received action");
+                effects.push(Effect::SetState(format!("Action: {}", action)));
+            }
+            // 收到 server 的 StartAudio, 刷新到 gui
+            ServerEvent::StartAudio {
+                text,
+                codec,
+                sample_rate,
+                channels,
+            } => {
+                log::info!(
+                    "Received audio start: {:?}, codec: {:?}, {} Hz x{}",
+                    text,
+                    codec,
+                    sample_rate,
+                    channels
+                );
+                if self.cancelled {
+                    log::info!("Ignore audio start of interrupted response");
+                    return;
+                }
+                effects.push(Effect::Decoder {
+                    codec,
+                    sample_rate,
+                    channels,
+                });
+                self.state = State::Speaking; //更新为 Speaking
+                self.response_ended = false;
+                self.barge_in_audio.clear();
+                effects.push(Effect::SetState(format!(
+                    "[{}ms]|{}",
+                    self.jitter.target().as_millis(),
+                    self.state.label()
+                )));
+                effects.push(Effect::SetText(text.trim().to_string()));
+                effects.push(Effect::Play(Playback::Start));
+                self.jitter.start(now);
+            }
+            // 收到 server 的 AudioChunk(已经解码为 PCM)
+            ServerEvent::AudioChunk { data } => {
+                log::info!("Received audio chunk");
+                // 确保是 Speaking 状态, 否则进入下一轮
+                if self.state != State::Speaking {
+                    log::warn!("Received audio chunk while not speaking");
+                    return;
+                }
+                // 先经过 jitter buffer, 缓存够目标深度后再送给扬声器播放
+                if let Some(data) = self.jitter.push(data, now) {
+                    effects.push(Effect::Play(Playback::Chunk(data)));
+                }
+            }
+            // 收到 server 的 EndAudio
+            ServerEvent::EndAudio => {
+                log::info!("Received audio end");
+                if self.state != State::Speaking {
+                    log::warn!("Received audio end while not speaking");
+                    return;
+                }
+                // 送出 jitter buffer 里剩余的数据
+                if let Some(data) = self.jitter.finish(now) {
+                    effects.push(Effect::Play(Playback::Chunk(data)));
+                }
+                log::info!(
+                    "Jitter buffer target: {:?}, stats: {:?}",
+                    self.jitter.target(),
+                    self.jitter.stats()
+                );
+                // 扬声器播放完成后会产生 PlaybackEnd 事件
+                effects.push(Effect::Play(Playback::End));
+                self.playing = true;
+            }
+            // 收到 server 的 EndResponse
+            ServerEvent::EndResponse => {
+                log::info!("Received request end");
+                if self.cancelled {
+                    // 被打断的回复结束了, 保持当前的收音状态
+                    self.cancelled = false;
+                    return;
+                }
+                if self.playing {
+                    // 还在播放, 等 PlaybackEnd 再切换状态
+                    self.response_ended = true;
+                    return;
+                }
+                self.state = State::Listening;
+                effects.push(Effect::SetState(self.state.label().to_string()));
+            }
+            // 以下是 hello 相关的分支, server 下发的是 WAV 文件, 转换为 PCM 后再交给扬声器
+            ServerEvent::HelloStart => {
+                self.new_hello = Some(WavParser::new(self.config.jitter.sample_rate));
+                self.new_hello_size = 0;
+                effects.push(Effect::Play(Playback::SetHelloStart));
+            }
+            ServerEvent::HelloChunk { data } => {
+                log::info!("Received hello chunk");
+                let Some(parser) = self.new_hello.as_mut() else {
+                    log::warn!("Ignore hello chunk");
+                    return;
+                };
+                match parser.push(&data) {
+                    Ok(pcm) if self.new_hello_size + pcm.len() > MAX_HELLO_SIZE => {
+                        let e =
+                            anyhow::anyhow!("Hello audio is longer than {} bytes", MAX_HELLO_SIZE);
+                        self.reject_hello(e, effects);
+                    }
+                    Ok(pcm) if pcm.is_empty() => {}
+                    Ok(pcm) => {
+                        self.new_hello_size += pcm.len();
+                        effects.push(Effect::Play(Playback::SetHelloChunk(pcm)));
+                    }
+                    Err(e) => self.reject_hello(e, effects),
+                }
+            }
+            ServerEvent::HelloEnd => {
+                log::info!("Received hello end");
+                let Some(mut parser) = self.new_hello.take() else {
+                    log::warn!("Ignore hello end");
+                    return;
+                };
+                // 重采样剩下的最后一段也属于新的 hello
+                match parser.finish() {
+                    Ok(pcm) if self.new_hello_size + pcm.len() > MAX_HELLO_SIZE => {
+                        let e =
+                            anyhow::anyhow!("Hello audio is longer than {} bytes", MAX_HELLO_SIZE);
+                        self.reject_hello(e, effects);
+                        return;
+                    }
+                    Ok(pcm) if pcm.is_empty() => {}
+                    Ok(pcm) => {
+                        self.new_hello_size += pcm.len();
+                        effects.push(Effect::Play(Playback::SetHelloChunk(pcm)));
+                    }
+                    Err(e) => {
+                        self.reject_hello(e, effects);
+                        return;
+                    }
+                }
+                effects.push(Effect::Play(Playback::SetHelloEnd));
+                effects.push(Effect::Send(ClientEvent::SettingsAck {
+                    key: HELLO_ACK.to_string(),
+                    ok: true,
+                }));
+                effects.push(Effect::SetState("Hello set".to_string()));
+            }
+            ServerEvent::ResetHello => {
+                log::info!("Received hello reset");
+                if self.new_hello.take().is_some() {
+                    effects.push(Effect::Play(Playback::SetHelloAbort));
+                }
+                effects.push(Effect::Play(Playback::ResetHello));
+                effects.push(Effect::Send(ClientEvent::SettingsAck {
+                    key: HELLO_ACK.to_string(),
+                    ok: true,
+                }));
+                effects.push(Effect::SetState("Hello reset".to_string()));
+            }
+            // 以下是背景图片相关的分支
+            ServerEvent::BGStart => {
+                self.new_gui_bg = Some(vec![]);
+            }
+            ServerEvent::BGChunk { data } => {
+                log::info!("Received background chunk");
+                let Some(bg) = self.new_gui_bg.as_mut() else {
+                    log::warn!("Ignore background chunk");
+                    return;
+                };
+                if bg.len() + data.len() > MAX_BACKGROUND_SIZE {
+                    self.new_gui_bg = None;
+                    let e =
+                        anyhow::anyhow!("Background is larger than {} bytes", MAX_BACKGROUND_SIZE);
+                    self.reject_background(e, effects);
+                    return;
+                }
+                bg.extend(data);
+            }
+            ServerEvent::BGEnd => {
+                log::info!("Received background end");
+                match self.new_gui_bg.take() {
+                    Some(bg) if !bg.is_empty() => effects.push(Effect::Background(bg)),
+                    Some(_) => {
+                        let e = anyhow::anyhow!("Received empty background data");
+                        self.reject_background(e, effects);
+                    }
+                    None => log::warn!("Ignore background end"),
+                }
+            }
+            // 预留给video
+            ServerEvent::StartVideo | ServerEvent::EndVideo => {}
+            // 握手只在连接时进行, 会话中收到说明 server 状态异常
+            ServerEvent::HandshakeAccept { .. } => {
+                log::warn!("Received handshake accept after connected");
+            }
+            ServerEvent::HandshakeReject { reason } => {
+                effects.push(Effect::SetState(format!("Rejected: {}", reason)));
+                effects.push(Effect::Rejected(reason));
+            }
+            ServerEvent::SetVolume { volume } => {
+                self.set_volume(volume, effects);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn speech(ms: u64) -> Vec<u8> {
+    vec![0u8; (32 * ms) as usize]
+}
+
+#[test]
+fn test_conversation_wake_and_submit() {
+    let mut conv = Conversation::new(Config::default());
+    let t0 = Instant::now();
+
+    // Idle 状态下的语音只保留最近 500ms 作为 preroll
+    assert!(conv
+        .handle(Event::MicAudioChunk(speech(800)), t0)
+        .is_empty());
+    let effects = conv.handle(Event::Event(Event::GAIA), t0);
+    assert_eq!(
+        effects,
+        vec![
+            Effect::Play(Playback::Hello),
+            Effect::SetState("Listening...".to_string()),
+        ]
+    );
+    assert_eq!(conv.state(), State::Listening);
+    let deadline = conv.idle_deadline(t0).unwrap();
+    assert_eq!(deadline, t0 + Duration::from_secs(30));
+
+    // preroll 作为这一轮语音的开头, 满 8192 字节发送一次
+    let effects = conv.handle(Event::MicAudioChunk(speech(100)), t0);
+    assert_eq!(
+        effects,
+        vec![Effect::SendAudio {
+            data: speech(600),
+            end: false
+        }]
+    );
+    conv.handle(Event::MicAudioChunk(speech(450)), t0);
+    let effects = conv.handle(Event::MicAudioEnd, t0);
+    assert_eq!(
+        effects,
+        vec![
+            Effect::SendAudio {
+                data: vec![],
+                end: true
+            },
+            Effect::Send(ClientEvent::EndOfUtterance {
+                reason: EndReason::Normal
+            }),
+        ]
+    );
+    assert_eq!(conv.state(), State::Wait);
+    assert_eq!(conv.idle_deadline(t0), None);
+}
+
+#[test]
+fn test_conversation_response_playback() {
+    let mut conv = Conversation::new(Config::default());
+    let t0 = Instant::now();
+    conv.handle(Event::Event(Event::K0_), t0);
+    assert_eq!(conv.state(), State::Recording);
+    conv.handle(Event::MicAudioChunk(speech(1200)), t0);
+    conv.handle(Event::MicAudioEnd, t0);
+    assert_eq!(conv.state(), State::Wait);
+
+    let start = ServerEvent::StartAudio {
+        text: " hi ".to_string(),
+        codec: AudioCodec::Opus,
+        sample_rate: 24000,
+        channels: 1,
+    };
+    let effects = conv.handle(Event::ServerEvent(start), t0);
+    assert_eq!(
+        effects[0],
+        Effect::Decoder {
+            codec: AudioCodec::Opus,
+            sample_rate: 24000,
+            channels: 1
+        }
+    );
+    assert!(effects.contains(&Effect::SetText("hi".to_string())));
+    assert_eq!(effects.last(), Some(&Effect::Play(Playback::Start)));
+    assert_eq!(conv.state(), State::Speaking);
+
+    // 短回复在 EndAudio 时一次性送出
+    let chunk = ServerEvent::AudioChunk { data: speech(100) };
+    assert!(conv.handle(Event::ServerEvent(chunk), t0).is_empty());
+    let effects = conv.handle(Event::ServerEvent(ServerEvent::EndAudio), t0);
+    assert_eq!(
+        effects,
+        vec![
+            Effect::Play(Playback::Chunk(speech(100))),
+            Effect::Play(Playback::End),
+        ]
+    );
+
+    // 播放完成前收到 EndResponse, 等 PlaybackEnd 再回到 Listening
+    let effects = conv.handle(Event::ServerEvent(ServerEvent::EndResponse), t0);
+    assert!(effects.is_empty());
+    assert_eq!(conv.state(), State::Speaking);
+    let effects = conv.handle(Event::PlaybackEnd, t0);
+    assert_eq!(effects, vec![Effect::SetState("Listening...".to_string())]);
+    assert_eq!(conv.state(), State::Listening);
+}
+
+#[test]
+fn test_conversation_barge_in() {
+    let mut conv = Conversation::new(Config::default());
+    let t0 = Instant::now();
+    conv.handle(Event::Event(Event::K0), t0);
+    conv.handle(Event::MicAudioChunk(speech(1200)), t0);
+    conv.handle(Event::MicAudioEnd, t0);
+    let start = ServerEvent::StartAudio {
+        text: String::new(),
+        codec: AudioCodec::Pcm16,
+        sample_rate: 16000,
+        channels: 1,
+    };
+    conv.handle(Event::ServerEvent(start), t0);
+
+    // 说话不足 400ms 不算打断
+    assert!(conv
+        .handle(Event::MicAudioChunk(speech(300)), t0)
+        .is_empty());
+    let effects = conv.handle(Event::MicAudioChunk(speech(200)), t0);
+    assert_eq!(effects[0], Effect::Play(Playback::Interrupt));
+    assert_eq!(effects[1], Effect::Send(ClientEvent::Interrupt));
+    assert_eq!(conv.state(), State::Listening);
+
+    // 被打断的回复剩余的数据直接丢弃
+    let start = ServerEvent::StartAudio {
+        text: String::new(),
+        codec: AudioCodec::Pcm16,
+        sample_rate: 16000,
+        channels: 1,
+    };
+    assert!(conv.handle(Event::ServerEvent(start), t0).is_empty());
+    let effects = conv.handle(Event::ServerEvent(ServerEvent::EndResponse), t0);
+    assert!(effects.is_empty());
+    assert_eq!(conv.state(), State::Listening);
+
+    // 打断时的语音作为新一轮语音的开头
+    let effects = conv.handle(Event::MicAudioChunk(speech(100)), t0);
+    assert_eq!(
+        effects,
+        vec![Effect::SendAudio {
+            data: speech(600),
+            end: false
+        }]
+    );
+}
+
+#[test]
+fn test_conversation_cancel_and_reset() {
+    let mut conv = Conversation::new(Config::default());
+    let t0 = Instant::now();
+    conv.handle(Event::Event(Event::K0), t0);
+    conv.handle(Event::MicAudioChunk(speech(1200)), t0);
+    conv.handle(Event::MicAudioEnd, t0);
+    assert_eq!(conv.state(), State::Wait);
+
+    let effects = conv.handle(Event::Event(Event::K0), t0);
+    assert_eq!(effects[0], Effect::Play(Playback::Interrupt));
+    assert_eq!(effects[1], Effect::Send(ClientEvent::Cancel));
+    assert_eq!(conv.state(), State::Listening);
+
+    let effects = conv.handle(Event::Event(Event::YES), t0);
+    assert_eq!(
+        effects,
+        vec![Effect::Send(ClientEvent::Confirmation { accepted: true })]
+    );
+
+    conv.handle(Event::Event(Event::RESET), t0);
+    assert_eq!(conv.state(), State::Idle);
+    assert!(conv.handle(Event::Event(Event::NO), t0).is_empty());
+}
+
+#[test]
+fn test_conversation_volume() {
+    let mut conv = Conversation::new(Config {
+        volume: 95,
+        ..Default::default()
+    });
+    let t0 = Instant::now();
+
+    let effects = conv.handle(Event::Event(Event::K2), t0);
+    assert_eq!(effects, vec![Effect::SetVolume(volume::MAX)]);
+    conv.handle(Event::Event(Event::VOLUME_UP), t0);
+    assert_eq!(conv.volume(), volume::MAX);
+
+    // 调整音量不影响对话状态
+    conv.handle(Event::Event(Event::K0), t0);
+    let effects = conv.handle(Event::Event(Event::K1), t0);
+    assert_eq!(effects, vec![Effect::SetVolume(90)]);
+    assert_eq!(conv.state(), State::Listening);
+    conv.handle(Event::Event(Event::VOLUME_DOWN), t0);
+    assert_eq!(conv.volume(), 80);
+
+    // server 设置的音量超出范围时取最大值
+    let effects = conv.handle(
+        Event::ServerEvent(ServerEvent::SetVolume { volume: 150 }),
+        t0,
+    );
+    assert_eq!(effects, vec![Effect::SetVolume(volume::MAX)]);
+    conv.handle(Event::ServerEvent(ServerEvent::SetVolume { volume: 0 }), t0);
+    assert_eq!(conv.volume(), 0);
+    conv.handle(Event::Event(Event::K1), t0);
+    assert_eq!(conv.volume(), 0);
+}
+
+#[test]
+fn test_conversation_idle_timeout() {
+    let mut conv = Conversation::new(Config::default());
+    let t0 = Instant::now();
+    conv.handle(Event::Event(Event::K0), t0);
+
+    // 用户正在说话时重新计时
+    conv.handle(Event::MicAudioChunk(speech(100)), t0);
+    assert!(conv.handle(Event::IdleTimeout, t0).is_empty());
+    let t1 = t0 + Duration::from_secs(1);
+    assert_eq!(conv.idle_deadline(t1), Some(t1 + Duration::from_secs(30)));
+
+    conv.handle(Event::MicAudioEnd, t1);
+    let effects = conv.handle(Event::IdleTimeout, t1);
+    assert_eq!(effects, vec![Effect::SetState("Idle".to_string())]);
+    assert_eq!(conv.state(), State::Idle);
+}
+
+#[test]
+fn test_conversation_reconnect() {
+    let mut conv = Conversation::new(Config::default());
+    let t0 = Instant::now();
+    conv.handle(Event::Event(Event::K0_), t0);
+    conv.handle(Event::MicAudioChunk(speech(100)), t0);
+
+    let (effects, audio) = conv.link_lost();
+    assert!(effects.is_empty());
+    assert_eq!(audio, speech(100));
+    assert!(conv.is_capturing());
+
+    // 断线期间说完了一句话, 补发后等待回复
+    let effects = conv.reconnected(Some((speech(1000), true)));
+    assert_eq!(
+        effects,
+        vec![
+            Effect::SendAudio {
+                data: speech(1000),
+                end: true
+            },
+            Effect::Send(ClientEvent::EndOfUtterance {
+                reason: EndReason::Recording
+            }),
+            Effect::SetState("Waiting...".to_string()),
+        ]
+    );
+    assert_eq!(conv.state(), State::Wait);
+
+    conv.link_lost();
+    assert_eq!(
+        conv.reconnected(None),
+        vec![Effect::SetState("Idle".to_string())]
+    );
+}
+
+#[test]
+fn test_conversation_set_hello() {
+    let mut conv = Conversation::new(Config::default());
+    let t0 = Instant::now();
+    let hello = include_bytes!("../assets/hello.wav");
+    let mut server = |evt| conv.handle(Event::ServerEvent(evt), t0);
+
+    // 转换为 PCM 后再交给扬声器, WAV 头不会被播放
+    assert_eq!(
+        server(ServerEvent::HelloStart),
+        vec![Effect::Play(Playback::SetHelloStart)]
+    );
+    let mut pcm = vec![];
+    for data in hello.chunks(4096) {
+        for effect in server(ServerEvent::HelloChunk {
+            data: data.to_vec(),
+        }) {
+            match effect {
+                Effect::Play(Playback::SetHelloChunk(data)) => pcm.extend(data),
+                effect => panic!("Unexpected effect: {:?}", effect),
+            }
+        }
+    }
+    assert_eq!(pcm, crate::wav::decode(hello, 16000).unwrap());
+    let effects = server(ServerEvent::HelloEnd);
+    assert_eq!(effects[0], Effect::Play(Playback::SetHelloEnd));
+    assert_eq!(
+        effects[1],
+        Effect::Send(ClientEvent::SettingsAck {
+            key: HELLO_ACK.to_string(),
+            ok: true
+        })
+    );
+
+    // 不支持的格式通知 server, 忽略剩余的数据
+    server(ServerEvent::HelloStart);
+    let effects = server(ServerEvent::HelloChunk {
+        data: b"ID3\x04 mp3 data".to_vec(),
+    });
+    assert_eq!(effects[0], Effect::Play(Playback::SetHelloAbort));
+    assert_eq!(
+        effects[1],
+        Effect::Send(ClientEvent::SettingsAck {
+            key: HELLO_ACK.to_string(),
+            ok: false
+        })
+    );
+    assert!(server(ServerEvent::HelloChunk { data: vec![0; 100] }).is_empty());
+    assert!(server(ServerEvent::HelloEnd).is_empty());
+
+    // 超过最大长度
+    server(ServerEvent::HelloStart);
+    let at = hello.windows(4).position(|w| w == b"data").unwrap();
+    let mut long = hello[..at].to_vec();
+    long.extend_from_slice(b"data\0\0\0\0");
+    server(ServerEvent::HelloChunk { data: long });
+    let effects = server(ServerEvent::HelloChunk {
+        data: vec![0; MAX_HELLO_SIZE + 2],
+    });
+    assert_eq!(effects[0], Effect::Play(Playback::SetHelloAbort));
+
+    let effects = server(ServerEvent::ResetHello);
+    assert_eq!(effects[0], Effect::Play(Playback::ResetHello));
+}
+
+#[test]
+fn test_conversation_background() {
+    let mut conv = Conversation::new(Config::default());
+    let t0 = Instant::now();
+    let mut server = |evt| conv.handle(Event::ServerEvent(evt), t0);
+
+    server(ServerEvent::BGStart);
+    server(ServerEvent::BGChunk {
+        data: b"GIF89a".to_vec(),
+    });
+    server(ServerEvent::BGChunk { data: vec![0; 10] });
+    let effects = server(ServerEvent::BGEnd);
+    assert_eq!(
+        effects,
+        vec![Effect::Background([&b"GIF89a"[..], &[0; 10]].concat())]
+    );
+
+    // 超过大小限制时通知 server, 忽略剩余的数据
+    server(ServerEvent::BGStart);
+    let effects = server(ServerEvent::BGChunk {
+        data: vec![0; MAX_BACKGROUND_SIZE + 1],
+    });
+    assert_eq!(
+        effects[0],
+        Effect::Send(ClientEvent::SettingsAck {
+            key: BACKGROUND_ACK.to_string(),
+            ok: false
+        })
+    );
+    assert!(server(ServerEvent::BGChunk { data: vec![0; 10] }).is_empty());
+    assert!(server(ServerEvent::BGEnd).is_empty());
+}
@@ -0,0 +1,667 @@
+// 执行状态机产生的 Effect, 与具体的设备无关, 设备上的 app::main_work 和模拟器共用
+// - 发送给 server: ws::ReconnectingServer
+// - 送给扬声器: player 的消息队列, 设备上由 player::run 播放, 模拟器保存到文件
+// - 刷新屏幕: ui::UI, 输出到 ui::set_display 设置的 DisplaySink
+// - Opus 编解码需要 esp_audio_codec, 由 Codecs 提供, 模拟器不支持
+use tokio::sync::mpsc;
+
+use crate::{
+    assets::{AssetKind, AssetStore, BACKGROUND_ASSET},
+    conversation::{self, Effect, Event, Playback},
+    player::{AudioData, PlayerTx, SAMPLE_RATE},
+    protocol::{AudioCodec, ClientEvent, ServerEvent},
+    resample::Resampler,
+    ui::UI,
+    ws::ReconnectingServer,
+};
+
+// 下行音频的解码器, 输出 16bit mono PCM
+pub trait Decode {
+    fn decode(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+// 上行音频的编码器, 输入 16bit mono PCM
+pub trait Encode {
+    // 返回编码好的完整帧, 不足一帧的数据留到下一次
+    fn encode(&mut self, pcm: &[u8]) -> anyhow::Result<Vec<Vec<u8>>>;
+    // 一段语音结束时调用, 编码剩余的数据
+    fn flush(&mut self) -> anyhow::Result<Option<Vec<u8>>>;
+}
+
+// 设备提供的 Opus 编解码, ESP 上的实现见 codec::EspCodecs
+pub trait Codecs {
+    fn opus_decoder(&self, sample_rate: u32) -> anyhow::Result<Box<dyn Decode>>;
+    fn opus_encoder(&self, sample_rate: u32) -> anyhow::Result<Box<dyn Encode>>;
+}
+
+// 不支持 Opus 的设备(模拟器), 握手时也不要声明 Opus
+pub struct NoOpus;
+
+impl Codecs for NoOpus {
+    fn opus_decoder(&self, _sample_rate: u32) -> anyhow::Result<Box<dyn Decode>> {
+        anyhow::bail!("Opus is not supported")
+    }
+
+    fn opus_encoder(&self, _sample_rate: u32) -> anyhow::Result<Box<dyn Encode>> {
+        anyhow::bail!("Opus is not supported")
+    }
+}
+
+// 监听 evt_rx(from 麦克风), server(from服务器), 扬声器播放完成和空闲超时的事件
+// server 连接断开时返回 Err, 由调用者负责重连
+async fn select_evt(
+    evt_rx: &mut mpsc::Receiver<Event>,
+    server: &mut ReconnectingServer,
+    playback: &mut Option<tokio::sync::oneshot::Receiver<()>>,
+    idle_deadline: Option<tokio::time::Instant>,
+) -> anyhow::Result<Option<Event>> {
+    tokio::select! {
+        // select! 总会求值分支的表达式, unwrap 要放在 async 块里, 只在 poll 时执行
+        _ = async { tokio::time::sleep_until(idle_deadline.unwrap()).await }, if idle_deadline.is_some() => {
+            log::info!("Idle timeout");
+            Ok(Some(Event::IdleTimeout))
+        }
+        _ = async { playback.as_mut().unwrap().await }, if playback.is_some() => {
+            log::info!("Playback end");
+            *playback = None;
+            Ok(Some(Event::PlaybackEnd))
+        }
+        evt = evt_rx.recv() => {
+            match &evt {
+                Some(Event::Event(_))=>{
+                    log::info!("Received event: {:?}", evt);
+                },
+                Some(Event::MicAudioEnd)=>{
+                    log::info!("Received MicAudioEnd");
+                },
+                Some(Event::MicAudioChunk(data))=>{
+                    log::debug!("Received MicAudioChunk with {} bytes", data.len());
+                },
+                Some(Event::PlaybackEnd | Event::IdleTimeout)=>{},
+                Some(Event::ServerEvent(_))=>{
+                    log::info!("Received ServerEvent: {:?}", evt);
+                },
+                None=>{
+                    log::info!("No events");
+                },
+            }
+            Ok(evt)
+        }
+        msg = server.recv() => {
+            let msg = msg?;
+            match msg {
+                Event::ServerEvent(ServerEvent::AudioChunk { .. })=>{
+                    log::info!("Received AudioChunk");
+                }
+                Event::ServerEvent(ServerEvent::HelloChunk { .. })=>{
+                    log::info!("Received HelloChunk");
+                }
+                Event::ServerEvent(ServerEvent::BGChunk { .. })=>{
+                    log::info!("Received BGChunk");
+                }
+                _=> {
+                    log::info!("Received message: {:?}", msg);
+                }
+            }
+            Ok(Some(msg))
+        }
+    }
+}
+
+// 上行音频的编码方式, 由握手时 server 接受的 codec 决定
+// server 不支持 Opus 或者编码器创建失败时, 退回到原始 PCM
+enum Uplink {
+    Pcm,
+    Opus(Box<dyn Encode>),
+}
+
+impl Uplink {
+    fn new(codecs: &[AudioCodec], device: &impl Codecs) -> Self {
+        if codecs.contains(&AudioCodec::Opus) {
+            match device.opus_encoder(SAMPLE_RATE) {
+                Ok(encoder) => return Uplink::Opus(encoder),
+                Err(e) => log::error!("Failed to create opus encoder, fallback to pcm: {:?}", e),
+            }
+        }
+        Uplink::Pcm
+    }
+
+    // 将一批麦克风 PCM 按协商好的 codec 发送给 server
+    // end 表示本段语音已经结束, 需要把编码器里剩余的数据也发出去
+    async fn send(
+        &mut self,
+        server: &mut ReconnectingServer,
+        data: Vec<u8>,
+        end: bool,
+    ) -> anyhow::Result<()> {
+        match self {
+            Uplink::Pcm => {
+                if !data.is_empty() {
+                    server.send(ClientEvent::AudioChunk { data }).await?;
+                }
+            }
+            Uplink::Opus(encoder) => {
+                let mut frames = encoder.encode(&data)?;
+                if end {
+                    frames.extend(encoder.flush()?);
+                }
+                if !frames.is_empty() {
+                    server.send(ClientEvent::OpusChunk { frames }).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// 下行音频的解码器, 在送给播放器之前统一转换为 16bit mono PCM
+enum Downlink {
+    Pcm,
+    Adpcm,
+    Opus(Box<dyn Decode>),
+}
+
+impl Downlink {
+    fn decode(&mut self, data: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Downlink::Pcm => Ok(data),
+            Downlink::Adpcm => crate::adpcm::decode_block(&data),
+            Downlink::Opus(decoder) => decoder.decode(&data),
+        }
+    }
+}
+
+// server 在会话中途拒绝了设备(HandshakeReject), 原因已经显示在屏幕上
+#[derive(Debug)]
+pub struct Rejected {
+    pub reason: String,
+}
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server rejected the device: {}", self.reason)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+// 在单独的线程里把背景图写入 flash, 写入可能需要几百毫秒
+// 这期间 runtime 上的其他任务(扬声器和麦克风)不会被阻塞
+async fn save_background(assets: AssetStore, data: Vec<u8>) -> bool {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    let spawned = std::thread::Builder::new().stack_size(8192).spawn(move || {
+        let _ = tx.send(assets.put(BACKGROUND_ASSET, AssetKind::Background, &data));
+    });
+    if let Err(e) = spawned {
+        log::error!("Failed to spawn the background writer: {:?}", e);
+        return false;
+    }
+    match rx.await {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            log::error!("Failed to save background: {:?}", e);
+            false
+        }
+        Err(_) => {
+            log::error!("Background writer exited without a result");
+            false
+        }
+    }
+}
+
+pub struct Executor<C: Codecs> {
+    pub server: ReconnectingServer,
+    pub gui: UI,
+    // 等待扬声器播放完成的 ack, 不阻塞主循环, 这样播放期间也能处理麦克风事件
+    pub playback: Option<tokio::sync::oneshot::Receiver<()>>,
+    player_tx: PlayerTx,
+    device: C,
+    uplink: Uplink,
+    // 下行音频的解码器和重采样, 每次 StartAudio 时根据 codec 和音频格式重新创建
+    decoder: Downlink,
+    resampler: Resampler,
+    // 保存调整后的音量, 下次启动时使用
+    save_volume: Box<dyn FnMut(u8)>,
+    // 保存 server 下发的背景图, 下次启动时使用, None 时不保存并回复失败
+    assets: Option<AssetStore>,
+}
+
+impl<C: Codecs> Executor<C> {
+    pub fn new(
+        server: ReconnectingServer,
+        player_tx: PlayerTx,
+        gui: UI,
+        device: C,
+        save_volume: impl FnMut(u8) + 'static,
+        assets: Option<AssetStore>,
+    ) -> anyhow::Result<Self> {
+        Ok(Self {
+            uplink: Uplink::new(server.codecs(), &device),
+            server,
+            gui,
+            playback: None,
+            player_tx,
+            device,
+            decoder: Downlink::Pcm,
+            resampler: Resampler::new(SAMPLE_RATE, 1, SAMPLE_RATE)?,
+            save_volume: Box::new(save_volume),
+            assets,
+        })
+    }
+
+    // 重连成功后调用, 新的连接可能协商出不同的 codec
+    pub fn reconnected(&mut self) {
+        self.uplink = Uplink::new(self.server.codecs(), &self.device);
+    }
+
+    // 等待下一个事件, 见 select_evt
+    pub async fn next_event(
+        &mut self,
+        evt_rx: &mut mpsc::Receiver<Event>,
+        idle_deadline: Option<tokio::time::Instant>,
+    ) -> anyhow::Result<Option<Event>> {
+        select_evt(evt_rx, &mut self.server, &mut self.playback, idle_deadline).await
+    }
+
+    // 下行音频先解码为 PCM 并转换为播放器的采样率, 再交给状态机
+    // speaking 为 false 时状态机会丢弃音频, 不需要解码; 解码失败时返回 None
+    pub fn decode(&mut self, evt: Event, speaking: bool) -> Option<Event> {
+        match evt {
+            Event::ServerEvent(ServerEvent::AudioChunk { data }) if speaking => {
+                match self.decoder.decode(data) {
+                    Ok(data) => Some(Event::ServerEvent(ServerEvent::AudioChunk {
+                        data: self.resampler.process(&data),
+                    })),
+                    Err(e) => {
+                        log::error!("Error decoding audio chunk: {:?}", e);
+                        None
+                    }
+                }
+            }
+            evt => Some(evt),
+        }
+    }
+
+    pub async fn run(&mut self, effects: Vec<Effect>) -> anyhow::Result<()> {
+        let mut flush = false;
+        for effect in effects {
+            match effect {
+                Effect::Send(evt) => self.server.send(evt).await?,
+                Effect::SendAudio { data, end } => {
+                    self.uplink.send(&mut self.server, data, end).await?
+                }
+                Effect::Play(playback) => self.play(playback).await?,
+                Effect::Decoder {
+                    codec,
+                    sample_rate,
+                    channels,
+                } => self.set_decoder(codec, sample_rate, channels)?,
+                Effect::SetState(state) => {
+                    log::info!("UI state: {}", state);
+                    self.gui.state = state;
+                    // 状态变化时不再显示音量条
+                    self.gui.volume = None;
+                    flush = true;
+                }
+                Effect::SetText(text) => {
+                    log::info!("UI text: {}", text);
+                    self.gui.text = text;
+                    flush = true;
+                }
+                Effect::Background(data) => {
+                    let ok = match UI::new(Some(&data)) {
+                        Ok(new_gui) => {
+                            self.gui = new_gui;
+                            self.gui.state = "Background data loaded".to_string();
+                            // 先显示新的背景图, 再等待保存完成
+                            self.gui.display_flush().unwrap();
+                            match self.assets.clone() {
+                                Some(assets) => save_background(assets, data).await,
+                                None => {
+                                    log::warn!("No asset storage, background is not saved");
+                                    false
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("Error creating GUI from background data: {:?}", e);
+                            self.gui.state = "Error on background data".to_string();
+                            false
+                        }
+                    };
+                    self.server
+                        .send(ClientEvent::SettingsAck {
+                            key: conversation::BACKGROUND_ACK.to_string(),
+                            ok,
+                        })
+                        .await?;
+                    flush = true;
+                }
+                Effect::Rejected(reason) => {
+                    // 状态行保留拒绝的原因(SetState), 由调用者决定什么时候重启
+                    self.gui.text = "Press K0 to restart".to_string();
+                    self.gui.display_flush().unwrap();
+                    return Err(Rejected { reason }.into());
+                }
+                Effect::SetVolume(volume) => {
+                    self.send_audio(AudioData::Volume(volume))?;
+                    (self.save_volume)(volume);
+                    self.gui.volume = Some(volume);
+                    flush = true;
+                }
+            }
+        }
+        if flush {
+            self.gui.display_flush().unwrap();
+        }
+        Ok(())
+    }
+
+    fn set_decoder(
+        &mut self,
+        codec: AudioCodec,
+        sample_rate: u32,
+        channels: u8,
+    ) -> anyhow::Result<()> {
+        let decoder = match codec {
+            AudioCodec::Pcm16 => Ok(Downlink::Pcm),
+            AudioCodec::Adpcm => Ok(Downlink::Adpcm),
+            AudioCodec::Opus => self.device.opus_decoder(SAMPLE_RATE).map(Downlink::Opus),
+        };
+        self.decoder = match decoder {
+            Ok(decoder) => decoder,
+            Err(e) => {
+                log::error!("Error creating {:?} decoder: {:?}", codec, e);
+                self.gui.state = format!("Unsupported codec {:?}", codec);
+                self.gui.display_flush().unwrap();
+                Downlink::Pcm
+            }
+        };
+        // Opus 解码器直接输出播放器的采样率和声道数
+        let (sample_rate, channels) = match codec {
+            AudioCodec::Opus => (SAMPLE_RATE, 1),
+            _ => (sample_rate, channels),
+        };
+        self.resampler = match Resampler::new(sample_rate, channels, SAMPLE_RATE) {
+            Ok(resampler) => resampler,
+            Err(e) => {
+                log::error!("Error creating resampler: {:?}", e);
+                self.gui.state = e.to_string();
+                self.gui.display_flush().unwrap();
+                Resampler::new(SAMPLE_RATE, 1, SAMPLE_RATE)?
+            }
+        };
+        Ok(())
+    }
+
+    async fn play(&mut self, playback: Playback) -> anyhow::Result<()> {
+        match playback {
+            Playback::Hello => {
+                // 创建 oneshot 的channel, 将 tx 使用 AudioData::Hello 封装后发送给扬声器线程
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                self.send_audio(AudioData::Hello(tx))?;
+                log::info!("Waiting for hello response");
+                // 扬声器线程播放完成后回复的 ack
+                let _ = rx.await;
+                log::info!("Hello response received");
+            }
+            Playback::End => {
+                // 重采样剩下的最后一段也要播放
+                let tail = self.resampler.flush();
+                if !tail.is_empty() {
+                    self.send_audio(AudioData::Chunk(tail))?;
+                }
+                // 不在这里等待 ack, 由 select_evt 收到后产生 PlaybackEnd 事件
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                self.send_audio(AudioData::End(tx))?;
+                self.playback = Some(rx);
+            }
+            Playback::Interrupt => {
+                // 丢弃被打断的语音在重采样里剩下的数据
+                self.resampler.flush();
+                self.send_audio(AudioData::Interrupt)?;
+                self.playback = None;
+            }
+            Playback::Start => self.send_audio(AudioData::Start)?,
+            Playback::Chunk(data) => self.send_audio(AudioData::Chunk(data))?,
+            Playback::SetHelloStart => self.send_audio(AudioData::SetHelloStart)?,
+            Playback::SetHelloChunk(data) => self.send_audio(AudioData::SetHelloChunk(data))?,
+            Playback::SetHelloEnd => self.send_audio(AudioData::SetHelloEnd)?,
+            Playback::SetHelloAbort => self.send_audio(AudioData::SetHelloAbort)?,
+            Playback::ResetHello => self.send_audio(AudioData::ResetHello)?,
+        }
+        Ok(())
+    }
+
+    fn send_audio(&self, data: AudioData) -> anyhow::Result<()> {
+        self.player_tx
+            .send(data)
+            .map_err(|e| anyhow::anyhow!("Error sending audio data: {e:?}"))
+    }
+}
+
+#[tokio::test]
+async fn test_executor_save_background() {
+    let dir = crate::assets::test_dir("executor");
+    let assets = AssetStore::open(&dir).unwrap();
+
+    assert!(save_background(assets.clone(), b"GIF89a".to_vec()).await);
+    assert_eq!(
+        assets.get(BACKGROUND_ASSET).unwrap().as_deref(),
+        Some(&b"GIF89a"[..])
+    );
+
+    // 存储不可用时回复失败
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(!save_background(assets, b"GIF87a".to_vec()).await);
+}
+
+// 连接到只接受 PCM 的 mock server, 扬声器使用 MemoryAudio 上的 player::run
+// 返回的 Vec 记录 save_volume 保存的音量
+#[cfg(test)]
+async fn spawn_executor(
+    script: crate::mock_server::Script,
+) -> (
+    crate::mock_server::MockServer,
+    Executor<NoOpus>,
+    crate::hal::MemoryAudio,
+    std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+) {
+    let mock = crate::mock_server::MockServer::start(vec![script])
+        .await
+        .unwrap();
+    let info = crate::mock_server::client::device_info();
+    let mut server = crate::ws::Server::new(mock.url()).await.unwrap();
+    server.handshake(info.clone()).await.unwrap();
+    let (audio, player_tx, _) = crate::player::spawn_player();
+    let saved = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let saved_ = saved.clone();
+    let exec = Executor::new(
+        ReconnectingServer::new(server, info),
+        player_tx,
+        UI::new(None).unwrap(),
+        NoOpus,
+        move |volume| saved_.lock().unwrap().push(volume),
+        None,
+    )
+    .unwrap();
+    (mock, exec, audio, saved)
+}
+
+#[cfg(test)]
+fn pcm(samples: &[i16]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+}
+
+#[tokio::test]
+async fn test_executor_play() {
+    let (_mock, mut exec, audio, _) = spawn_executor(Default::default()).await;
+    let (_evt_tx, mut evt_rx) = mpsc::channel(1);
+
+    // Hello 等待扬声器播放完成后才返回
+    exec.run(vec![Effect::Play(Playback::Hello)]).await.unwrap();
+    let hello = audio.played().len();
+    assert!(hello > 0);
+
+    // End 不阻塞, 播放完成后由 next_event 产生 PlaybackEnd
+    exec.run(vec![
+        Effect::Play(Playback::Start),
+        Effect::Play(Playback::Chunk(vec![1; 1000])),
+        Effect::Play(Playback::End),
+    ])
+    .await
+    .unwrap();
+    assert!(exec.playback.is_some());
+    let evt = exec.next_event(&mut evt_rx, None).await.unwrap();
+    assert!(matches!(evt, Some(Event::PlaybackEnd)));
+    assert!(exec.playback.is_none());
+    assert_eq!(audio.played()[hello..], vec![1; 1000]);
+
+    // Interrupt 之后不再等待 ack
+    exec.run(vec![
+        Effect::Play(Playback::Start),
+        Effect::Play(Playback::End),
+        Effect::Play(Playback::Interrupt),
+    ])
+    .await
+    .unwrap();
+    assert!(exec.playback.is_none());
+}
+
+#[tokio::test]
+async fn test_executor_set_volume() {
+    let (_mock, mut exec, audio, saved) = spawn_executor(Default::default()).await;
+    let (_evt_tx, mut evt_rx) = mpsc::channel(1);
+    exec.run(vec![Effect::Play(Playback::Hello)]).await.unwrap();
+    let before = audio.played().len();
+
+    exec.run(vec![Effect::SetVolume(50)]).await.unwrap();
+    assert_eq!(*saved.lock().unwrap(), [50]);
+    assert_eq!(exec.gui.volume, Some(50));
+
+    exec.run(vec![
+        Effect::Play(Playback::Start),
+        Effect::Play(Playback::Chunk(pcm(&[1000; 200]))),
+        Effect::Play(Playback::End),
+    ])
+    .await
+    .unwrap();
+    exec.next_event(&mut evt_rx, None).await.unwrap();
+    assert_eq!(audio.volume(), 50);
+    assert_eq!(audio.played()[before..], pcm(&[250; 200]));
+
+    // 状态变化时不再显示音量条
+    exec.run(vec![Effect::SetState("Listening".to_string())])
+        .await
+        .unwrap();
+    assert_eq!(exec.gui.state, "Listening");
+    assert_eq!(exec.gui.volume, None);
+}
+
+#[tokio::test]
+async fn test_executor_decode() {
+    let (_mock, mut exec, _, _) = spawn_executor(Default::default()).await;
+    let chunk = |data: Vec<u8>| Event::ServerEvent(ServerEvent::AudioChunk { data });
+    let decoded = |evt: Option<Event>| match evt {
+        Some(Event::ServerEvent(ServerEvent::AudioChunk { data })) => data,
+        evt => panic!("unexpected {:?}", evt),
+    };
+
+    // 默认是播放器格式的 PCM, 原样输出
+    let data = pcm(&[1, 2, 3]);
+    assert_eq!(decoded(exec.decode(chunk(data.clone()), true)), data);
+
+    // 双声道先混成单声道, 输出延迟一个采样
+    exec.run(vec![Effect::Decoder {
+        codec: AudioCodec::Pcm16,
+        sample_rate: SAMPLE_RATE,
+        channels: 2,
+    }])
+    .await
+    .unwrap();
+    let stereo = pcm(&[100, 300, 200, 400, 300, 500]);
+    assert_eq!(decoded(exec.decode(chunk(stereo), true)), pcm(&[200, 300]));
+    // 最后一个采样在播放结束时输出
+    assert_eq!(exec.resampler.flush(), pcm(&[400]));
+    // 不在播放时不解码
+    let raw = pcm(&[7, 7]);
+    assert_eq!(decoded(exec.decode(chunk(raw.clone()), false)), raw);
+
+    // 不支持的 codec 退回到 PCM, 并显示在屏幕上
+    exec.run(vec![Effect::Decoder {
+        codec: AudioCodec::Opus,
+        sample_rate: 48000,
+        channels: 2,
+    }])
+    .await
+    .unwrap();
+    assert_eq!(exec.gui.state, "Unsupported codec Opus");
+    assert_eq!(decoded(exec.decode(chunk(data.clone()), true)), data);
+
+    // 损坏的 ADPCM 数据被丢弃
+    exec.run(vec![Effect::Decoder {
+        codec: AudioCodec::Adpcm,
+        sample_rate: SAMPLE_RATE,
+        channels: 1,
+    }])
+    .await
+    .unwrap();
+    assert!(exec.decode(chunk(vec![1]), true).is_none());
+}
+
+#[tokio::test]
+async fn test_executor_send() {
+    let script = crate::mock_server::Script::new()
+        .wait_for_utterance()
+        .asr("done");
+    let (mock, mut exec, _, _) = spawn_executor(script).await;
+    let (_evt_tx, mut evt_rx) = mpsc::channel(1);
+
+    // server 只接受 PCM, 上行音频不编码, 空的数据不发送
+    exec.run(vec![
+        Effect::SendAudio {
+            data: vec![1; 640],
+            end: false,
+        },
+        Effect::SendAudio {
+            data: vec![],
+            end: true,
+        },
+        Effect::Send(ClientEvent::EndOfUtterance {
+            reason: crate::protocol::EndReason::Normal,
+        }),
+    ])
+    .await
+    .unwrap();
+    let evt = exec.next_event(&mut evt_rx, None).await.unwrap();
+    assert!(matches!(
+        evt,
+        Some(Event::ServerEvent(ServerEvent::ASR { .. }))
+    ));
+    assert_eq!(
+        mock.received()[1..],
+        [
+            ClientEvent::AudioChunk { data: vec![1; 640] },
+            ClientEvent::EndOfUtterance {
+                reason: crate::protocol::EndReason::Normal,
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_executor_rejected() {
+    let (_mock, mut exec, _, _) = spawn_executor(Default::default()).await;
+    let e = exec
+        .run(vec![
+            Effect::SetState("Rejected: firmware too old".to_string()),
+            Effect::Rejected("firmware too old".to_string()),
+        ])
+        .await
+        .unwrap_err();
+    // 调用者根据类型等待按键, 而不是直接重启
+    let rejected = e.downcast_ref::<Rejected>().unwrap();
+    assert_eq!(rejected.reason, "firmware too old");
+    assert_eq!(exec.gui.state, "Rejected: firmware too old");
+    assert_eq!(exec.gui.text, "Press K0 to restart");
+}
@@ -1,17 +1,370 @@
-#[cfg(feature = "box")]
-pub fn audio_init() {
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::conversation::Event;
+use crate::volume;
+
+#[cfg(target_os = "espidf")]
+use crate::board::{self, BoardProfile, Codec};
+
+// 按 profile 初始化音频 codec
+#[cfg(target_os = "espidf")]
+pub fn audio_init(codec: Codec) {
+    use crate::player::SAMPLE_RATE;
     use esp_idf_svc::sys::hal_driver;
-    const SAMPLE_RATE: u32 = 16000;
 
-    unsafe {
+    match codec {
+        Codec::None => {}
+        Codec::Es8311 => unsafe {
+            hal_driver::myiic_init();
+            hal_driver::xl9555_init();
+            hal_driver::es8311_init(SAMPLE_RATE as i32);
+            hal_driver::xl9555_pin_write(hal_driver::SPK_CTRL_IO as _, 1);
+            // 音量由 AudioDevice::set_volume 设置
+            hal_driver::es8311_set_voice_mute(0); /* 打开DAC */
+        },
+    }
+}
+
+// NVS 里没有设置 board 时自动检测
+// box 的 I2C 总线上有 XL9555 扩展 IO, 能探测到就是 box, 否则按 boards 处理
+// XL9555 只接在 board::CODEC_I2C_PINS 上, 而 boards 的 ST7789 复位线也是 GPIO45,
+// 所以必须在 lcd_init 之前调用: 这时复位线还没有配置, 探测产生的复位脉冲会被 lcd_init 的硬件复位覆盖
+// 探测完释放 I2C 总线并把引脚恢复为默认状态, audio_init 会重新创建总线
+#[cfg(target_os = "espidf")]
+pub fn detect_board() -> &'static BoardProfile {
+    use esp_idf_svc::sys::{self, hal_driver};
+
+    let found = unsafe {
         hal_driver::myiic_init();
-        hal_driver::xl9555_init();
-        hal_driver::es8311_init(SAMPLE_RATE as i32);
-        hal_driver::xl9555_pin_write(hal_driver::SPK_CTRL_IO as _, 1);
-        hal_driver::es8311_set_voice_volume(75); /* 设置喇叭音量，建议不超过65 */
-        hal_driver::es8311_set_voice_mute(0); /* 打开DAC */
+        let found = hal_driver::myiic_probe(hal_driver::XL9555_ADDR as _) == 0;
+        hal_driver::myiic_deinit();
+        for pin in board::CODEC_I2C_PINS {
+            sys::gpio_reset_pin(pin);
+        }
+        found
+    };
+    if found {
+        &board::BOX
+    } else {
+        &board::BOARDS
+    }
+}
+
+// 扫描 XL9555 上的 K1/K2, 返回新按下的按键, 需要先调用 audio_init 初始化 XL9555
+// 按住不放只返回一次
+#[cfg(target_os = "espidf")]
+pub fn scan_expander_keys() -> Option<&'static str> {
+    use esp_idf_svc::sys::hal_driver;
+
+    match unsafe { hal_driver::xl9555_key_scan(0) } as u32 {
+        hal_driver::KEY0_PRES => Some(Event::K1),
+        hal_driver::KEY1_PRES => Some(Event::K2),
+        _ => None,
+    }
+}
+
+// 按 BoardProfile 里的 GPIO 编号创建引脚
+// board 的测试保证同一个 profile 里每个编号只出现一次
+#[cfg(target_os = "espidf")]
+pub fn pin(gpio: i32) -> esp_idf_svc::hal::gpio::AnyIOPin {
+    unsafe { esp_idf_svc::hal::gpio::AnyIOPin::new(gpio) }
+}
+
+// 全双工的音频设备, 播放和录音都是 16bit 单声道 PCM, 采样率为 player::SAMPLE_RATE
+// ESP 上的实现见 audio::I2sAudio
+#[allow(async_fn_in_trait)]
+pub trait AudioDevice {
+    // 写入播放数据, 数据进入发送队列后返回
+    async fn write(&mut self, data: &[u8]) -> anyhow::Result<()>;
+    // 读取一段麦克风数据, 返回读取的字节数
+    fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize>;
+    // 设置扬声器音量, 0..=volume::MAX
+    fn set_volume(&mut self, volume: u8) -> anyhow::Result<()>;
+}
+
+// 显示屏, 像素格式为 RGB565 little endian
+// ESP 上的实现见 ui::EspLcd
+pub trait DisplaySink {
+    // 分辨率 (宽, 高)
+    fn size(&self) -> (usize, usize);
+    // 将按行排列的 data 刷新到 [x_start, x_end) x [y_start, y_end) 区域
+    fn flush(
+        &mut self,
+        data: &[u8],
+        x_start: i32,
+        y_start: i32,
+        x_end: i32,
+        y_end: i32,
+    ) -> anyhow::Result<()>;
+}
+
+// 按键
+// ESP 上的实现见 GpioButton
+#[allow(async_fn_in_trait)]
+pub trait InputSource {
+    // 按键当前是否处于按下状态
+    fn is_pressed(&self) -> bool;
+    // 等待按键按下
+    async fn wait_press(&mut self) -> anyhow::Result<()>;
+    // 等待按键松开
+    async fn wait_release(&mut self) -> anyhow::Result<()>;
+}
+
+// 按住超过这个时间算长按
+pub const LONG_PRESS: Duration = Duration::from_secs(1);
+
+// 等待一次完整的按键, 短按返回 K0, 长按返回 K0_
+// 长按不用等到松开, 超过 LONG_PRESS 就返回
+pub async fn next_key<I: InputSource>(input: &mut I) -> anyhow::Result<&'static str> {
+    input.wait_press().await?;
+    match tokio::time::timeout(LONG_PRESS, input.wait_release()).await {
+        Ok(r) => r.map(|_| Event::K0),
+        Err(_) => Ok(Event::K0_),
+    }
+}
+
+// GPIO 上的按键, 按下时为低电平
+#[cfg(target_os = "espidf")]
+pub struct GpioButton {
+    pin: esp_idf_svc::hal::gpio::PinDriver<
+        'static,
+        esp_idf_svc::hal::gpio::AnyIOPin,
+        esp_idf_svc::hal::gpio::Input,
+    >,
+}
+
+#[cfg(target_os = "espidf")]
+impl GpioButton {
+    pub fn new(pin: esp_idf_svc::hal::gpio::AnyIOPin) -> anyhow::Result<Self> {
+        use esp_idf_svc::hal::gpio::{InterruptType, PinDriver, Pull};
+
+        let mut pin = PinDriver::input(pin)?;
+        pin.set_pull(Pull::Up)?;
+        pin.set_interrupt_type(InterruptType::PosEdge)?;
+        Ok(Self { pin })
+    }
+}
+
+#[cfg(target_os = "espidf")]
+impl InputSource for GpioButton {
+    fn is_pressed(&self) -> bool {
+        self.pin.is_low()
+    }
+
+    async fn wait_press(&mut self) -> anyhow::Result<()> {
+        self.pin.wait_for_falling_edge().await?;
+        log::info!("Button pressed {:?}", self.pin.get_level());
+        Ok(())
+    }
+
+    async fn wait_release(&mut self) -> anyhow::Result<()> {
+        self.pin.wait_for_rising_edge().await?;
+        Ok(())
+    }
+}
+
+// 内存里的音频设备, 用于测试和模拟器
+// 麦克风从 mic 里读取, 读完后返回静音; 播放的数据按音量调整后追加到 played
+// clone 出来的副本共享同一份数据, 测试可以在设备交给播放器后继续检查
+#[derive(Clone)]
+pub struct MemoryAudio {
+    pub mic: Arc<Mutex<VecDeque<u8>>>,
+    pub played: Arc<Mutex<Vec<u8>>>,
+    volume: Arc<AtomicU8>,
+}
+
+impl Default for MemoryAudio {
+    fn default() -> Self {
+        Self {
+            mic: Default::default(),
+            played: Default::default(),
+            volume: Arc::new(AtomicU8::new(volume::MAX)),
+        }
+    }
+}
+
+impl MemoryAudio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn played(&self) -> Vec<u8> {
+        self.played.lock().unwrap().clone()
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume.load(Ordering::Relaxed)
+    }
+}
+
+impl AudioDevice for MemoryAudio {
+    async fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let mut data = data.to_vec();
+        volume::apply_gain(&mut data, self.volume());
+        self.played.lock().unwrap().extend_from_slice(&data);
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        let mut mic = self.mic.lock().unwrap();
+        for b in buf.iter_mut() {
+            *b = mic.pop_front().unwrap_or(0);
+        }
+        Ok(buf.len())
     }
+
+    fn set_volume(&mut self, volume: u8) -> anyhow::Result<()> {
+        self.volume
+            .store(volume.min(volume::MAX), Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+// 内存里的一帧屏幕
+#[derive(Clone)]
+pub struct MemoryDisplay {
+    pub width: usize,
+    pub height: usize,
+    pub frame: Vec<u8>,
+    // 刷新的次数
+    pub flushes: usize,
 }
 
-#[cfg(feature = "boards")]
-pub fn audio_init() {}
+impl MemoryDisplay {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            frame: vec![0; width * height * 2],
+            flushes: 0,
+        }
+    }
+}
+
+impl DisplaySink for MemoryDisplay {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    fn flush(
+        &mut self,
+        data: &[u8],
+        x_start: i32,
+        y_start: i32,
+        x_end: i32,
+        y_end: i32,
+    ) -> anyhow::Result<()> {
+        if x_start < 0 || y_start < 0 || x_end as usize > self.width || y_end as usize > self.height
+        {
+            return Err(anyhow::anyhow!(
+                "Area ({x_start}, {y_start})-({x_end}, {y_end}) out of bounds"
+            ));
+        }
+        let row = (x_end - x_start).max(0) as usize * 2;
+        let rows = (y_end - y_start).max(0) as usize;
+        if data.len() < row * rows {
+            return Err(anyhow::anyhow!(
+                "Expected {} bytes, got {}",
+                row * rows,
+                data.len()
+            ));
+        }
+        for (y, line) in (y_start as usize..y_end as usize).zip(data.chunks_exact(row.max(1))) {
+            let start = (y * self.width + x_start as usize) * 2;
+            self.frame[start..start + row].copy_from_slice(line);
+        }
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+// 按脚本模拟的按键, presses 是每次按键按住的时间
+// 脚本用完后不会再按下
+#[derive(Default)]
+pub struct MemoryInput {
+    pub presses: VecDeque<Duration>,
+    hold: Option<Duration>,
+}
+
+impl MemoryInput {
+    pub fn new(presses: impl IntoIterator<Item = Duration>) -> Self {
+        Self {
+            presses: presses.into_iter().collect(),
+            hold: None,
+        }
+    }
+}
+
+impl InputSource for MemoryInput {
+    fn is_pressed(&self) -> bool {
+        self.hold.is_some()
+    }
+
+    async fn wait_press(&mut self) -> anyhow::Result<()> {
+        match self.presses.pop_front() {
+            Some(hold) => {
+                self.hold = Some(hold);
+                Ok(())
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn wait_release(&mut self) -> anyhow::Result<()> {
+        if let Some(hold) = self.hold {
+            tokio::time::sleep(hold).await;
+            self.hold = None;
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_next_key() {
+    let mut input = MemoryInput::new([
+        Duration::from_millis(200),
+        Duration::from_millis(1500),
+        Duration::from_millis(100),
+    ]);
+    assert!(!input.is_pressed());
+
+    assert_eq!(next_key(&mut input).await.unwrap(), Event::K0);
+    assert!(!input.is_pressed());
+
+    // 长按超过 1s 就返回, 不等松开
+    let start = tokio::time::Instant::now();
+    assert_eq!(next_key(&mut input).await.unwrap(), Event::K0_);
+    assert_eq!(start.elapsed(), LONG_PRESS);
+    assert!(input.is_pressed());
+
+    assert_eq!(next_key(&mut input).await.unwrap(), Event::K0);
+
+    // 脚本用完后不再有按键
+    let r = tokio::time::timeout(Duration::from_secs(10), next_key(&mut input)).await;
+    assert!(r.is_err());
+}
+
+#[test]
+fn test_memory_display() {
+    let mut display = MemoryDisplay::new(4, 3);
+    // 刷新第 1..3 行的中间两列
+    let data = [1, 1, 2, 2, 3, 3, 4, 4];
+    display.flush(&data, 1, 1, 3, 3).unwrap();
+    assert_eq!(
+        display.frame,
+        [
+            [0, 0, 0, 0, 0, 0, 0, 0],
+            [0, 0, 1, 1, 2, 2, 0, 0],
+            [0, 0, 3, 3, 4, 4, 0, 0]
+        ]
+        .concat()
+    );
+    assert_eq!(display.flushes, 1);
+
+    assert!(display.flush(&data, 3, 1, 5, 3).is_err());
+    assert!(display.flush(&data[..4], 1, 1, 3, 3).is_err());
+    assert_eq!(display.flushes, 1);
+}
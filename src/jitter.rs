@@ -0,0 +1,288 @@
+use std::time::{Duration, Instant};
+
+// TTS 下行音频的 jitter buffer, 位于 ServerEvent::AudioChunk 和 AudioData::Chunk 之间
+// - 每段回复开始时先缓存, 直到缓存的音频时长达到目标深度才开始送给播放器
+// - 之后收到的数据直接送给播放器, 同时按照音频时长推算播放器什么时候会播完
+// - 如果数据到达时播放器已经播完(underrun), 重新进入缓存状态
+// - 目标深度根据到达时间的抖动自适应调整, 在多段回复之间保留
+// 所有接口都显式传入 now, 方便用模拟的时间做测试
+
+#[derive(Debug, Clone, Copy)]
+pub struct JitterConfig {
+    // 16bit mono PCM 的采样率
+    pub sample_rate: u32,
+    // 初始的目标深度
+    pub initial_depth: Duration,
+    // 目标深度的下限和上限
+    pub min_depth: Duration,
+    pub max_depth: Duration,
+}
+
+impl Default for JitterConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 16000,
+            initial_depth: Duration::from_millis(300),
+            min_depth: Duration::from_millis(100),
+            max_depth: Duration::from_millis(2000),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct JitterStats {
+    // 收到的数据块数量
+    pub chunks: u32,
+    // 播放器播空的次数
+    pub underruns: u32,
+    // 因 underrun 而重新缓存的总时长
+    pub rebuffer_time: Duration,
+    // 当前估计的到达抖动
+    pub jitter: Duration,
+}
+
+pub struct JitterBuffer {
+    config: JitterConfig,
+    target: Duration,
+    // 还没有送给播放器的 PCM
+    queue: Vec<u8>,
+    prebuffering: bool,
+    // 开始缓存的时间, 用于统计 rebuffer_time
+    buffering_since: Option<Instant>,
+    // 推算的播放器播完已送出数据的时间
+    play_until: Option<Instant>,
+    // 上一个数据块的到达时间和时长
+    last_arrival: Option<(Instant, Duration)>,
+    // RFC 3550 的到达抖动估计, 单位秒
+    jitter: f64,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    // 目标深度 = min_depth + JITTER_FACTOR * jitter
+    const JITTER_FACTOR: f64 = 4.0;
+
+    pub fn new(config: JitterConfig) -> Self {
+        Self {
+            target: config
+                .initial_depth
+                .clamp(config.min_depth, config.max_depth),
+            config,
+            queue: Vec::with_capacity(8192),
+            prebuffering: true,
+            buffering_since: None,
+            play_until: None,
+            last_arrival: None,
+            jitter: 0.0,
+            stats: JitterStats::default(),
+        }
+    }
+
+    pub fn target(&self) -> Duration {
+        self.target
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.stats
+    }
+
+    // 已缓存但还没有送给播放器的音频时长
+    pub fn buffered(&self) -> Duration {
+        self.duration(self.queue.len())
+    }
+
+    fn duration(&self, bytes: usize) -> Duration {
+        Duration::from_secs_f64(bytes as f64 / 2.0 / self.config.sample_rate as f64)
+    }
+
+    // 一段新的回复开始, 丢弃上一段残留的状态, 保留自适应的目标深度和统计
+    pub fn start(&mut self, now: Instant) {
+        self.queue.clear();
+        self.prebuffering = true;
+        self.buffering_since = Some(now);
+        self.play_until = None;
+        self.last_arrival = None;
+    }
+
+    // 收到一个数据块, 返回需要立即送给播放器的 PCM
+    pub fn push(&mut self, data: Vec<u8>, now: Instant) -> Option<Vec<u8>> {
+        self.stats.chunks += 1;
+        self.update_jitter(now, self.duration(data.len()));
+
+        if !self.prebuffering {
+            if let Some(play_until) = self.play_until {
+                if play_until < now {
+                    // 播放器已经播空了, 重新缓存到目标深度
+                    self.stats.underruns += 1;
+                    log::warn!(
+                        "Jitter buffer underrun, starved {:?}, target {:?}",
+                        now - play_until,
+                        self.target
+                    );
+                    self.prebuffering = true;
+                    self.buffering_since = Some(play_until);
+                    // 出现 underrun 说明目标深度不够, 至少加深一半
+                    self.target = (self.target + self.target / 2).min(self.config.max_depth);
+                }
+            }
+        }
+
+        self.queue.extend_from_slice(&data);
+        if self.prebuffering && self.buffered() < self.target {
+            return None;
+        }
+        self.release(now)
+    }
+
+    // 一段回复的数据已经全部收到, 返回剩余的 PCM
+    pub fn finish(&mut self, now: Instant) -> Option<Vec<u8>> {
+        let data = self.release(now);
+        self.prebuffering = true;
+        self.last_arrival = None;
+        data
+    }
+
+    fn release(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.prebuffering {
+            self.prebuffering = false;
+            if let Some(since) = self.buffering_since.take() {
+                self.stats.rebuffer_time += now.saturating_duration_since(since);
+            }
+        }
+        if self.queue.is_empty() {
+            return None;
+        }
+        let duration = self.buffered();
+        let start = match self.play_until {
+            Some(t) if t > now => t,
+            _ => now,
+        };
+        self.play_until = Some(start + duration);
+        Some(std::mem::replace(&mut self.queue, Vec::with_capacity(8192)))
+    }
+
+    // 用到达间隔与上一块音频时长之差估计抖动, 并据此调整目标深度
+    fn update_jitter(&mut self, now: Instant, duration: Duration) {
+        if let Some((last, last_duration)) = self.last_arrival {
+            let interval = now.saturating_duration_since(last).as_secs_f64();
+            let d = (interval - last_duration.as_secs_f64()).abs();
+            self.jitter += (d - self.jitter) / 16.0;
+            self.stats.jitter = Duration::from_secs_f64(self.jitter);
+
+            let target = self.config.min_depth.as_secs_f64() + Self::JITTER_FACTOR * self.jitter;
+            let target =
+                Duration::from_secs_f64(target).clamp(self.config.min_depth, self.config.max_depth);
+            // 抖动变小时缓慢收缩, 变大时立即加深
+            self.target = if target > self.target {
+                target
+            } else {
+                self.target - (self.target - target) / 16
+            };
+        }
+        self.last_arrival = Some((now, duration));
+    }
+}
+
+#[cfg(test)]
+fn chunk(ms: u64) -> Vec<u8> {
+    vec![0u8; (16000 * ms / 1000) as usize * 2]
+}
+
+#[test]
+fn test_jitter_prebuffer() {
+    let mut jb = JitterBuffer::new(JitterConfig::default());
+    let t0 = Instant::now();
+    jb.start(t0);
+
+    // 数据以实时速度均匀到达, 每块 100ms, 需要缓存到 300ms 才开始播放
+    assert_eq!(jb.push(chunk(100), t0), None);
+    assert_eq!(jb.push(chunk(100), t0 + Duration::from_millis(100)), None);
+    let data = jb
+        .push(chunk(100), t0 + Duration::from_millis(200))
+        .unwrap();
+    assert_eq!(data.len(), chunk(300).len());
+
+    // 之后的数据直接送出
+    for i in 3..20 {
+        let data = jb.push(chunk(100), t0 + Duration::from_millis(i * 100));
+        assert_eq!(data.unwrap().len(), chunk(100).len());
+    }
+    assert_eq!(jb.finish(t0 + Duration::from_millis(2000)), None);
+
+    let stats = jb.stats();
+    assert_eq!(stats.chunks, 20);
+    assert_eq!(stats.underruns, 0);
+    assert_eq!(stats.jitter, Duration::ZERO);
+    // 没有抖动时收缩到下限
+    assert!(jb.target() < Duration::from_millis(300));
+    assert!(jb.target() >= Duration::from_millis(100));
+}
+
+#[test]
+fn test_jitter_short_response() {
+    let mut jb = JitterBuffer::new(JitterConfig::default());
+    let t0 = Instant::now();
+    jb.start(t0);
+
+    // 不足目标深度的短回复, 在结束时一次性送出
+    assert_eq!(jb.push(chunk(100), t0), None);
+    assert_eq!(jb.buffered(), Duration::from_millis(100));
+    let data = jb.finish(t0 + Duration::from_millis(50)).unwrap();
+    assert_eq!(data.len(), chunk(100).len());
+    assert_eq!(jb.buffered(), Duration::ZERO);
+}
+
+#[test]
+fn test_jitter_underrun() {
+    let mut jb = JitterBuffer::new(JitterConfig::default());
+    let t0 = Instant::now();
+    jb.start(t0);
+
+    let mut t = t0;
+    for _ in 0..3 {
+        jb.push(chunk(100), t);
+        t += Duration::from_millis(100);
+    }
+    // 此时已经送出 300ms, 播放器会在 t0 + 500ms 播完
+    // 下一块在 t0 + 1000ms 才到, 产生 underrun 并重新缓存
+    let target = jb.target();
+    t = t0 + Duration::from_millis(1000);
+    assert_eq!(jb.push(chunk(100), t), None);
+    assert_eq!(jb.stats().underruns, 1);
+    assert!(jb.target() > target);
+    assert!(jb.stats().jitter > Duration::ZERO);
+
+    // 重新缓存到新的目标深度后恢复播放
+    let mut released = None;
+    while released.is_none() {
+        t += Duration::from_millis(100);
+        released = jb.push(chunk(100), t);
+    }
+    assert!(released.unwrap().len() >= chunk(jb.target().as_millis() as u64).len() - 2);
+    assert_eq!(jb.stats().underruns, 1);
+    assert!(jb.stats().rebuffer_time >= Duration::from_millis(500));
+}
+
+#[test]
+fn test_jitter_adaptive_depth() {
+    let mut jb = JitterBuffer::new(JitterConfig::default());
+    let t0 = Instant::now();
+    jb.start(t0);
+
+    // 数据成批到达: 每 400ms 到达 4 块 100ms 的数据
+    let mut t = t0;
+    for i in 0..40 {
+        if i % 4 == 0 && i > 0 {
+            t += Duration::from_millis(400);
+        }
+        jb.push(chunk(100), t);
+    }
+    let stats = jb.stats();
+    assert!(stats.jitter >= Duration::from_millis(50));
+    assert!(jb.target() > Duration::from_millis(300));
+    assert!(jb.target() <= Duration::from_millis(2000));
+    // 下一段回复保留学到的目标深度
+    let target = jb.target();
+    jb.start(t);
+    assert_eq!(jb.target(), target);
+}
@@ -1,10 +1,31 @@
+pub mod adpcm;
+pub mod aec;
+#[cfg(target_os = "espidf")]
 pub mod app;
+pub mod assets;
+#[cfg(target_os = "espidf")]
 pub mod audio;
+pub mod board;
+#[cfg(target_os = "espidf")]
 pub mod bt;
+#[cfg(target_os = "espidf")]
+pub mod codec;
+pub mod conversation;
+pub mod executor;
 pub mod hal;
+pub mod jitter;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock_server;
+#[cfg(target_os = "espidf")]
 pub mod network;
+pub mod player;
 pub mod protocol;
+pub mod resample;
+pub mod store;
 pub mod ui;
+pub mod upload;
+pub mod volume;
+pub mod wav;
 pub mod ws;
 
 #[derive(Debug, Clone)]
@@ -12,5 +33,7 @@ pub struct Setting {
     pub ssid: String,
     pub pass: String,
     pub server_url: String,
-    pub background_gif: (Vec<u8>, bool), // (data, ended)
+    pub wake_word: String, // WakeNet 模型名, 为空时使用 model 分区里的第一个
+    pub board: String,     // 开发板型号, 为空时自动检测
+    pub background_gif: (Vec<u8>, bool), // (data, ended), BLE 上传校验通过后才设置 ended
 }
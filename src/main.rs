@@ -1,12 +1,15 @@
 use std::sync::{Arc, Mutex};
 
 use echokit::app;
+use echokit::assets::{self, AssetKind, AssetStore};
 use echokit::audio;
+use echokit::board;
 use echokit::bt;
-use echokit::hal;
+use echokit::hal::{self, AudioDevice, InputSource};
 use echokit::network;
-// use echokit::protocol;
+use echokit::protocol;
 use echokit::ui;
+use echokit::volume;
 use echokit::ws;
 use echokit::Setting;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
@@ -18,12 +21,32 @@ fn main() -> anyhow::Result<()> {
     let sysloop = EspSystemEventLoop::take()?;
     let _fs = esp_idf_svc::io::vfs::MountedEventfs::mount(20)?;
     let partition = esp_idf_svc::nvs::EspDefaultNvsPartition::take()?;
-    let nvs = esp_idf_svc::nvs::EspDefaultNvs::new(partition, "setting", true)?;
+    let mut nvs = esp_idf_svc::nvs::EspDefaultNvs::new(partition, "setting", true)?;
 
     log_heap();
 
-    crate::hal::audio_init();
-    ui::lcd_init().unwrap();
+    // 选择开发板: NVS 里设置的 board 优先, 否则自动检测
+    // 自动检测必须在 lcd_init 之前, 检测结果保存到 NVS, 之后启动不再探测
+    let mut board_buf = [0; 32];
+    let board_name = nvs
+        .get_str("board", &mut board_buf)
+        .map_err(|e| log::error!("Failed to get board: {:?}", e))
+        .ok()
+        .flatten();
+    let profile = match board_name.and_then(board::by_name) {
+        Some(profile) => profile,
+        None => {
+            let profile = hal::detect_board();
+            if let Err(e) = nvs.set_str("board", profile.name) {
+                log::error!("Failed to save board: {:?}", e);
+            }
+            profile
+        }
+    };
+    log::info!("Board: {:?} -> {}", board_name, profile.name);
+
+    hal::audio_init(profile.codec);
+    ui::set_display(ui::lcd_init(profile.display).unwrap());
 
     log_heap();
     let mut ssid_buf = [0; 32];
@@ -47,17 +70,67 @@ fn main() -> anyhow::Result<()> {
         .ok()
         .flatten();
 
-    // 1MB buffer for GIF
-    let mut gif_buf = vec![0; 1024 * 1024];
-    let background_gif = nvs.get_blob("background_gif", &mut gif_buf)?;
+    let mut wake_word = [0; 32];
+    let wake_word = nvs
+        .get_str("wake_word", &mut wake_word)
+        .map_err(|e| log::error!("Failed to get wake_word: {:?}", e))
+        .ok()
+        .flatten();
+
+    let volume = nvs
+        .get_u8("volume")
+        .map_err(|e| log::error!("Failed to get volume: {:?}", e))
+        .ok()
+        .flatten()
+        .unwrap_or(volume::DEFAULT)
+        .min(volume::MAX);
+
+    // 背景图等资源保存在 assets 分区, 旧版本保存在 NVS 里的背景图在这里迁移过去
+    // 挂载失败时不影响启动, 只是不能加载和保存背景图
+    let mounted = assets::mount(|| {
+        let mut ui = ui::UI::new(None).unwrap();
+        ui.state = "Formatting storage...".to_string();
+        ui.text = "This takes tens of seconds on the first boot".to_string();
+        ui.display_flush().unwrap();
+    });
+    let assets = match mounted.and_then(|()| AssetStore::open(assets::MOUNT_POINT)) {
+        Ok(assets) => {
+            match assets::migrate_background(&mut nvs, &assets) {
+                Ok(true) => log::info!("Background GIF migrated from NVS"),
+                Ok(false) => {}
+                Err(e) => log::error!("Failed to migrate background GIF: {:?}", e),
+            }
+            match assets::migrate_hello(&mut nvs, &assets) {
+                Ok(true) => log::info!("Hello audio migrated from NVS"),
+                Ok(false) => {}
+                Err(e) => log::error!("Failed to migrate hello audio: {:?}", e),
+            }
+            Some(assets)
+        }
+        Err(e) => {
+            log::error!("Failed to open assets, background is disabled: {:?}", e);
+            None
+        }
+    };
+    let background_gif = assets.as_ref().and_then(|assets| {
+        assets
+            .get(assets::BACKGROUND_ASSET)
+            .map_err(|e| log::error!("Failed to load background GIF: {:?}", e))
+            .ok()
+            .flatten()
+    });
 
     log::info!("SSID: {:?}", ssid);
     log::info!("PASS: {:?}", pass);
     log::info!("Server URL: {:?}", server_url);
+    log::info!("Wake word: {:?}", wake_word);
+    log::info!("Volume: {}", volume);
 
     log_heap();
     if let Some(background_gif) = background_gif {
-        let _ = ui::backgroud(&background_gif);
+        if let Err(e) = ui::backgroud(&background_gif) {
+            log::error!("Failed to show background GIF: {:?}", e);
+        }
     } else {
         let mut ui = ui::UI::new(None).unwrap();
         ui.text = "You can hold K0 goto setup page".to_string();
@@ -69,9 +142,7 @@ fn main() -> anyhow::Result<()> {
     }
 
     // Configures the button
-    let mut button = esp_idf_svc::hal::gpio::PinDriver::input(peripherals.pins.gpio0)?;
-    button.set_pull(esp_idf_svc::hal::gpio::Pull::Up)?;
-    button.set_interrupt_type(esp_idf_svc::hal::gpio::InterruptType::PosEdge)?;
+    let mut button = hal::GpioButton::new(hal::pin(profile.buttons.k0))?;
 
     let b = tokio::runtime::Builder::new_current_thread()
         .enable_all()
@@ -84,7 +155,9 @@ fn main() -> anyhow::Result<()> {
             ssid: ssid.unwrap_or_default().to_string(),
             pass: pass.unwrap_or_default().to_string(),
             server_url: server_url.unwrap_or_default().to_string(),
-            background_gif: (Vec::with_capacity(1024 * 1024), false), // 1MB
+            wake_word: wake_word.unwrap_or_default().to_string(),
+            board: board_name.unwrap_or_default().to_string(),
+            background_gif: (Vec::new(), false),
         },
         nvs,
     )));
@@ -96,7 +169,7 @@ fn main() -> anyhow::Result<()> {
         setting.0.ssid.is_empty()
             || setting.0.pass.is_empty()
             || setting.0.server_url.is_empty()
-            || button.is_low()
+            || button.is_pressed()
     };
     // 如果开机时, 检测到 settings 里有任意条件满足
     // 则进入初始化等待
@@ -109,23 +182,25 @@ fn main() -> anyhow::Result<()> {
             .to_string();
         gui.display_qrcode("https://echokit.dev/setup/").unwrap();
 
-        #[cfg(feature = "boards")]
+        // 播放欢迎语音, 只在扬声器单独接在 I2S 上的板子上播放
+        if let board::AudioPins::Split {
+            spk_bclk,
+            spk_lrclk,
+            spk_dout,
+            ..
+        } = profile.audio
         {
-            let dout = peripherals.pins.gpio7;
-            let bclk = peripherals.pins.gpio15;
-            let lrclk = peripherals.pins.gpio16;
-            // 播放欢迎语音
             audio::player_welcome(
                 peripherals.i2s0,
-                bclk.into(),
-                dout.into(),
-                lrclk.into(),
+                hal::pin(spk_bclk),
+                hal::pin(spk_dout),
+                hal::pin(spk_lrclk),
                 None,
                 None,
             );
         }
         // 等待 K0(BOOT) 按键按下
-        b.block_on(button.wait_for_falling_edge()).unwrap();
+        b.block_on(button.wait_press()).unwrap();
         {
             let mut setting = setting.lock().unwrap();
             if setting.0.background_gif.1 {
@@ -135,21 +210,8 @@ fn main() -> anyhow::Result<()> {
                 let mut new_gif = Vec::new();
                 // 然后从 settings 里取出背景图到 vec 里
                 std::mem::swap(&mut setting.0.background_gif.0, &mut new_gif);
-                // 然后设置背景图到 UI
-                let _ = ui::backgroud(&new_gif);
-                log::info!("Background GIF set from NVS");
-                // 刷新 framebuffer
-                gui.text = "Background GIF set OK".to_string();
-                gui.display_flush().unwrap();
-                // 如果确实有背景图, 那么将它写入到 flash 中
-                if !new_gif.is_empty() {
-                    setting
-                        .1
-                        .set_blob("background_gif", &new_gif)
-                        .map_err(|e| log::error!("Failed to save background GIF to NVS: {:?}", e))
-                        .unwrap();
-                    log::info!("Background GIF saved to NVS");
-                }
+                // 然后设置背景图到 UI, 能够显示才写入到 flash 中
+                save_background(&mut gui, assets.as_ref(), &new_gif);
             } else {
                 gui.text = "Testing background GIF...".to_string();
                 gui.display_flush().unwrap();
@@ -158,21 +220,8 @@ fn main() -> anyhow::Result<()> {
                 let mut new_gif = Vec::new();
                 // 然后从 settings 里取出背景图到 vec 里
                 std::mem::swap(&mut default_gif, &mut new_gif);
-                // 然后设置背景图到 UI
-                let _ = ui::backgroud(&new_gif);
-                log::info!("Background GIF set from NVS");
-                // 刷新 framebuffer
-                gui.text = "Background GIF set OK".to_string();
-                gui.display_flush().unwrap();
-                // 如果确实有背景图, 那么将它写入到 flash 中
-                if !new_gif.is_empty() {
-                    setting
-                        .1
-                        .set_blob("background_gif", &new_gif)
-                        .map_err(|e| log::error!("Failed to save background GIF to NVS: {:?}", e))
-                        .unwrap();
-                    log::info!("Background GIF saved to NVS");
-                }
+                // 然后设置背景图到 UI, 能够显示才写入到 flash 中
+                save_background(&mut gui, assets.as_ref(), &new_gif);
             }
         }
         // 主动重启
@@ -196,7 +245,7 @@ fn main() -> anyhow::Result<()> {
         gui.state = "Failed to connect to wifi".to_string();
         gui.text = "Press K0 to restart".to_string();
         gui.display_flush().unwrap();
-        b.block_on(button.wait_for_falling_edge()).unwrap();
+        b.block_on(button.wait_press()).unwrap();
         unsafe { esp_idf_svc::sys::esp_restart() }
     }
 
@@ -213,44 +262,18 @@ fn main() -> anyhow::Result<()> {
     // 用于收发 audio 数据
     let (tx1, rx1) = tokio::sync::mpsc::unbounded_channel();
 
-    #[cfg(feature = "box")]
-    let i2s_task = {
-        let bclk = peripherals.pins.gpio21;
-        let din = peripherals.pins.gpio47;
-        let dout = peripherals.pins.gpio14;
-        let ws = peripherals.pins.gpio13;
-
-        audio::i2s_task(
-            peripherals.i2s0,
-            bclk.into(),
-            din.into(),
-            dout.into(),
-            ws.into(),
-            (evt_tx.clone(), rx1),
-        )
-    };
+    let wake_word = setting.lock().unwrap().0.wake_word.clone();
 
-    #[cfg(feature = "boards")]
-    let i2s_task = {
-        let sck = peripherals.pins.gpio5;
-        let din = peripherals.pins.gpio6;
-        let dout = peripherals.pins.gpio7;
-        let ws = peripherals.pins.gpio4;
-        let bclk = peripherals.pins.gpio15;
-        let lrclk = peripherals.pins.gpio16;
-        // 创建i2s task, 用于接收音频,处理音频(数据和vad检测), 播放音频
-        audio::i2s_task_(
-            peripherals.i2s0,
-            ws.into(),
-            sck.into(),
-            din.into(),
-            peripherals.i2s1,
-            bclk.into(),
-            lrclk.into(),
-            dout.into(),
-            (evt_tx.clone(), rx1),
-        )
-    };
+    let mut device = audio::I2sAudio::new(
+        profile.audio,
+        profile.codec,
+        peripherals.i2s0,
+        peripherals.i2s1,
+    )?;
+    device.set_volume(volume)?;
+    // 创建音频 task, 用于接收音频,处理音频(数据和vad检测), 播放音频
+    // server 下发的 hello 音效由播放器保存在 assets 分区
+    let audio_task = audio::audio_task(device, wake_word, assets.clone(), (evt_tx.clone(), rx1));
 
     gui.state = "Connecting to server...".to_string();
     gui.text.clear();
@@ -269,53 +292,93 @@ fn main() -> anyhow::Result<()> {
         gui.state = "Failed to connect to server".to_string();
         gui.text = format!("Please check your server URL: {server_url}");
         gui.display_flush().unwrap();
-        b.block_on(button.wait_for_falling_edge()).unwrap();
+        b.block_on(button.wait_press()).unwrap();
         unsafe { esp_idf_svc::sys::esp_restart() }
     }
 
-    let server = server.unwrap();
+    let mut server = server.unwrap();
+    // 连接成功后先握手, 告知 server 固件版本/板型/采样率/codec
+    let device_info = protocol::ClientEvent::DeviceInfo {
+        protocol_version: protocol::PROTOCOL_VERSION,
+        firmware_version: env!("CARGO_PKG_VERSION").to_string(),
+        board: profile.name.to_string(),
+        sample_rate: audio::SAMPLE_RATE,
+        codecs: vec![
+            protocol::AudioCodec::Opus,
+            protocol::AudioCodec::Adpcm,
+            protocol::AudioCodec::Pcm16,
+        ],
+    };
+    // 如果握手失败(版本不匹配或被 server 拒绝), 显示原因并等待按键触发重启
+    if let Err(e) = b.block_on(server.handshake(device_info.clone())) {
+        log::error!("Handshake failed: {:?}", e);
+        gui.state = e.to_string();
+        gui.text = format!(
+            "Firmware {} is not accepted by the server, please update the firmware or server",
+            env!("CARGO_PKG_VERSION")
+        );
+        gui.display_flush().unwrap();
+        b.block_on(button.wait_press()).unwrap();
+        unsafe { esp_idf_svc::sys::esp_restart() }
+    }
+    // 断线后由 main_work 负责重连, 不再直接重启设备
+    let server = ws::ReconnectingServer::new(server, device_info);
     // 注意这里main_work是async的, 因此ws_task只是一个future, 等到await的时候才会真正运行
-    let ws_task = app::main_work(server, tx1, evt_rx, background_gif);
+    let mut config = app::Config::default();
+    config.conversation.volume = volume;
+    // 调整后的音量保存到 NVS
+    let save_volume = {
+        let setting = setting.clone();
+        move |volume: u8| {
+            if let Err(e) = setting.lock().unwrap().1.set_u8("volume", volume) {
+                log::error!("Failed to save volume: {:?}", e);
+            }
+        }
+    };
+    let ws_task = app::main_work(
+        server,
+        tx1,
+        evt_rx,
+        background_gif.as_deref(),
+        config,
+        save_volume,
+        assets,
+    );
 
-    b.spawn(async move {
-        loop {
-            // 当检测按键按下后
-            let _ = button.wait_for_falling_edge().await;
-            log::info!("Button k0 pressed {:?}", button.get_level());
-            // 启动 tokio 定时器, 如果在 1s 内没有按键松开, 则返回Err
-            let r = tokio::time::timeout(
-                std::time::Duration::from_secs(1),
-                button.wait_for_rising_edge(),
-            )
-            .await;
-            match r {
-                Ok(_) => {
-                    // 如果检测到按键松开, 发送K0 event
-                    if evt_tx
-                        .send(app::Event::Event(app::Event::K0))
-                        .await
-                        .is_err()
-                    {
-                        log::error!("Failed to send K0 event");
+    // box 的 K1/K2 接在 XL9555 上, 没有中断, 在单独的线程里轮询
+    if profile.buttons.expander_keys {
+        let evt_tx = evt_tx.clone();
+        std::thread::Builder::new()
+            .stack_size(4096)
+            .spawn(move || loop {
+                if let Some(key) = hal::scan_expander_keys() {
+                    if evt_tx.blocking_send(app::Event::Event(key)).is_err() {
+                        log::error!("Failed to send {} event", key);
                         break;
                     }
                 }
-                Err(_) => {
-                    // 如果检测到按键松开, 发送K0_ event
-                    if evt_tx
-                        .send(app::Event::Event(app::Event::K0_))
-                        .await
-                        .is_err()
-                    {
-                        log::error!("Failed to send K0 event");
-                        break;
-                    }
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            })?;
+    }
+
+    b.spawn(async move {
+        loop {
+            // 短按发送 K0 event, 按住超过 1s 发送 K0_ event
+            let key = match hal::next_key(&mut button).await {
+                Ok(key) => key,
+                Err(e) => {
+                    log::error!("Failed to read button: {:?}", e);
+                    break;
                 }
+            };
+            if evt_tx.send(app::Event::Event(key)).await.is_err() {
+                log::error!("Failed to send {} event", key);
+                break;
             }
         }
     });
-    // 启动 i2s_task
-    b.spawn(i2s_task);
+    // 启动 audio_task
+    b.spawn(audio_task);
     // 启动 ws_task
     b.block_on(async move {
         let r = ws_task.await;
@@ -329,6 +392,36 @@ fn main() -> anyhow::Result<()> {
     unsafe { esp_idf_svc::sys::esp_restart() }
 }
 
+// 显示并保存 BLE 设置的背景图, 在屏幕上显示结果
+// 不能解码的背景图不保存, 否则每次启动都会显示失败
+// 失败(比如 assets 分区没有挂载)时停留几秒, 让用户看到错误再重启
+fn save_background(gui: &mut ui::UI, assets: Option<&AssetStore>, gif: &[u8]) {
+    if let Err(e) = ui::backgroud(gif) {
+        log::error!("Invalid background GIF: {:?}", e);
+        gui.text = "Invalid background GIF".to_string();
+        gui.display_flush().unwrap();
+        std::thread::sleep(std::time::Duration::from_secs(3));
+        return;
+    }
+    let r = match assets {
+        Some(assets) => assets.put(assets::BACKGROUND_ASSET, AssetKind::Background, gif),
+        None => Err(anyhow::anyhow!("Assets partition is not mounted")),
+    };
+    match r {
+        Ok(()) => {
+            log::info!("Background GIF saved to assets");
+            gui.text = "Background GIF set OK".to_string();
+            gui.display_flush().unwrap();
+        }
+        Err(e) => {
+            log::error!("Failed to save background GIF: {:?}", e);
+            gui.text = "Failed to save background GIF".to_string();
+            gui.display_flush().unwrap();
+            std::thread::sleep(std::time::Duration::from_secs(3));
+        }
+    }
+}
+
 pub fn log_heap() {
     unsafe {
         use esp_idf_svc::sys::{heap_caps_get_free_size, MALLOC_CAP_INTERNAL, MALLOC_CAP_SPIRAM};
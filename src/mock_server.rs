@@ -0,0 +1,567 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::sync::Notify;
+use tokio_websockets::Message;
+
+use crate::protocol::{AudioCodec, ClientEvent, ServerEvent, PROTOCOL_VERSION};
+
+// 用于集成测试的 echokit server 替身
+// - 每个连接先完成握手, 然后按照一个 Script 依次下发 ServerEvent, 期间可以等待客户端的消息、延迟和注入故障
+// - 记录客户端在所有连接上发送的 ClientEvent
+// - 第 n 个连接使用第 n 个 Script, 脚本用完后不再接受新的连接, 用于测试断线重连
+
+type Matcher = Box<dyn Fn(&ClientEvent) -> bool + Send + Sync>;
+
+enum Step {
+    Send(ServerEvent),
+    // 发送任意的 binary 消息, 用于注入无法解析的 msgpack
+    Raw(Vec<u8>),
+    WaitFor(Matcher),
+    Sleep(Duration),
+    // 不发送 close 直接断开 TCP 连接
+    Disconnect,
+    // 发送 close 正常关闭 websocket
+    Close,
+    // 不再读取客户端的消息(也就不再回复 pong), 但保持 TCP 连接, 模拟半开的连接
+    Stall,
+}
+
+enum Handshake {
+    Accept(Vec<AudioCodec>),
+    Reject(String),
+    // 收到 DeviceInfo 后不回复
+    Ignore,
+}
+
+pub struct Script {
+    handshake: Handshake,
+    protocol_version: u32,
+    steps: Vec<Step>,
+}
+
+impl Default for Script {
+    fn default() -> Self {
+        Self {
+            handshake: Handshake::Accept(vec![AudioCodec::Pcm16]),
+            protocol_version: PROTOCOL_VERSION,
+            steps: vec![],
+        }
+    }
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 握手时接受的 codec, 默认只接受 PCM
+    pub fn accept(mut self, codecs: Vec<AudioCodec>) -> Self {
+        self.handshake = Handshake::Accept(codecs);
+        self
+    }
+
+    // 握手时回复的协议版本, 默认与固件相同
+    pub fn protocol_version(mut self, version: u32) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    // 握手时拒绝设备, 然后关闭连接
+    pub fn reject(mut self, reason: &str) -> Self {
+        self.handshake = Handshake::Reject(reason.to_string());
+        self
+    }
+
+    // 收到 DeviceInfo 后不回复, 保持连接, 模拟卡住的 server
+    pub fn ignore_handshake(mut self) -> Self {
+        self.handshake = Handshake::Ignore;
+        self
+    }
+
+    pub fn send(mut self, evt: ServerEvent) -> Self {
+        self.steps.push(Step::Send(evt));
+        self
+    }
+
+    pub fn asr(self, text: &str) -> Self {
+        self.send(ServerEvent::ASR {
+            text: text.to_string(),
+        })
+    }
+
+    // 一段完整的回复: StartAudio, AudioChunk..., EndAudio, EndResponse
+    // 每个 AudioChunk 之前等待 interval, 模拟慢速下发
+    pub fn response(
+        mut self,
+        text: &str,
+        codec: AudioCodec,
+        chunks: Vec<Vec<u8>>,
+        interval: Duration,
+    ) -> Self {
+        self = self.send(ServerEvent::StartAudio {
+            text: text.to_string(),
+            codec,
+            sample_rate: 16000,
+            channels: 1,
+        });
+        for data in chunks {
+            if !interval.is_zero() {
+                self = self.sleep(interval);
+            }
+            self = self.send(ServerEvent::AudioChunk { data });
+        }
+        self.send(ServerEvent::EndAudio)
+            .send(ServerEvent::EndResponse)
+    }
+
+    // 分块下发新的 hello 音效(WAV 文件): HelloStart, HelloChunk..., HelloEnd
+    pub fn hello(mut self, data: &[u8], chunk_size: usize) -> Self {
+        self = self.send(ServerEvent::HelloStart);
+        for data in data.chunks(chunk_size) {
+            self = self.send(ServerEvent::HelloChunk {
+                data: data.to_vec(),
+            });
+        }
+        self.send(ServerEvent::HelloEnd)
+    }
+
+    // 分块下发新的背景图: BGStart, BGChunk..., BGEnd
+    pub fn background(mut self, data: &[u8], chunk_size: usize) -> Self {
+        self = self.send(ServerEvent::BGStart);
+        for data in data.chunks(chunk_size) {
+            self = self.send(ServerEvent::BGChunk {
+                data: data.to_vec(),
+            });
+        }
+        self.send(ServerEvent::BGEnd)
+    }
+
+    // 等待客户端发送一个满足条件的消息(握手之后的)
+    pub fn wait_for(
+        mut self,
+        matcher: impl Fn(&ClientEvent) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.steps.push(Step::WaitFor(Box::new(matcher)));
+        self
+    }
+
+    // 等待客户端提交一段语音
+    pub fn wait_for_utterance(self) -> Self {
+        self.wait_for(|evt| matches!(evt, ClientEvent::EndOfUtterance { .. }))
+    }
+
+    pub fn sleep(mut self, duration: Duration) -> Self {
+        self.steps.push(Step::Sleep(duration));
+        self
+    }
+
+    pub fn malformed(mut self, data: &[u8]) -> Self {
+        self.steps.push(Step::Raw(data.to_vec()));
+        self
+    }
+
+    pub fn disconnect(mut self) -> Self {
+        self.steps.push(Step::Disconnect);
+        self
+    }
+
+    pub fn close(mut self) -> Self {
+        self.steps.push(Step::Close);
+        self
+    }
+
+    pub fn stall(mut self) -> Self {
+        self.steps.push(Step::Stall);
+        self
+    }
+}
+
+// 客户端发送的消息
+#[derive(Default)]
+struct Recorder {
+    events: Mutex<Vec<ClientEvent>>,
+    notify: Notify,
+}
+
+impl Recorder {
+    fn push(&self, evt: ClientEvent) {
+        self.events.lock().unwrap().push(evt);
+        self.notify.notify_waiters();
+    }
+
+    fn len(&self) -> usize {
+        self.events.lock().unwrap().len()
+    }
+
+    // 从 cursor 开始等待满足条件的消息, 返回它之后的位置
+    async fn wait_for(&self, mut cursor: usize, matcher: &Matcher) -> usize {
+        loop {
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            {
+                let events = self.events.lock().unwrap();
+                while cursor < events.len() {
+                    cursor += 1;
+                    if matcher(&events[cursor - 1]) {
+                        return cursor;
+                    }
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+pub struct MockServer {
+    addr: SocketAddr,
+    recorder: Arc<Recorder>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockServer {
+    pub async fn start(scripts: Vec<Script>) -> anyhow::Result<Self> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let recorder = Arc::new(Recorder::default());
+        let recorder_ = recorder.clone();
+        let task = tokio::spawn(async move {
+            let mut connections = tokio::task::JoinSet::new();
+            for (i, script) in scripts.into_iter().enumerate() {
+                let Ok((stream, _)) = listener.accept().await else {
+                    break;
+                };
+                let recorder = recorder_.clone();
+                connections.spawn(async move {
+                    if let Err(e) = serve(stream, script, recorder).await {
+                        log::warn!("Mock connection {} error: {:?}", i, e);
+                    }
+                });
+            }
+            // 脚本已经用完, 关闭 listener, 之后的连接会被拒绝
+            drop(listener);
+            while connections.join_next().await.is_some() {}
+        });
+        Ok(Self {
+            addr,
+            recorder,
+            task,
+        })
+    }
+
+    pub fn url(&self) -> String {
+        format!("ws://{}/", self.addr)
+    }
+
+    // 客户端到目前为止发送的所有消息
+    pub fn received(&self) -> Vec<ClientEvent> {
+        self.recorder.events.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn encode(evt: &ServerEvent) -> anyhow::Result<Message> {
+    let data = rmp_serde::to_vec_named(evt)?;
+    Ok(Message::binary(bytes::Bytes::from(data)))
+}
+
+async fn serve(
+    stream: tokio::net::TcpStream,
+    script: Script,
+    recorder: Arc<Recorder>,
+) -> anyhow::Result<()> {
+    let ws = tokio_websockets::ServerBuilder::new()
+        .accept(stream)
+        .await?;
+    let (mut sink, mut stream) = ws.split();
+
+    // 第一个消息必须是 DeviceInfo
+    let info = loop {
+        let msg = stream
+            .next()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Closed before handshake"))??;
+        if msg.is_binary() {
+            break rmp_serde::from_slice::<ClientEvent>(&msg.into_payload())?;
+        }
+    };
+    let ClientEvent::DeviceInfo { .. } = info else {
+        anyhow::bail!("Expected DeviceInfo, got {:?}", info);
+    };
+    recorder.push(info);
+    match script.handshake {
+        Handshake::Accept(codecs) => {
+            sink.send(encode(&ServerEvent::HandshakeAccept {
+                protocol_version: script.protocol_version,
+                codecs,
+            })?)
+            .await?;
+        }
+        Handshake::Reject(reason) => {
+            sink.send(encode(&ServerEvent::HandshakeReject { reason })?)
+                .await?;
+            sink.send(Message::close(None, "")).await?;
+            return Ok(());
+        }
+        Handshake::Ignore => std::future::pending::<()>().await,
+    }
+
+    // 持续读取并记录客户端的消息, 同时由 tokio_websockets 自动回复 ping
+    let recorder_ = recorder.clone();
+    let reader = tokio::spawn(async move {
+        while let Some(Ok(msg)) = stream.next().await {
+            if msg.is_binary() {
+                match rmp_serde::from_slice::<ClientEvent>(&msg.into_payload()) {
+                    Ok(evt) => recorder_.push(evt),
+                    Err(e) => log::warn!("Mock server failed to parse client event: {}", e),
+                }
+            } else if msg.is_close() {
+                break;
+            }
+        }
+    });
+
+    let mut cursor = recorder.len();
+    for step in script.steps {
+        match step {
+            Step::Send(evt) => sink.send(encode(&evt)?).await?,
+            Step::Raw(data) => sink.send(Message::binary(bytes::Bytes::from(data))).await?,
+            Step::WaitFor(matcher) => cursor = recorder.wait_for(cursor, &matcher).await,
+            Step::Sleep(duration) => tokio::time::sleep(duration).await,
+            Step::Disconnect => {
+                reader.abort();
+                return Ok(());
+            }
+            Step::Close => {
+                sink.send(Message::close(None, "")).await?;
+                break;
+            }
+            Step::Stall => {
+                reader.abort();
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+    let _ = reader.await;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod client {
+    use std::collections::VecDeque;
+
+    use crate::conversation::{Config, Conversation, Effect, Event, Playback, State};
+    use crate::protocol::{AudioCodec, ClientEvent, PROTOCOL_VERSION};
+    use crate::ws::Server;
+
+    pub fn device_info() -> ClientEvent {
+        ClientEvent::DeviceInfo {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: env!("CARGO_PKG_VERSION").to_string(),
+            board: "test".to_string(),
+            sample_rate: 16000,
+            codecs: vec![AudioCodec::Pcm16],
+        }
+    }
+
+    // 用 Conversation 和 ws::Server 组成的最小客户端, 只执行网络和播放相关的 Effect
+    pub struct Client {
+        pub conv: Conversation,
+        pub server: Server,
+        pub played: Vec<u8>,
+        pub ui: Vec<String>,
+        pending: VecDeque<Event>,
+    }
+
+    impl Client {
+        pub async fn connect(url: String) -> anyhow::Result<Self> {
+            let mut server = Server::new(url).await?;
+            server.handshake(device_info()).await?;
+            Ok(Self {
+                conv: Conversation::new(Config {
+                    idle_timeout: None,
+                    ..Default::default()
+                }),
+                server,
+                played: vec![],
+                ui: vec![],
+                pending: VecDeque::new(),
+            })
+        }
+
+        pub async fn handle(&mut self, evt: Event) -> anyhow::Result<()> {
+            self.pending.push_back(evt);
+            while let Some(evt) = self.pending.pop_front() {
+                for effect in self.conv.handle(evt, std::time::Instant::now()) {
+                    match effect {
+                        Effect::Send(evt) => self.server.send(evt).await?,
+                        Effect::SendAudio { data, .. } if !data.is_empty() => {
+                            self.server.send(ClientEvent::AudioChunk { data }).await?
+                        }
+                        Effect::Play(Playback::Chunk(data)) => self.played.extend(data),
+                        Effect::Play(Playback::End) => self.pending.push_back(Event::PlaybackEnd),
+                        Effect::SetState(state) => self.ui.push(state),
+                        _ => {}
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        // 唤醒后说一段话并提交
+        pub async fn speak(&mut self, ms: usize) -> anyhow::Result<()> {
+            self.handle(Event::Event(Event::K0)).await?;
+            for _ in 0..ms / 20 {
+                self.handle(Event::MicAudioChunk(vec![0; 640])).await?;
+            }
+            self.handle(Event::MicAudioEnd).await
+        }
+
+        // 处理 server 的消息, 直到回复结束回到 Listening
+        pub async fn wait_response(&mut self) -> anyhow::Result<()> {
+            while self.conv.state() != State::Listening {
+                let evt = self.server.recv().await?;
+                self.handle(evt).await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_mock_server_conversation() {
+    let chunk = vec![1u8; 3200];
+    let mock = MockServer::start(vec![Script::new()
+        .wait_for_utterance()
+        .asr("hello")
+        .response(
+            "hi there",
+            AudioCodec::Pcm16,
+            vec![chunk.clone(); 5],
+            Duration::from_millis(10),
+        )])
+    .await
+    .unwrap();
+
+    let mut client = client::Client::connect(mock.url()).await.unwrap();
+    client.speak(1200).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), client.wait_response())
+        .await
+        .expect("response not finished")
+        .unwrap();
+
+    assert_eq!(client.played, vec![1u8; 5 * 3200]);
+    assert!(client.ui.contains(&"ASR".to_string()));
+
+    let received = mock.received();
+    assert!(matches!(received[0], ClientEvent::DeviceInfo { .. }));
+    let uploaded: usize = received
+        .iter()
+        .map(|evt| match evt {
+            ClientEvent::AudioChunk { data } => data.len(),
+            _ => 0,
+        })
+        .sum();
+    assert_eq!(uploaded, 1200 * 32);
+    assert!(matches!(
+        received.last(),
+        Some(ClientEvent::EndOfUtterance { .. })
+    ));
+}
+
+#[tokio::test]
+async fn test_mock_server_faults() {
+    let mock = MockServer::start(vec![
+        Script::new()
+            .malformed(b"\xc1 not msgpack")
+            .asr("still alive")
+            .disconnect(),
+        Script::new().reject("firmware too old"),
+        Script::new().protocol_version(PROTOCOL_VERSION + 1),
+    ])
+    .await
+    .unwrap();
+
+    // 无法解析的消息被跳过, 之后的消息正常收到, 断开后 recv 返回错误
+    let mut client = client::Client::connect(mock.url()).await.unwrap();
+    let evt = client.server.recv().await.unwrap();
+    assert!(matches!(
+        evt,
+        crate::conversation::Event::ServerEvent(ServerEvent::ASR { ref text }) if text == "still alive"
+    ));
+    assert!(client.server.recv().await.is_err());
+
+    // 第二个连接被拒绝
+    let e = client::Client::connect(mock.url()).await.err().unwrap();
+    assert!(e.to_string().contains("firmware too old"));
+
+    // 第三个连接的 server 协议版本不同, 握手失败
+    let e = client::Client::connect(mock.url()).await.err().unwrap();
+    assert_eq!(
+        e.to_string(),
+        format!(
+            "Rejected: protocol version {} != {}",
+            PROTOCOL_VERSION + 1,
+            PROTOCOL_VERSION
+        )
+    );
+
+    // 脚本用完后不再接受连接
+    assert!(crate::ws::Server::new(mock.url()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_mock_server_reconnect() {
+    let mock = MockServer::start(vec![
+        Script::new().disconnect(),
+        Script::new().asr("reconnected"),
+    ])
+    .await
+    .unwrap();
+
+    let mut server = crate::ws::Server::new(mock.url()).await.unwrap();
+    server.handshake(client::device_info()).await.unwrap();
+    let mut server = crate::ws::ReconnectingServer::new(server, client::device_info());
+    assert!(server.recv().await.is_err());
+    server.reconnect().await.unwrap();
+    let evt = server.recv().await.unwrap();
+    assert!(matches!(
+        evt,
+        crate::conversation::Event::ServerEvent(ServerEvent::ASR { ref text }) if text == "reconnected"
+    ));
+    let infos = mock
+        .received()
+        .iter()
+        .filter(|evt| matches!(evt, ClientEvent::DeviceInfo { .. }))
+        .count();
+    assert_eq!(infos, 2);
+}
+
+#[tokio::test]
+async fn test_mock_server_reconnect_timeout() {
+    let mock = MockServer::start(vec![
+        Script::new().disconnect(),
+        Script::new().ignore_handshake(),
+    ])
+    .await
+    .unwrap();
+
+    let mut server = crate::ws::Server::new(mock.url()).await.unwrap();
+    server.handshake(client::device_info()).await.unwrap();
+    let mut server = crate::ws::ReconnectingServer::new(server, client::device_info());
+    server.connect_timeout = Duration::from_millis(200);
+    assert!(server.recv().await.is_err());
+    // server 不回复握手时按超时失败, 而不是一直等待
+    let e = server.reconnect().await.unwrap_err();
+    assert!(e.to_string().starts_with("Reconnect timeout"), "{e}");
+}
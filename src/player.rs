@@ -0,0 +1,427 @@
+use crate::aec::EchoReference;
+use crate::assets::{AssetKind, AssetStore, HELLO_ASSET};
+use crate::hal::AudioDevice;
+
+// 播放和录音的采样率, 播放的数据都是这个采样率的 16bit mono PCM
+pub const SAMPLE_RATE: u32 = 16000;
+
+// 唤醒后播放的提示音, 服务器可以通过 SetHello* 替换
+pub static WAKE_WAV: &[u8] = include_bytes!("../assets/hello_beep.wav");
+// hello 音效(PCM)的最大长度, 10s
+pub const MAX_HELLO_SIZE: usize = 10 * SAMPLE_RATE as usize * 2;
+
+pub enum AudioData {
+    Hello(tokio::sync::oneshot::Sender<()>),
+    // 新的 hello 音效(已经转换为 PCM), SetHelloEnd 之后才替换当前的音效
+    SetHelloStart,
+    SetHelloChunk(Vec<u8>),
+    SetHelloEnd,
+    // 新的 hello 音效格式错误, 丢弃已经收到的部分
+    SetHelloAbort,
+    // 恢复内置的 hello 音效
+    ResetHello,
+    Start,
+    Chunk(Vec<u8>),
+    End(tokio::sync::oneshot::Sender<()>),
+    // 打断当前的播放, 丢弃还没有播放的语音数据
+    Interrupt,
+    // 设置音量, 不排队, 立即生效
+    Volume(u8),
+}
+
+pub type PlayerTx = tokio::sync::mpsc::UnboundedSender<AudioData>;
+pub type PlayerRx = tokio::sync::mpsc::UnboundedReceiver<AudioData>;
+
+// 每次写入音频设备的播放数据长度(10ms), 与每次读取麦克风的长度一致
+// 这样播放的同时也能持续给 AFE 喂数据(全双工), 才能检测到用户打断
+const PLAY_SLICE: usize = 2 * 160;
+// i2s 发送队列里最多保持 60ms 未播出的数据
+// 小于 DMA 的容量(默认 6 x 240 帧 = 90ms), 写入不会阻塞, 回声参考的模型才准确
+const PLAY_AHEAD: usize = 960;
+// 回声参考信号相对麦克风的额外延迟(采样数), 根据硬件实测调整
+const REF_DELAY: usize = 0;
+
+// 播放器的消息队列
+// rx 里的消息先全部取出放到这里, 这样 Interrupt 不用排在未播放的语音数据后面
+struct PlayQueue {
+    queue: std::collections::VecDeque<AudioData>,
+    // 正在播放的语音数据, 以及已经播放到的位置
+    current: Vec<u8>,
+    pos: usize,
+    // current 播放完后需要回复的 ack(hello 音效)
+    ack: Option<tokio::sync::oneshot::Sender<()>>,
+    speaking: bool,
+}
+
+impl PlayQueue {
+    fn new() -> Self {
+        Self {
+            queue: std::collections::VecDeque::new(),
+            current: vec![],
+            pos: 0,
+            ack: None,
+            speaking: false,
+        }
+    }
+
+    fn push(&mut self, data: AudioData) {
+        if let AudioData::Interrupt = data {
+            self.interrupt();
+        } else {
+            self.queue.push_back(data);
+        }
+    }
+
+    // 丢弃正在播放和还没有播放的语音数据
+    // 等待中的 End 直接 ack, hello 相关的消息保留
+    fn interrupt(&mut self) {
+        log::info!("Playback interrupted");
+        self.current.clear();
+        self.pos = 0;
+        self.speaking = false;
+        if let Some(tx) = self.ack.take() {
+            let _ = tx.send(());
+        }
+        for data in std::mem::take(&mut self.queue) {
+            match data {
+                AudioData::Start | AudioData::Chunk(_) | AudioData::Interrupt => {}
+                AudioData::End(tx) => {
+                    let _ = tx.send(());
+                }
+                data => self.queue.push_back(data),
+            }
+        }
+    }
+
+    // 取出下一段需要写入音频设备的语音数据
+    fn next_slice(&mut self) -> Option<&[u8]> {
+        if self.pos >= self.current.len() {
+            return None;
+        }
+        let start = self.pos;
+        self.pos = (self.pos + PLAY_SLICE).min(self.current.len());
+        Some(&self.current[start..self.pos])
+    }
+
+    // 当前的语音数据播放完后, 再取出下一条消息
+    fn pop(&mut self) -> Option<AudioData> {
+        if self.pos < self.current.len() {
+            return None;
+        }
+        if let Some(tx) = self.ack.take() {
+            let _ = tx.send(());
+        }
+        self.queue.pop_front()
+    }
+
+    fn play(&mut self, data: Vec<u8>) {
+        self.current = data;
+        self.pos = 0;
+    }
+
+    // 播放完成后通过 tx 回复 ack
+    fn play_with_ack(&mut self, data: Vec<u8>, tx: tokio::sync::oneshot::Sender<()>) {
+        self.play(data);
+        self.ack = Some(tx);
+    }
+}
+
+// 启动时读取保存的 hello 音效, 没有或者已经损坏时使用内置的 WAKE_WAV
+fn load_hello(assets: Option<&AssetStore>) -> anyhow::Result<Vec<u8>> {
+    match assets.map(|assets| assets.get(HELLO_ASSET)) {
+        Some(Ok(Some(hello))) if hello.len() <= MAX_HELLO_SIZE => {
+            log::info!("Loaded hello audio: {} bytes", hello.len());
+            return Ok(hello);
+        }
+        Some(Ok(Some(hello))) => log::warn!("Hello audio is too large: {} bytes", hello.len()),
+        Some(Ok(None)) | None => {}
+        Some(Err(e)) => log::warn!("Failed to load hello audio: {:?}", e),
+    }
+    crate::wav::decode(WAKE_WAV, SAMPLE_RATE)
+}
+
+// 播放器的主循环, 与具体的音频设备无关
+// 播放 rx 收到的语音数据, 同时持续读取麦克风, 与回声参考交织后交给 feed(AFE)
+// server 下发的 hello 音效保存在 assets 里, 重启后继续使用; assets 分区不可用时为 None, 不保存
+pub async fn run<D: AudioDevice>(
+    mut device: D,
+    mut rx: PlayerRx,
+    assets: Option<AssetStore>,
+    mut feed: impl FnMut(&[u8]),
+) -> anyhow::Result<()> {
+    // 10ms 的buffer
+    let mut buf = [0u8; PLAY_SLICE];
+    // 麦克风和回声参考信号交织后的数据
+    let mut feed_buf = Vec::with_capacity(buf.len() * 2);
+    let mut echo = EchoReference::new(REF_DELAY);
+    let mut queue = PlayQueue::new();
+    // 播放hello音效
+    let mut hello_audio = load_hello(assets.as_ref())?;
+    // 正在接收的新 hello 音效
+    let mut new_hello = vec![];
+    queue.play(hello_audio.clone());
+    log::info!("Playing hello audio, waiting for response...");
+    // 创建一个死循环
+    loop {
+        // 先取出 rx channel 里所有的消息, Interrupt 和 Volume 会立即生效
+        while let Ok(data) = rx.try_recv() {
+            if let AudioData::Volume(volume) = data {
+                device.set_volume(volume)?;
+            } else {
+                queue.push(data);
+            }
+        }
+        // 保持发送队列里有足够的数据, 写入的数据同时记录为回声参考
+        while echo.queued() < PLAY_AHEAD {
+            // 如果有正在播放的语音数据, 写入一段
+            if let Some(data) = queue.next_slice() {
+                device
+                    .write(data)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Error play audio data: {:?}", e))?;
+                echo.push(data);
+                continue;
+            }
+            let Some(data) = queue.pop() else {
+                break;
+            };
+            match data {
+                // 如果是Hello, 播放 hello 音效, 播放完成后通过 tx 进行 ack
+                AudioData::Hello(tx) => {
+                    log::info!("Received hello");
+                    queue.play_with_ack(hello_audio.clone(), tx);
+                    queue.speaking = false; // 更新no speaking
+                }
+                // 如果是设置hello音效
+                AudioData::SetHelloStart => {
+                    log::info!("Received set hello start");
+                    new_hello.clear();
+                }
+                AudioData::SetHelloChunk(data) => {
+                    log::info!("Received set hello chunk");
+                    new_hello.extend(data); // 追加音频数据
+                }
+                AudioData::SetHelloEnd => {
+                    log::info!("Received set hello end");
+                    hello_audio = std::mem::take(&mut new_hello);
+                    if let Some(assets) = &assets {
+                        if let Err(e) = assets.put(HELLO_ASSET, AssetKind::Sound, &hello_audio) {
+                            log::error!("Failed to save hello audio: {:?}", e);
+                        }
+                    }
+                    // 播放新的 hello 音效
+                    queue.play(hello_audio.clone());
+                }
+                AudioData::SetHelloAbort => {
+                    log::info!("Received set hello abort");
+                    new_hello = vec![];
+                }
+                AudioData::ResetHello => {
+                    log::info!("Received reset hello");
+                    if let Some(assets) = &assets {
+                        if let Err(e) = assets.delete(HELLO_ASSET) {
+                            log::error!("Failed to remove hello audio: {:?}", e);
+                        }
+                    }
+                    hello_audio = crate::wav::decode(WAKE_WAV, SAMPLE_RATE)?;
+                    queue.play(hello_audio.clone());
+                }
+                // 如果是开始(接收语音)
+                AudioData::Start => {
+                    log::info!("Received start");
+                    queue.speaking = true; // 更新speaking
+                }
+                // 如果是语音数据(段)
+                AudioData::Chunk(data) => {
+                    log::info!("Received audio chunk");
+                    // 如果当前是speaking状态, 分段写入音频设备
+                    if queue.speaking {
+                        queue.play(data);
+                    }
+                }
+                // 如果是结束(接收完毕), 此时前面的语音数据都已经写入音频设备
+                AudioData::End(tx) => {
+                    log::info!("Received end");
+                    let _ = tx.send(()); //ack play done
+                    queue.speaking = false; // 更新no speaking
+                }
+                AudioData::Interrupt | AudioData::Volume(_) => {}
+            }
+        }
+        // 无论是否在播放, 都读取麦克风数据, 与回声参考交织后喂给afe
+        let n = device.read(&mut buf)?;
+        echo.interleave(&buf[..n], &mut feed_buf);
+        feed(&feed_buf);
+        tokio::task::yield_now().await;
+    }
+}
+
+#[cfg(test)]
+fn spawn_player_with_assets(
+    assets: Option<AssetStore>,
+) -> (
+    crate::hal::MemoryAudio,
+    PlayerTx,
+    tokio::task::JoinHandle<()>,
+) {
+    let audio = crate::hal::MemoryAudio::new();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let device = audio.clone();
+    let task = tokio::spawn(async move {
+        run(device, rx, assets, |_| {}).await.unwrap();
+    });
+    (audio, tx, task)
+}
+
+#[cfg(test)]
+pub(crate) fn spawn_player() -> (
+    crate::hal::MemoryAudio,
+    PlayerTx,
+    tokio::task::JoinHandle<()>,
+) {
+    spawn_player_with_assets(None)
+}
+
+#[tokio::test]
+async fn test_player_hello_and_response() {
+    let (audio, tx, task) = spawn_player();
+
+    // 启动时先播放一次 hello 音效, Hello 播放完后 ack
+    let (ack_tx, ack_rx) = tokio::sync::oneshot::channel();
+    tx.send(AudioData::Hello(ack_tx)).unwrap();
+    ack_rx.await.unwrap();
+    // 播放的是去掉 WAV 头后的 PCM
+    let wake = crate::wav::decode(WAKE_WAV, SAMPLE_RATE).unwrap();
+    assert_eq!(audio.played(), [&wake[..], &wake].concat());
+
+    // 不在 Start/End 之间的 Chunk 被丢弃
+    tx.send(AudioData::Chunk(vec![9; 4])).unwrap();
+    tx.send(AudioData::Start).unwrap();
+    tx.send(AudioData::Chunk(vec![1; 1000])).unwrap();
+    tx.send(AudioData::Chunk(vec![2; 10])).unwrap();
+    let (end_tx, end_rx) = tokio::sync::oneshot::channel();
+    tx.send(AudioData::End(end_tx)).unwrap();
+    end_rx.await.unwrap();
+    let played = audio.played();
+    assert_eq!(
+        &played[wake.len() * 2..],
+        [vec![1; 1000], vec![2; 10]].concat()
+    );
+
+    task.abort();
+}
+
+#[tokio::test]
+async fn test_player_interrupt() {
+    let (audio, tx, task) = spawn_player();
+    let (ack_tx, ack_rx) = tokio::sync::oneshot::channel();
+    tx.send(AudioData::Hello(ack_tx)).unwrap();
+    ack_rx.await.unwrap();
+    let before = audio.played().len();
+
+    // 一次性收到很长的语音和打断, 只会写入打断前已经取出的一小段
+    tx.send(AudioData::Start).unwrap();
+    tx.send(AudioData::Chunk(vec![1; 32000])).unwrap();
+    let (end_tx, end_rx) = tokio::sync::oneshot::channel();
+    tx.send(AudioData::End(end_tx)).unwrap();
+    tokio::task::yield_now().await;
+    tx.send(AudioData::Interrupt).unwrap();
+    // 被丢弃的 End 也会 ack
+    end_rx.await.unwrap();
+    let played = audio.played().len() - before;
+    assert!(played < 32000, "played {played} bytes after interrupt");
+
+    task.abort();
+}
+
+#[tokio::test]
+async fn test_player_volume() {
+    let (audio, tx, task) = spawn_player();
+    let (ack_tx, ack_rx) = tokio::sync::oneshot::channel();
+    tx.send(AudioData::Hello(ack_tx)).unwrap();
+    ack_rx.await.unwrap();
+    let before = audio.played().len();
+
+    tx.send(AudioData::Volume(50)).unwrap();
+    tx.send(AudioData::Start).unwrap();
+    tx.send(AudioData::Chunk(1000i16.to_le_bytes().repeat(200)))
+        .unwrap();
+    let (end_tx, end_rx) = tokio::sync::oneshot::channel();
+    tx.send(AudioData::End(end_tx)).unwrap();
+    end_rx.await.unwrap();
+    assert_eq!(audio.volume(), 50);
+    assert_eq!(audio.played()[before..], 250i16.to_le_bytes().repeat(200));
+
+    task.abort();
+}
+
+#[tokio::test]
+async fn test_player_set_hello() {
+    let (audio, tx, task) = spawn_player();
+    let hello = |tx: &PlayerTx| {
+        let (ack_tx, ack_rx) = tokio::sync::oneshot::channel();
+        tx.send(AudioData::Hello(ack_tx)).unwrap();
+        ack_rx
+    };
+    hello(&tx).await.unwrap();
+    let wake = crate::wav::decode(WAKE_WAV, SAMPLE_RATE).unwrap();
+
+    // 中途放弃的 hello 不会替换当前的音效
+    tx.send(AudioData::SetHelloStart).unwrap();
+    tx.send(AudioData::SetHelloChunk(vec![1; 100])).unwrap();
+    tx.send(AudioData::SetHelloAbort).unwrap();
+    let before = audio.played().len();
+    hello(&tx).await.unwrap();
+    assert_eq!(audio.played()[before..], wake);
+
+    // SetHelloEnd 时播放一次新的音效, 之后的 Hello 也使用它
+    tx.send(AudioData::SetHelloStart).unwrap();
+    tx.send(AudioData::SetHelloChunk(vec![2; 100])).unwrap();
+    tx.send(AudioData::SetHelloEnd).unwrap();
+    let before = audio.played().len();
+    hello(&tx).await.unwrap();
+    assert_eq!(audio.played()[before..], vec![2; 200]);
+
+    task.abort();
+}
+
+#[tokio::test]
+async fn test_player_persist_hello() {
+    let dir = crate::assets::test_dir("player-hello");
+    let assets = AssetStore::open(&dir).unwrap();
+    let hello = |tx: &PlayerTx| {
+        let (ack_tx, ack_rx) = tokio::sync::oneshot::channel();
+        tx.send(AudioData::Hello(ack_tx)).unwrap();
+        ack_rx
+    };
+    let (_, tx, task) = spawn_player_with_assets(Some(assets.clone()));
+    tx.send(AudioData::SetHelloStart).unwrap();
+    tx.send(AudioData::SetHelloChunk(vec![3; 100])).unwrap();
+    tx.send(AudioData::SetHelloEnd).unwrap();
+    hello(&tx).await.unwrap();
+    task.abort();
+
+    // 重启后启动音和 Hello 都使用保存的音效
+    let (audio, tx, task) = spawn_player_with_assets(Some(assets.clone()));
+    hello(&tx).await.unwrap();
+    assert_eq!(audio.played(), vec![3; 200]);
+
+    // 恢复内置音效后不再保存
+    tx.send(AudioData::ResetHello).unwrap();
+    hello(&tx).await.unwrap();
+    let wake = crate::wav::decode(WAKE_WAV, SAMPLE_RATE).unwrap();
+    assert_eq!(audio.played()[200..], [&wake[..], &wake].concat());
+    assert!(assets.list().is_empty());
+    task.abort();
+
+    // 损坏的数据不会被使用
+    assets
+        .put(HELLO_ASSET, AssetKind::Sound, &[4; 100])
+        .unwrap();
+    std::fs::write(dir.join(HELLO_ASSET), [5; 100]).unwrap();
+    let (audio, tx, task) = spawn_player_with_assets(Some(assets));
+    hello(&tx).await.unwrap();
+    assert_eq!(audio.played(), [&wake[..], &wake].concat());
+    task.abort();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
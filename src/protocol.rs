@@ -1,25 +1,140 @@
 use serde::{Deserialize, Serialize};
 
+// bump when ClientEvent or ServerEvent change in an incompatible way
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AudioCodec {
+    // 16bit little endian PCM
+    #[default]
+    Pcm16,
+    // Opus packets, a packet is never split across chunks
+    Opus,
+    // IMA ADPCM, each chunk is one block with its own 4 byte header
+    Adpcm,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ServerEvent {
+    // reply to ClientEvent::DeviceInfo
+    HandshakeAccept {
+        protocol_version: u32,
+        codecs: Vec<AudioCodec>,
+    },
+    HandshakeReject {
+        reason: String,
+    },
+
     // set Hello
     HelloStart,
-    HelloChunk { data: Vec<u8> },
+    HelloChunk {
+        data: Vec<u8>,
+    },
     HelloEnd,
+    // go back to the built-in hello sound
+    ResetHello,
 
     // set Background
     BGStart,
-    BGChunk { data: Vec<u8> },
+    BGChunk {
+        data: Vec<u8>,
+    },
     BGEnd,
 
-    ASR { text: String },
-    Action { action: String },
-    StartAudio { text: String },
-    AudioChunk { data: Vec<u8> },
+    ASR {
+        text: String,
+    },
+    Action {
+        action: String,
+    },
+    StartAudio {
+        text: String,
+        // codec of the following AudioChunks, older servers only send PCM
+        #[serde(default)]
+        codec: AudioCodec,
+        // format of the decoded audio, the device resamples it to its own sample rate
+        #[serde(default = "default_sample_rate")]
+        sample_rate: u32,
+        #[serde(default = "default_channels")]
+        channels: u8,
+    },
+    AudioChunk {
+        data: Vec<u8>,
+    },
     EndAudio,
     StartVideo,
     EndVideo,
     EndResponse,
+
+    // set the speaker volume, 0 (mute) to 100
+    // the device saves it and uses it after reboot
+    SetVolume {
+        volume: u8,
+    },
+}
+
+// StartAudio from servers that predate sample_rate/channels is 16kHz mono
+fn default_sample_rate() -> u32 {
+    16000
+}
+
+fn default_channels() -> u8 {
+    1
+}
+
+// why the device ended an utterance
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum EndReason {
+    // VAD detected the end of speech while listening
+    Normal,
+    // the user released K0 after a long press
+    Recording,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum ClientEvent {
+    // 16kHz 16bit mono PCM from the microphone
+    AudioChunk {
+        data: Vec<u8>,
+    },
+    // used instead of AudioChunk when Opus was accepted in the handshake
+    OpusChunk {
+        frames: Vec<Vec<u8>>,
+    },
+    EndOfUtterance {
+        reason: EndReason,
+    },
+    // stop the current response
+    Interrupt,
+    // the user cancelled the pending request or current response with the button
+    Cancel,
+    // "yes"/"no" recognized on the device
+    Confirmation {
+        accepted: bool,
+    },
+    Button {
+        key: String,
+    },
+    // sent right after connecting, the server must answer with HandshakeAccept or HandshakeReject
+    DeviceInfo {
+        protocol_version: u32,
+        firmware_version: String,
+        board: String,
+        sample_rate: u32,
+        codecs: Vec<AudioCodec>,
+    },
+    SettingsAck {
+        key: String,
+        ok: bool,
+    },
+    Ping,
+}
+
+impl ClientEvent {
+    pub fn to_vec(&self) -> anyhow::Result<Vec<u8>> {
+        rmp_serde::to_vec_named(self)
+            .map_err(|e| anyhow::anyhow!("Failed to serialize client event: {}", e))
+    }
 }
 
 #[test]
@@ -41,3 +156,160 @@ fn test_rmp_command() {
         _ => panic!("Unexpected command: {:?}", cmd),
     }
 }
+
+#[test]
+fn test_rmp_client_event() {
+    let events = vec![
+        ClientEvent::AudioChunk {
+            data: vec![0, 1, 127, 128, 255],
+        },
+        ClientEvent::OpusChunk {
+            frames: vec![vec![0x78, 1, 2], vec![0x78, 3]],
+        },
+        ClientEvent::EndOfUtterance {
+            reason: EndReason::Normal,
+        },
+        ClientEvent::EndOfUtterance {
+            reason: EndReason::Recording,
+        },
+        ClientEvent::Interrupt,
+        ClientEvent::Button {
+            key: "k0".to_string(),
+        },
+        ClientEvent::DeviceInfo {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: "0.1.0".to_string(),
+            board: "boards".to_string(),
+            sample_rate: 16000,
+            codecs: vec![AudioCodec::Opus, AudioCodec::Pcm16],
+        },
+        ClientEvent::SettingsAck {
+            key: "background".to_string(),
+            ok: true,
+        },
+        ClientEvent::Ping,
+    ];
+    for event in events {
+        let data = event.to_vec().unwrap();
+        let evt: ClientEvent = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(evt, event);
+        // the server may still encode without field names
+        let data = rmp_serde::to_vec(&event).unwrap();
+        let evt: ClientEvent = rmp_serde::from_slice(&data).unwrap();
+        assert_eq!(evt, event);
+    }
+}
+
+#[test]
+fn test_rmp_client_event_wire_names() {
+    // the server matches on these names, renaming a variant is a breaking change
+    let data = ClientEvent::EndOfUtterance {
+        reason: EndReason::Recording,
+    }
+    .to_vec()
+    .unwrap();
+    let text = String::from_utf8_lossy(&data);
+    assert!(text.contains("EndOfUtterance"));
+    assert!(text.contains("reason"));
+    assert!(text.contains("Recording"));
+
+    let data = ClientEvent::Cancel.to_vec().unwrap();
+    assert!(String::from_utf8_lossy(&data).contains("Cancel"));
+
+    let data = ClientEvent::Confirmation { accepted: true }
+        .to_vec()
+        .unwrap();
+    let text = String::from_utf8_lossy(&data);
+    assert!(text.contains("Confirmation"));
+    assert!(text.contains("accepted"));
+}
+
+#[test]
+fn test_rmp_handshake() {
+    let event = ServerEvent::HandshakeAccept {
+        protocol_version: PROTOCOL_VERSION,
+        codecs: vec![AudioCodec::Pcm16],
+    };
+    let data = rmp_serde::to_vec(&event).unwrap();
+    match rmp_serde::from_slice::<ServerEvent>(&data).unwrap() {
+        ServerEvent::HandshakeAccept {
+            protocol_version,
+            codecs,
+        } => {
+            assert_eq!(protocol_version, PROTOCOL_VERSION);
+            assert_eq!(codecs, vec![AudioCodec::Pcm16]);
+        }
+        cmd => panic!("Unexpected command: {:?}", cmd),
+    }
+
+    let event = ServerEvent::HandshakeReject {
+        reason: "firmware too old".to_string(),
+    };
+    let data = rmp_serde::to_vec_named(&event).unwrap();
+    match rmp_serde::from_slice::<ServerEvent>(&data).unwrap() {
+        ServerEvent::HandshakeReject { reason } => assert_eq!(reason, "firmware too old"),
+        cmd => panic!("Unexpected command: {:?}", cmd),
+    }
+}
+
+#[test]
+fn test_rmp_start_audio_codec() {
+    // StartAudio from a server that does not know about codecs
+    #[derive(Serialize)]
+    enum OldServerEvent {
+        StartAudio { text: String },
+    }
+    let old = OldServerEvent::StartAudio {
+        text: "hi".to_string(),
+    };
+    for data in [
+        rmp_serde::to_vec(&old).unwrap(),
+        rmp_serde::to_vec_named(&old).unwrap(),
+    ] {
+        match rmp_serde::from_slice::<ServerEvent>(&data).unwrap() {
+            ServerEvent::StartAudio {
+                text,
+                codec,
+                sample_rate,
+                channels,
+            } => {
+                assert_eq!(text, "hi");
+                assert_eq!(codec, AudioCodec::Pcm16);
+                assert_eq!((sample_rate, channels), (16000, 1));
+            }
+            cmd => panic!("Unexpected command: {:?}", cmd),
+        }
+    }
+
+    let event = ServerEvent::StartAudio {
+        text: "hi".to_string(),
+        codec: AudioCodec::Opus,
+        sample_rate: 24000,
+        channels: 2,
+    };
+    let data = rmp_serde::to_vec(&event).unwrap();
+    match rmp_serde::from_slice::<ServerEvent>(&data).unwrap() {
+        ServerEvent::StartAudio {
+            codec,
+            sample_rate,
+            channels,
+            ..
+        } => {
+            assert_eq!(codec, AudioCodec::Opus);
+            assert_eq!((sample_rate, channels), (24000, 2));
+        }
+        cmd => panic!("Unexpected command: {:?}", cmd),
+    }
+}
+
+#[test]
+fn test_rmp_set_volume() {
+    let data = rmp_serde::to_vec_named(&ServerEvent::SetVolume { volume: 40 }).unwrap();
+    let text = String::from_utf8_lossy(&data);
+    assert!(text.contains("SetVolume"));
+    assert!(text.contains("volume"));
+    match rmp_serde::from_slice::<ServerEvent>(&data).unwrap() {
+        ServerEvent::SetVolume { volume } => assert_eq!(volume, 40),
+        evt => panic!("Unexpected event: {:?}", evt),
+    }
+}
@@ -0,0 +1,178 @@
+// 下行音频的重采样, 把任意采样率/声道数的 16bit le PCM 转换为播放器使用的单声道 PCM
+// 多声道先取平均混成单声道, 再做线性插值
+// 线性插值没有抗混叠滤波, 从 44.1k/48k 降采样时高于新奈奎斯特频率的成分会混叠, 对语音影响不大
+
+pub struct Resampler {
+    from_rate: u32,
+    to_rate: u32,
+    channels: usize,
+    // 下一个输出采样在输入里的位置, 单位是 1 / to_rate 个输入采样, 相对于 prev
+    pos: u64,
+    // 上一段输入的最后一个采样, 插值时作为这一段的第 0 个采样
+    prev: Option<i32>,
+    // 上一段输入末尾不完整的一帧
+    partial: Vec<u8>,
+}
+
+impl Resampler {
+    pub fn new(from_rate: u32, channels: u8, to_rate: u32) -> anyhow::Result<Self> {
+        if from_rate == 0 || to_rate == 0 || channels == 0 {
+            anyhow::bail!(
+                "Unsupported audio format: {} Hz, {} channels",
+                from_rate,
+                channels
+            );
+        }
+        Ok(Self {
+            from_rate,
+            to_rate,
+            channels: channels as usize,
+            pos: 0,
+            prev: None,
+            partial: Vec::new(),
+        })
+    }
+
+    // 采样率相同的单声道不需要转换
+    pub fn is_passthrough(&self) -> bool {
+        self.from_rate == self.to_rate && self.channels == 1
+    }
+
+    // 转换一段输入, chunk 可以在任意字节处切分
+    // 每段输入的最后一个采样要等下一段到来才能插值, 所以输出比输入延迟一个采样
+    pub fn process(&mut self, data: &[u8]) -> Vec<u8> {
+        if self.is_passthrough() {
+            self.partial.extend_from_slice(data);
+            let n = self.partial.len() & !1;
+            return self.partial.drain(..n).collect();
+        }
+
+        let frame_size = self.channels * 2;
+        self.partial.extend_from_slice(data);
+        let frames = self.partial.len() / frame_size;
+        let mut samples = Vec::with_capacity(frames + 1);
+        samples.extend(self.prev);
+        for frame in self.partial[..frames * frame_size].chunks_exact(frame_size) {
+            let sum: i32 = frame
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as i32)
+                .sum();
+            samples.push(sum / self.channels as i32);
+        }
+        self.partial.drain(..frames * frame_size);
+
+        let to_rate = self.to_rate as u64;
+        let mut out =
+            Vec::with_capacity((frames as u64 * to_rate / self.from_rate as u64 + 1) as usize * 2);
+        while ((self.pos / to_rate) as usize) + 1 < samples.len() {
+            let i = (self.pos / to_rate) as usize;
+            let frac = (self.pos % to_rate) as i64;
+            let (a, b) = (samples[i] as i64, samples[i + 1] as i64);
+            let sample = a + (b - a) * frac / to_rate as i64;
+            out.extend_from_slice(&(sample as i16).to_le_bytes());
+            self.pos += self.from_rate as u64;
+        }
+
+        // 只保留最后一个采样, 位置改为相对于它
+        if let Some(&last) = samples.last() {
+            self.pos -= (samples.len() as u64 - 1) * to_rate;
+            self.prev = Some(last);
+        }
+        out
+    }
+
+    // 输入结束时调用, 输出最后一个采样之后剩余的位置(保持最后一个采样的值)
+    // n 个输入采样总共输出 ceil(n * to_rate / from_rate) 个采样, 之后可以开始新的一段输入
+    pub fn flush(&mut self) -> Vec<u8> {
+        let mut out = vec![];
+        if let Some(last) = self.prev.take() {
+            while self.pos < self.to_rate as u64 {
+                out.extend_from_slice(&(last as i16).to_le_bytes());
+                self.pos += self.from_rate as u64;
+            }
+        }
+        self.pos = 0;
+        self.partial.clear();
+        out
+    }
+}
+
+#[cfg(test)]
+fn sine(rate: u32, freq: f64, ms: u32, channels: usize) -> Vec<u8> {
+    let n = (rate * ms / 1000) as usize;
+    (0..n)
+        .flat_map(|i| {
+            let t = i as f64 / rate as f64;
+            let s = (10000.0 * (2.0 * std::f64::consts::PI * freq * t).sin()) as i16;
+            std::iter::repeat(s.to_le_bytes()).take(channels).flatten()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn to_samples(data: &[u8]) -> Vec<i16> {
+    data.chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect()
+}
+
+#[test]
+fn test_resample_reference_sine() {
+    // 与直接按 16k 生成的同一个正弦波比较
+    let expected = to_samples(&sine(16000, 440.0, 500, 1));
+    for (rate, channels) in [(24000, 1), (22050, 1), (44100, 2), (8000, 1)] {
+        let mut resampler = Resampler::new(rate, channels, 16000).unwrap();
+        let out = to_samples(&resampler.process(&sine(rate, 440.0, 500, channels as usize)));
+        // 最后一个输入采样之后的输出要等下一段输入
+        assert!(
+            out.len().abs_diff(expected.len()) <= 2,
+            "{rate} Hz: {} samples",
+            out.len()
+        );
+        let error = out
+            .iter()
+            .zip(&expected)
+            .map(|(a, b)| (*a as i32 - *b as i32).abs())
+            .max()
+            .unwrap();
+        // 8k 的正弦波本身采样点少, 线性插值的误差最大
+        assert!(error < 300, "{rate} Hz x{channels}: max error {error}");
+    }
+}
+
+#[test]
+fn test_resample_chunked() {
+    // 在任意字节处切分输入, 结果与一次性转换相同
+    let input = sine(24000, 1000.0, 200, 2);
+    let whole = Resampler::new(24000, 2, 16000).unwrap().process(&input);
+
+    let mut resampler = Resampler::new(24000, 2, 16000).unwrap();
+    let mut chunked = vec![];
+    for chunk in input.chunks(333) {
+        chunked.extend(resampler.process(chunk));
+    }
+    assert_eq!(chunked, whole);
+}
+
+#[test]
+fn test_resample_passthrough_and_downmix() {
+    let mut resampler = Resampler::new(16000, 1, 16000).unwrap();
+    assert!(resampler.is_passthrough());
+    let input = sine(16000, 440.0, 20, 1);
+    assert_eq!(resampler.process(&input), input);
+
+    // 左右声道取平均
+    let mut resampler = Resampler::new(16000, 2, 16000).unwrap();
+    let stereo: Vec<u8> = [1000i16, -400, 300, 500, 0, 0]
+        .iter()
+        .flat_map(|s| s.to_le_bytes())
+        .collect();
+    assert_eq!(to_samples(&resampler.process(&stereo)), [300, 400]);
+
+    // 最后一个采样在 flush 时输出
+    assert_eq!(to_samples(&resampler.flush()), [0]);
+    assert!(resampler.flush().is_empty());
+
+    assert!(Resampler::new(0, 1, 16000).is_err());
+    assert!(Resampler::new(24000, 0, 16000).is_err());
+}
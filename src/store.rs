@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// NVS 里的二进制设置, 旧版本用它保存 hello 音效和背景图, 现在只用于迁移到 assets 分区
+// ESP 上的实现是 NVS 的 blob, 测试使用 MemoryStore
+pub trait BlobStore {
+    fn get(&mut self, key: &str) -> anyhow::Result<Option<Vec<u8>>>;
+    fn set(&mut self, key: &str, data: &[u8]) -> anyhow::Result<()>;
+    fn remove(&mut self, key: &str) -> anyhow::Result<()>;
+
+    // 带校验头保存, 读取时用 load 检查
+    fn save(&mut self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        let mut blob = Vec::with_capacity(HEADER_SIZE + data.len());
+        blob.extend_from_slice(MAGIC);
+        blob.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        blob.extend_from_slice(&crc32(data).to_le_bytes());
+        blob.extend_from_slice(data);
+        self.set(key, &blob)
+    }
+
+    // 读取 save 保存的数据, 超过 max_size 或者校验失败时返回错误
+    fn load(&mut self, key: &str, max_size: usize) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(mut blob) = self.get(key)? else {
+            return Ok(None);
+        };
+        if blob.len() < HEADER_SIZE || &blob[0..4] != MAGIC {
+            anyhow::bail!("Invalid {} blob header", key);
+        }
+        let len = u32::from_le_bytes([blob[4], blob[5], blob[6], blob[7]]) as usize;
+        let crc = u32::from_le_bytes([blob[8], blob[9], blob[10], blob[11]]);
+        if len > max_size {
+            anyhow::bail!("{} is too large: {} > {}", key, len, max_size);
+        }
+        if blob.len() - HEADER_SIZE != len {
+            anyhow::bail!(
+                "{} is truncated: {} of {} bytes",
+                key,
+                blob.len() - HEADER_SIZE,
+                len
+            );
+        }
+        blob.drain(..HEADER_SIZE);
+        if crc32(&blob) != crc {
+            anyhow::bail!("{} checksum mismatch", key);
+        }
+        Ok(Some(blob))
+    }
+}
+
+// 旧版本保存背景图 GIF 的 key, 没有校验头, 启动时由 assets::migrate_background 移动到 assets 分区
+pub const BACKGROUND_KEY: &str = "background_gif";
+// 旧版本保存 hello 音效的 key, 带校验头, 启动时由 assets::migrate_hello 移动到 assets 分区
+pub const HELLO_KEY: &str = "hello";
+
+const MAGIC: &[u8; 4] = b"EKB1";
+// MAGIC, 长度, CRC32
+const HEADER_SIZE: usize = 12;
+
+// CRC-32/ISO-HDLC, 与 zlib 的 crc32 相同
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+// 分段计算 CRC32, crc 为前面数据的结果, 第一段传 0
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[cfg(target_os = "espidf")]
+impl BlobStore for esp_idf_svc::nvs::EspDefaultNvs {
+    fn get(&mut self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(len) = self.blob_len(key)? else {
+            return Ok(None);
+        };
+        let mut buf = vec![0; len];
+        Ok(self.get_blob(key, &mut buf)?.map(|data| data.to_vec()))
+    }
+
+    fn set(&mut self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        self.set_blob(key, data)?;
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        esp_idf_svc::nvs::EspDefaultNvs::remove(self, key)?;
+        Ok(())
+    }
+}
+
+// 内存里的 BlobStore, clone 出来的副本共享同一份数据
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    pub blobs: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl BlobStore for MemoryStore {
+    fn get(&mut self, key: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        Ok(self.blobs.lock().unwrap().get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, data: &[u8]) -> anyhow::Result<()> {
+        self.blobs
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> anyhow::Result<()> {
+        self.blobs.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xcbf4_3926);
+}
+
+#[test]
+fn test_store_load_checked() {
+    let mut store = MemoryStore::default();
+    assert_eq!(store.load("hello", 100).unwrap(), None);
+
+    store.save("hello", &[1, 2, 3]).unwrap();
+    assert_eq!(store.load("hello", 100).unwrap(), Some(vec![1, 2, 3]));
+    assert!(store.load("hello", 2).is_err());
+
+    // 数据损坏或不完整
+    let mut blob = store.get("hello").unwrap().unwrap();
+    blob[HEADER_SIZE] ^= 0xff;
+    store.set("hello", &blob).unwrap();
+    assert!(store.load("hello", 100).is_err());
+    store.set("hello", &blob[..blob.len() - 1]).unwrap();
+    assert!(store.load("hello", 100).is_err());
+    // 没有校验头的旧数据
+    store.set("hello", b"RIFF").unwrap();
+    assert!(store.load("hello", 100).is_err());
+
+    store.remove("hello").unwrap();
+    assert_eq!(store.load("hello", 100).unwrap(), None);
+}
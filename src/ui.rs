@@ -1,45 +1,36 @@
+use std::sync::Mutex;
+
 use embedded_graphics::{
-    framebuffer::{buffer_size, Framebuffer},
-    image::GetPixel,
-    pixelcolor::{
-        raw::{LittleEndian, RawU16},
-        Rgb565,
-    },
+    pixelcolor::{raw::RawU16, Rgb565},
     prelude::*,
-    primitives::{PrimitiveStyleBuilder, Rectangle},
+    primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle},
     text::{
         renderer::{CharacterStyle, TextRenderer},
         Alignment, Text,
     },
 };
 use embedded_text::TextBox;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::sys::EspError;
 use u8g2_fonts::U8g2TextStyle;
 
-pub type ColorFormat = Rgb565;
+#[cfg(target_os = "espidf")]
+use crate::board::{Display, DisplayController};
+use crate::hal::DisplaySink;
 
-#[cfg(feature = "boards")]
-const DISPLAY_WIDTH: usize = 240;
-#[cfg(feature = "boards")]
-const DISPLAY_HEIGHT: usize = 240;
-
-#[cfg(feature = "box")]
-const DISPLAY_WIDTH: usize = 320;
-#[cfg(feature = "box")]
-const DISPLAY_HEIGHT: usize = 240;
+pub type ColorFormat = Rgb565;
 
-fn init_spi() -> Result<(), EspError> {
+#[cfg(target_os = "espidf")]
+fn init_spi(mosi: i32, clk: i32, width: usize, height: usize) -> Result<(), EspError> {
     use esp_idf_svc::sys::*;
     const GPIO_NUM_NC: i32 = -1;
-    const DISPLAY_MOSI_PIN: i32 = 47;
-    const DISPLAY_CLK_PIN: i32 = 21;
     let mut buscfg = spi_bus_config_t::default();
-    buscfg.__bindgen_anon_1.mosi_io_num = DISPLAY_MOSI_PIN;
+    buscfg.__bindgen_anon_1.mosi_io_num = mosi;
     buscfg.__bindgen_anon_2.miso_io_num = GPIO_NUM_NC;
-    buscfg.sclk_io_num = DISPLAY_CLK_PIN;
+    buscfg.sclk_io_num = clk;
     buscfg.__bindgen_anon_3.quadwp_io_num = GPIO_NUM_NC;
     buscfg.__bindgen_anon_4.quadhd_io_num = GPIO_NUM_NC;
-    buscfg.max_transfer_sz = (DISPLAY_WIDTH * DISPLAY_HEIGHT * std::mem::size_of::<u16>()) as i32;
+    buscfg.max_transfer_sz = (width * height * std::mem::size_of::<u16>()) as i32;
     esp!(unsafe {
         spi_bus_initialize(
             spi_host_device_t_SPI3_HOST,
@@ -49,18 +40,17 @@ fn init_spi() -> Result<(), EspError> {
     })
 }
 
+#[cfg(target_os = "espidf")]
 static mut ESP_LCD_PANEL_HANDLE: esp_idf_svc::sys::esp_lcd_panel_handle_t = std::ptr::null_mut();
 
-#[cfg(feature = "boards")]
-fn init_lcd() -> Result<(), EspError> {
+#[cfg(target_os = "espidf")]
+fn init_lcd(cs: i32, dc: i32, rst: i32, invert_color: bool) -> Result<(), EspError> {
     use esp_idf_svc::sys::*;
-    const DISPLAY_CS_PIN: i32 = 41;
-    const DISPLAY_DC_PIN: i32 = 40;
     ::log::info!("Install panel IO");
     let mut panel_io: esp_lcd_panel_io_handle_t = std::ptr::null_mut();
     let mut io_config = esp_lcd_panel_io_spi_config_t::default();
-    io_config.cs_gpio_num = DISPLAY_CS_PIN;
-    io_config.dc_gpio_num = DISPLAY_DC_PIN;
+    io_config.cs_gpio_num = cs;
+    io_config.dc_gpio_num = dc;
     io_config.spi_mode = 3;
     io_config.pclk_hz = 40 * 1000 * 1000;
     io_config.trans_queue_depth = 10;
@@ -71,11 +61,10 @@ fn init_lcd() -> Result<(), EspError> {
     })?;
 
     ::log::info!("Install LCD driver");
-    const DISPLAY_RST_PIN: i32 = 45;
     let mut panel_config = esp_lcd_panel_dev_config_t::default();
     let mut panel: esp_lcd_panel_handle_t = std::ptr::null_mut();
 
-    panel_config.reset_gpio_num = DISPLAY_RST_PIN;
+    panel_config.reset_gpio_num = rst;
     panel_config.data_endian = lcd_rgb_data_endian_t_LCD_RGB_DATA_ENDIAN_LITTLE;
     panel_config.__bindgen_anon_1.rgb_ele_order = lcd_rgb_element_order_t_LCD_RGB_ELEMENT_ORDER_RGB;
     panel_config.bits_per_pixel = 16;
@@ -86,13 +75,12 @@ fn init_lcd() -> Result<(), EspError> {
     const DISPLAY_MIRROR_X: bool = false;
     const DISPLAY_MIRROR_Y: bool = false;
     const DISPLAY_SWAP_XY: bool = false;
-    const DISPLAY_INVERT_COLOR: bool = true;
 
     ::log::info!("Reset LCD panel");
     unsafe {
         esp!(esp_lcd_panel_reset(panel))?;
         esp!(esp_lcd_panel_init(panel))?;
-        esp!(esp_lcd_panel_invert_color(panel, DISPLAY_INVERT_COLOR))?;
+        esp!(esp_lcd_panel_invert_color(panel, invert_color))?;
         esp!(esp_lcd_panel_swap_xy(panel, DISPLAY_SWAP_XY))?;
         esp!(esp_lcd_panel_mirror(
             panel,
@@ -105,64 +93,183 @@ fn init_lcd() -> Result<(), EspError> {
     Ok(())
 }
 
-#[cfg(feature = "boards")]
-pub fn lcd_init() -> Result<(), EspError> {
-    init_spi()?;
-    init_lcd()?;
-    Ok(())
-}
-
-#[cfg(feature = "box")]
-pub fn lcd_init() -> Result<(), EspError> {
-    use esp_idf_svc::sys::hal_driver;
-    unsafe {
-        let config: hal_driver::lcd_cfg_t = std::mem::zeroed();
-        hal_driver::lcd_init(config);
+// 按 BoardProfile 初始化屏幕
+#[cfg(target_os = "espidf")]
+pub fn lcd_init(display: Display) -> Result<EspLcd, EspError> {
+    match display.controller {
+        DisplayController::St7789 {
+            mosi,
+            clk,
+            cs,
+            dc,
+            rst,
+            invert_color,
+        } => {
+            init_spi(mosi, clk, display.width, display.height)?;
+            init_lcd(cs, dc, rst, invert_color)?;
+        }
+        DisplayController::HalDriver => {
+            use esp_idf_svc::sys::hal_driver;
+            unsafe {
+                let config: hal_driver::lcd_cfg_t = std::mem::zeroed();
+                hal_driver::lcd_init(config);
+                ESP_LCD_PANEL_HANDLE = std::mem::transmute(hal_driver::panel_handle);
+            }
+        }
     }
-    Ok(())
+    Ok(EspLcd {
+        width: display.width,
+        height: display.height,
+    })
 }
 
+#[cfg(target_os = "espidf")]
 #[inline(always)]
 fn get_esp_lcd_panel_handle() -> esp_idf_svc::sys::esp_lcd_panel_handle_t {
-    #[cfg(feature = "boards")]
-    unsafe {
-        ESP_LCD_PANEL_HANDLE
+    unsafe { ESP_LCD_PANEL_HANDLE }
+}
+
+// 初始化完成的 LCD, 只能通过 lcd_init 获得
+#[cfg(target_os = "espidf")]
+pub struct EspLcd {
+    width: usize,
+    height: usize,
+}
+
+// 通过 C ffi, 将指定&[u8] 刷新到指定坐标域区
+#[cfg(target_os = "espidf")]
+impl DisplaySink for EspLcd {
+    fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
     }
-    #[cfg(feature = "box")]
-    unsafe {
-        std::mem::transmute(esp_idf_svc::sys::hal_driver::panel_handle)
+
+    fn flush(
+        &mut self,
+        data: &[u8],
+        x_start: i32,
+        y_start: i32,
+        x_end: i32,
+        y_end: i32,
+    ) -> anyhow::Result<()> {
+        unsafe {
+            esp_idf_svc::sys::esp!(esp_idf_svc::sys::esp_lcd_panel_draw_bitmap(
+                get_esp_lcd_panel_handle(),
+                x_start,
+                y_start,
+                x_end,
+                y_end,
+                data.as_ptr().cast(),
+            ))?;
+        }
+        Ok(())
     }
 }
-// 通过 C ffi, 将指定&[u8] 刷新到指定坐标域区
+
+// UI 刷新屏幕时使用的显示输出, 设备上是 lcd_init 返回的 EspLcd
+// 没有设置时(例如 host 上的测试)刷新直接忽略
+static DISPLAY: Mutex<Option<Box<dyn DisplaySink + Send>>> = Mutex::new(None);
+
+pub fn set_display(display: impl DisplaySink + Send + 'static) {
+    *DISPLAY.lock().unwrap() = Some(Box::new(display));
+}
+
+// 没有设置显示输出时 UI 使用的分辨率
+const DEFAULT_DISPLAY_SIZE: (usize, usize) = (240, 240);
+
+// 当前显示输出的分辨率, UI 按它创建 framebuffer 和布局
+pub fn display_size() -> (usize, usize) {
+    DISPLAY
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map_or(DEFAULT_DISPLAY_SIZE, |display| display.size())
+}
+
 pub fn flush_display(color_data: &[u8], x_start: i32, y_start: i32, x_end: i32, y_end: i32) -> i32 {
-    unsafe {
-        let e = esp_idf_svc::sys::esp_lcd_panel_draw_bitmap(
-            get_esp_lcd_panel_handle(),
-            x_start,
-            y_start,
-            x_end,
-            y_end,
-            color_data.as_ptr().cast(),
-        );
-        if e != 0 {
-            log::warn!("flush_display error: {}", e);
+    let mut display = DISPLAY.lock().unwrap();
+    let Some(display) = display.as_mut() else {
+        return 0;
+    };
+    match display.flush(color_data, x_start, y_start, x_end, y_end) {
+        Ok(()) => 0,
+        Err(e) => {
+            log::warn!("flush_display error: {:?}", e);
+            -1
+        }
+    }
+}
+
+// 运行时决定大小的 framebuffer, 像素格式为 RGB565 little endian, data() 可以直接刷新到屏幕
+pub struct FrameBuffer {
+    width: usize,
+    height: usize,
+    data: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            data: vec![0; width * height * 2],
         }
-        e
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    fn index(&self, p: Point) -> Option<usize> {
+        let (x, y) = (usize::try_from(p.x).ok()?, usize::try_from(p.y).ok()?);
+        (x < self.width && y < self.height).then_some((y * self.width + x) * 2)
+    }
+
+    pub fn pixel(&self, p: Point) -> Option<ColorFormat> {
+        let i = self.index(p)?;
+        let raw = u16::from_le_bytes([self.data[i], self.data[i + 1]]);
+        Some(RawU16::new(raw).into())
     }
 }
 
-pub fn backgroud(gif: &[u8]) -> Result<(), std::convert::Infallible> {
-    let image = tinygif::Gif::<ColorFormat>::from_slice(gif).unwrap();
+impl OriginDimensions for FrameBuffer {
+    fn size(&self) -> Size {
+        Size::new(self.width as u32, self.height as u32)
+    }
+}
+
+impl DrawTarget for FrameBuffer {
+    type Color = ColorFormat;
+    type Error = std::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(p, color) in pixels {
+            if let Some(i) = self.index(p) {
+                let raw = RawU16::from(color).into_inner();
+                self.data[i..i + 2].copy_from_slice(&raw.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self, color: Self::Color) -> Result<(), Self::Error> {
+        let raw = RawU16::from(color).into_inner().to_le_bytes();
+        for pixel in self.data.chunks_exact_mut(2) {
+            pixel.copy_from_slice(&raw);
+        }
+        Ok(())
+    }
+}
+
+pub fn backgroud(gif: &[u8]) -> anyhow::Result<()> {
+    let image = tinygif::Gif::<ColorFormat>::from_slice(gif)
+        .map_err(|e| anyhow::anyhow!("Failed to parse GIF: {:?}", e))?;
 
     // Create a new framebuffer
-    let mut display = Box::new(Framebuffer::<
-        ColorFormat,
-        _,
-        LittleEndian,
-        DISPLAY_WIDTH,
-        DISPLAY_HEIGHT,
-        { buffer_size::<ColorFormat>(DISPLAY_WIDTH, DISPLAY_HEIGHT) },
-    >::new());
+    let (width, height) = display_size();
+    let mut display = FrameBuffer::new(width, height);
 
     display.clear(ColorFormat::WHITE)?;
 
@@ -170,14 +277,8 @@ pub fn backgroud(gif: &[u8]) -> Result<(), std::convert::Infallible> {
         if !frame.is_transparent {
             display.clear(ColorFormat::WHITE)?;
         }
-        frame.draw(display.as_mut())?;
-        flush_display(
-            display.data(),
-            0,
-            0,
-            DISPLAY_WIDTH as _,
-            DISPLAY_HEIGHT as _,
-        );
+        frame.draw(&mut display)?;
+        flush_display(display.data(), 0, 0, width as _, height as _);
         let delay_ms = frame.delay_centis * 10;
         std::thread::sleep(std::time::Duration::from_millis(delay_ms as u64));
     }
@@ -270,17 +371,10 @@ pub struct UI {
     pub text: String,
     text_area: Rectangle,
     text_background: Vec<Pixel<ColorFormat>>,
+    // 不为 None 时在状态区域底部显示音量条
+    pub volume: Option<u8>,
 
-    display: Box<
-        Framebuffer<
-            ColorFormat,
-            RawU16,
-            LittleEndian,
-            DISPLAY_WIDTH,
-            DISPLAY_HEIGHT,
-            { buffer_size::<ColorFormat>(DISPLAY_WIDTH, DISPLAY_HEIGHT) },
-        >,
-    >,
+    display: FrameBuffer,
 }
 
 const COLOR_WIDTH: u32 = 2;
@@ -373,28 +467,22 @@ impl qrcode::render::Canvas for QrCanvas {
 
 impl UI {
     pub fn new(backgroud_gif: Option<&[u8]>) -> anyhow::Result<Self> {
-        // 创建 embedded_graphics 的 framebuffer
-        let mut display = Box::new(Framebuffer::<
-            ColorFormat, //rgb565
-            _,
-            LittleEndian,
-            DISPLAY_WIDTH,
-            DISPLAY_HEIGHT,
-            { buffer_size::<ColorFormat>(DISPLAY_WIDTH, DISPLAY_HEIGHT) },
-        >::new());
+        // 按屏幕的分辨率创建 framebuffer
+        let (width, height) = display_size();
+        let mut display = FrameBuffer::new(width, height);
         // 以白色填充
         display.clear(ColorFormat::WHITE).unwrap();
-        // 从左上角的坐标开始, 绘制一个矩形, 宽度为 DISPLAY_WIDTH, 高度为 32
+        // 从左上角的坐标开始, 绘制一个矩形, 宽度为屏幕宽度, 高度为 32
         // 用于表示状态区域
         let state_area = Rectangle::new(
             display.bounding_box().top_left + Point::new(0, 0),
-            Size::new(DISPLAY_WIDTH as u32, 32),
+            Size::new(width as u32, 32),
         );
-        // 在状态区域紧接着的位置, 绘制一个矩形, 宽度为 DISPLAY_WIDTH, 高度为 DISPLAY_HEIGHT - 32
+        // 在状态区域紧接着的位置, 绘制一个矩形, 宽度为屏幕宽度, 高度为屏幕高度 - 32
         // 用于表示文本区域
         let text_area = Rectangle::new(
             display.bounding_box().top_left + Point::new(0, 32),
-            Size::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32 - 32),
+            Size::new(width as u32, height as u32 - 32),
         );
         // 如果有背景图, 则绘制背景图
         // 方法是将背景图的 raw data, 通过 tinygif 解析, 然后绘制到 framebuffer(即 display 变量) 中
@@ -402,11 +490,9 @@ impl UI {
             let image = tinygif::Gif::<ColorFormat>::from_slice(gif)
                 .map_err(|e| anyhow::anyhow!("Failed to parse GIF: {:?}", e))?;
             for frame in image.frames() {
-                frame.draw(display.as_mut()).unwrap();
+                frame.draw(&mut display).unwrap();
             }
         }
-        // 将 framebuffer 转换为 ImageRaw 类型
-        let img = display.as_image();
         // 将状态区域转换为Vec<Pixel<ColorFormat>>
         let state_pixels: Vec<Pixel<ColorFormat>> = state_area
             .into_styled(
@@ -419,7 +505,7 @@ impl UI {
             .pixels() // 状态区域的pixel iter
             .map(|p| {
                 // 遍历状态区域的每个 pixel, 混入透明度?
-                if let Some(color) = img.pixel(p.0) {
+                if let Some(color) = display.pixel(p.0) {
                     Pixel(p.0, alpha_mix(color, p.1, ALPHA))
                 } else {
                     p
@@ -437,7 +523,7 @@ impl UI {
             )
             .pixels()
             .map(|p| {
-                if let Some(color) = img.pixel(p.0) {
+                if let Some(color) = display.pixel(p.0) {
                     Pixel(p.0, alpha_mix(color, p.1, ALPHA))
                 } else {
                     p
@@ -450,6 +536,7 @@ impl UI {
             state_background: state_pixels,
             text: String::new(),
             text_background: box_pixels,
+            volume: None,
             display,
             state_area,
             text_area,
@@ -460,11 +547,11 @@ impl UI {
         self.state_background
             .iter()
             .cloned()
-            .draw(self.display.as_mut())?;
+            .draw(&mut self.display)?;
         self.text_background
             .iter()
             .cloned()
-            .draw(self.display.as_mut())?;
+            .draw(&mut self.display)?;
 
         Text::with_alignment(
             &self.state,
@@ -475,7 +562,11 @@ impl UI {
             ),
             Alignment::Center,
         )
-        .draw(self.display.as_mut())?;
+        .draw(&mut self.display)?;
+
+        if let Some(volume) = self.volume {
+            self.draw_volume(volume)?;
+        }
 
         let textbox_style = embedded_text::style::TextBoxStyleBuilder::new()
             .height_mode(embedded_text::style::HeightMode::FitToText)
@@ -495,7 +586,7 @@ impl UI {
             ),
             textbox_style,
         );
-        text_box.draw(self.display.as_mut())?;
+        text_box.draw(&mut self.display)?;
 
         for i in 0..5 {
             let e = flush_area::<COLOR_WIDTH>(
@@ -517,6 +608,22 @@ impl UI {
         Ok(())
     }
 
+    // 在状态区域底部画一个 4px 高的音量条
+    fn draw_volume(&mut self, volume: u8) -> anyhow::Result<()> {
+        const BAR_HEIGHT: u32 = 4;
+        let width = self.state_area.size.width;
+        let top_left = self.state_area.top_left
+            + Point::new(0, (self.state_area.size.height - BAR_HEIGHT) as i32);
+        Rectangle::new(top_left, Size::new(width, BAR_HEIGHT))
+            .into_styled(PrimitiveStyle::with_fill(ColorFormat::CSS_DIM_GRAY))
+            .draw(&mut self.display)?;
+        let filled = width * volume.min(crate::volume::MAX) as u32 / crate::volume::MAX as u32;
+        Rectangle::new(top_left, Size::new(filled, BAR_HEIGHT))
+            .into_styled(PrimitiveStyle::with_fill(ColorFormat::CSS_LIGHT_CYAN))
+            .draw(&mut self.display)?;
+        Ok(())
+    }
+
     pub fn display_qrcode(&mut self, qr_context: &str) -> anyhow::Result<()> {
         let code = qrcode::QrCode::new(qr_context).unwrap();
         let ((width, height), code_pixel) = code
@@ -528,11 +635,11 @@ impl UI {
         self.state_background
             .iter()
             .cloned()
-            .draw(self.display.as_mut())?;
+            .draw(&mut self.display)?;
         self.text_background
             .iter()
             .cloned()
-            .draw(self.display.as_mut())?;
+            .draw(&mut self.display)?;
 
         self.display
             .cropped(&Rectangle::new(
@@ -554,7 +661,7 @@ impl UI {
             ),
             Alignment::Center,
         )
-        .draw(self.display.as_mut())?;
+        .draw(&mut self.display)?;
 
         let textbox_style = embedded_text::style::TextBoxStyleBuilder::new()
             .height_mode(embedded_text::style::HeightMode::FitToText)
@@ -574,7 +681,7 @@ impl UI {
             ),
             textbox_style,
         );
-        text_box.draw(self.display.as_mut())?;
+        text_box.draw(&mut self.display)?;
 
         for i in 0..5 {
             let e = flush_area::<COLOR_WIDTH>(
@@ -596,3 +703,26 @@ impl UI {
         Ok(())
     }
 }
+
+#[test]
+fn test_framebuffer() {
+    let mut fb = FrameBuffer::new(3, 2);
+    fb.clear(ColorFormat::WHITE).unwrap();
+    assert_eq!(fb.data(), [0xff; 12]);
+
+    Pixel(Point::new(2, 1), ColorFormat::RED)
+        .draw(&mut fb)
+        .unwrap();
+    // 超出范围的像素被忽略
+    Pixel(Point::new(3, 0), ColorFormat::RED)
+        .draw(&mut fb)
+        .unwrap();
+    assert_eq!(fb.pixel(Point::new(2, 1)), Some(ColorFormat::RED));
+    assert_eq!(fb.pixel(Point::new(1, 1)), Some(ColorFormat::WHITE));
+    assert_eq!(fb.pixel(Point::new(-1, 0)), None);
+    assert_eq!(&fb.data()[10..], [0x00, 0xf8]);
+    assert_eq!(
+        fb.bounding_box(),
+        Rectangle::new(Point::zero(), Size::new(3, 2))
+    );
+}
@@ -0,0 +1,320 @@
+// BLE 分段上传背景图的协议, 所有整数都是 little endian
+// 客户端依次写入:
+//   Start:  0x01, 总长度 u32, 整个文件的 CRC32 u32
+//   Chunk:  0x02, 序号 u16 (从 0 开始, 每段加 1, 溢出后回到 0), 数据
+//   Commit: 0x03, 检查长度和 CRC32, 通过后上传完成
+//   Abort:  0x04, 放弃当前上传
+// 每次写入后设备通过进度 characteristic 通知 progress() 的内容
+// 出错后需要重新 Start, 新的 Start 也会放弃正在进行的上传
+// 旧版本的配置页面直接写入 GIF 数据(协议版本 1), 不再支持, 会收到 InvalidFrame 错误
+// 客户端可以读取版本 characteristic 得到 PROTOCOL_VERSION, 读取失败说明是只支持版本 1 的旧固件
+
+// 分段上传的协议版本, 修改帧格式或者进度通知时需要加 1
+pub const PROTOCOL_VERSION: u8 = 2;
+
+const START: u8 = 0x01;
+const CHUNK: u8 = 0x02;
+const COMMIT: u8 = 0x03;
+const ABORT: u8 = 0x04;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frame<'a> {
+    Start { size: u32, crc32: u32 },
+    Chunk { seq: u16, data: &'a [u8] },
+    Commit,
+    Abort,
+}
+
+impl<'a> Frame<'a> {
+    pub fn parse(frame: &'a [u8]) -> Result<Self, UploadError> {
+        match frame {
+            [START, rest @ ..] if rest.len() == 8 => Ok(Frame::Start {
+                size: u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]),
+                crc32: u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]),
+            }),
+            [CHUNK, s0, s1, data @ ..] if !data.is_empty() => Ok(Frame::Chunk {
+                seq: u16::from_le_bytes([*s0, *s1]),
+                data,
+            }),
+            [COMMIT] => Ok(Frame::Commit),
+            [ABORT] => Ok(Frame::Abort),
+            _ => Err(UploadError::InvalidFrame),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Frame::Start { size, crc32 } => {
+                [&[START][..], &size.to_le_bytes(), &crc32.to_le_bytes()].concat()
+            }
+            Frame::Chunk { seq, data } => [&[CHUNK][..], &seq.to_le_bytes(), data].concat(),
+            Frame::Commit => vec![COMMIT],
+            Frame::Abort => vec![ABORT],
+        }
+    }
+}
+
+// 错误码会通过进度通知发给客户端, 不要修改已有的值
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadError {
+    InvalidFrame = 1,
+    // 没有 Start 就写入 Chunk 或 Commit
+    NotStarted = 2,
+    // Start 的长度超过限制, 或者 Chunk 超过了 Start 的长度
+    TooLarge = 3,
+    // Chunk 的序号不连续, 中间有写入丢失
+    Sequence = 4,
+    // Commit 时收到的数据比 Start 的长度短
+    Incomplete = 5,
+    Checksum = 6,
+}
+
+impl std::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            UploadError::InvalidFrame => "invalid frame",
+            UploadError::NotStarted => "upload not started",
+            UploadError::TooLarge => "upload is too large",
+            UploadError::Sequence => "chunk out of sequence",
+            UploadError::Incomplete => "upload is incomplete",
+            UploadError::Checksum => "checksum mismatch",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+// 进度通知里的状态, 不要修改已有的值
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadState {
+    Idle = 0,
+    Receiving = 1,
+    Done = 2,
+    Failed = 3,
+}
+
+pub struct Upload {
+    max_size: usize,
+    state: UploadState,
+    error: Option<UploadError>,
+    data: Vec<u8>,
+    // 已经收到的长度, 上传完成取走 data 后仍然保留
+    received: u32,
+    // Start 里的长度和 CRC32
+    size: u32,
+    crc32: u32,
+    next_seq: u16,
+}
+
+impl Upload {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            state: UploadState::Idle,
+            error: None,
+            data: Vec::new(),
+            received: 0,
+            size: 0,
+            crc32: 0,
+            next_seq: 0,
+        }
+    }
+
+    pub fn state(&self) -> UploadState {
+        self.state
+    }
+
+    // 处理一次写入, 上传完成并且校验通过时返回完整的数据
+    pub fn write(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, UploadError> {
+        let frame = match Frame::parse(frame) {
+            Ok(frame) => frame,
+            Err(e) => return self.fail(e),
+        };
+
+        match frame {
+            Frame::Start { size, crc32 } => {
+                if size == 0 {
+                    return self.fail(UploadError::InvalidFrame);
+                }
+                if size as usize > self.max_size {
+                    return self.fail(UploadError::TooLarge);
+                }
+                self.reset(UploadState::Receiving);
+                self.data.reserve_exact(size as usize);
+                self.size = size;
+                self.crc32 = crc32;
+                Ok(None)
+            }
+            Frame::Chunk { seq, data } => {
+                if self.state != UploadState::Receiving {
+                    return self.fail(UploadError::NotStarted);
+                }
+                if seq != self.next_seq {
+                    log::warn!("Expected chunk {}, got {}", self.next_seq, seq);
+                    return self.fail(UploadError::Sequence);
+                }
+                if self.data.len() + data.len() > self.size as usize {
+                    return self.fail(UploadError::TooLarge);
+                }
+                self.data.extend_from_slice(data);
+                self.received = self.data.len() as u32;
+                self.next_seq = self.next_seq.wrapping_add(1);
+                Ok(None)
+            }
+            Frame::Commit => {
+                if self.state != UploadState::Receiving {
+                    return self.fail(UploadError::NotStarted);
+                }
+                if self.data.len() != self.size as usize {
+                    return self.fail(UploadError::Incomplete);
+                }
+                if crate::store::crc32(&self.data) != self.crc32 {
+                    return self.fail(UploadError::Checksum);
+                }
+                self.state = UploadState::Done;
+                Ok(Some(std::mem::take(&mut self.data)))
+            }
+            Frame::Abort => {
+                self.reset(UploadState::Idle);
+                Ok(None)
+            }
+        }
+    }
+
+    // 进度通知: 状态 u8, 错误码 u8 (没有错误时为 0), 已收到的长度 u32, 总长度 u32
+    pub fn progress(&self) -> [u8; 10] {
+        let mut progress = [0; 10];
+        progress[0] = self.state as u8;
+        progress[1] = self.error.map_or(0, |e| e as u8);
+        progress[2..6].copy_from_slice(&self.received.to_le_bytes());
+        progress[6..10].copy_from_slice(&self.size.to_le_bytes());
+        progress
+    }
+
+    fn reset(&mut self, state: UploadState) {
+        self.state = state;
+        self.error = None;
+        self.data = Vec::new();
+        self.received = 0;
+        self.size = 0;
+        self.crc32 = 0;
+        self.next_seq = 0;
+    }
+
+    fn fail(&mut self, e: UploadError) -> Result<Option<Vec<u8>>, UploadError> {
+        self.reset(UploadState::Failed);
+        self.error = Some(e);
+        Err(e)
+    }
+}
+
+#[cfg(test)]
+fn upload_frames(data: &[u8], chunk_size: usize) -> Vec<Vec<u8>> {
+    let mut frames = vec![Frame::Start {
+        size: data.len() as u32,
+        crc32: crate::store::crc32(data),
+    }
+    .encode()];
+    for (i, chunk) in data.chunks(chunk_size).enumerate() {
+        frames.push(
+            Frame::Chunk {
+                seq: i as u16,
+                data: chunk,
+            }
+            .encode(),
+        );
+    }
+    frames.push(Frame::Commit.encode());
+    frames
+}
+
+#[test]
+fn test_upload_frames() {
+    let gif: Vec<u8> = (0..2048u32).map(|i| i as u8).collect();
+    // 最后一段正好 512 字节也能正常结束
+    for chunk_size in [512, 500, 1] {
+        let mut upload = Upload::new(4096);
+        let frames = upload_frames(&gif, chunk_size);
+        let (commit, chunks) = frames.split_last().unwrap();
+        for frame in chunks {
+            assert_eq!(upload.write(frame), Ok(None));
+        }
+        assert_eq!(upload.progress()[0], UploadState::Receiving as u8);
+        assert_eq!(upload.progress()[2..6], 2048u32.to_le_bytes());
+        assert_eq!(upload.write(commit), Ok(Some(gif.clone())));
+        assert_eq!(upload.state(), UploadState::Done);
+        assert_eq!(
+            upload.progress()[2..10],
+            [2048u32.to_le_bytes(), 2048u32.to_le_bytes()].concat()
+        );
+    }
+
+    assert_eq!(Frame::parse(&[CHUNK, 0, 0]), Err(UploadError::InvalidFrame));
+    assert_eq!(
+        Frame::parse(&[START, 0, 0, 0]),
+        Err(UploadError::InvalidFrame)
+    );
+    assert_eq!(Frame::parse(&[COMMIT, 0]), Err(UploadError::InvalidFrame));
+}
+
+#[test]
+fn test_upload_errors() {
+    let gif = b"GIF89a this is not a real gif".to_vec();
+    let frames = upload_frames(&gif, 8);
+    let check = |frames: &[Vec<u8>], e: UploadError| {
+        let mut upload = Upload::new(1024);
+        let (last, frames) = frames.split_last().unwrap();
+        for frame in frames {
+            upload.write(frame).unwrap();
+        }
+        assert_eq!(upload.write(last), Err(e));
+        assert_eq!(upload.progress()[..2], [UploadState::Failed as u8, e as u8]);
+        // 出错后需要重新 Start
+        assert_eq!(upload.write(&frames[1]), Err(UploadError::NotStarted));
+    };
+
+    // 丢失了一段
+    check(
+        &[&frames[..2], &frames[3..4]].concat(),
+        UploadError::Sequence,
+    );
+    // 少了最后一段就 Commit
+    check(
+        &[&frames[..4], &frames[5..]].concat(),
+        UploadError::Incomplete,
+    );
+    // 数据损坏
+    let mut corrupted = frames.clone();
+    corrupted[1][5] ^= 0xff;
+    check(&corrupted, UploadError::Checksum);
+    // 超过 Start 的长度
+    let mut extra = frames.clone();
+    extra[4].push(0);
+    check(&extra[..5], UploadError::TooLarge);
+    // Start 超过大小限制
+    let mut upload = Upload::new(16);
+    assert_eq!(upload.write(&frames[0]), Err(UploadError::TooLarge));
+
+    // 新的 Start 放弃正在进行的上传
+    let mut upload = Upload::new(1024);
+    upload.write(&frames[0]).unwrap();
+    upload.write(&frames[1]).unwrap();
+    for frame in &frames {
+        upload.write(frame).unwrap();
+    }
+    assert_eq!(upload.state(), UploadState::Done);
+}
+
+#[test]
+fn test_upload_raw_gif() {
+    // 旧版本的配置页面直接写入的 GIF 数据不再接收
+    let gif: Vec<u8> = b"GIF89a".iter().copied().cycle().take(1200).collect();
+    let mut upload = Upload::new(4096);
+    assert_eq!(upload.write(&gif[..512]), Err(UploadError::InvalidFrame));
+    assert_eq!(
+        upload.progress()[..2],
+        [UploadState::Failed as u8, UploadError::InvalidFrame as u8]
+    );
+    assert_eq!(upload.write(&gif[512..]), Err(UploadError::InvalidFrame));
+}
@@ -0,0 +1,63 @@
+// 扬声器音量, 0 静音, 100 最大
+// box 使用 ES8311 的硬件音量, 其他板子在写入 I2S 前用 apply_gain 调整 PCM
+
+pub const MAX: u8 = 100;
+// 第一次启动(NVS 里没有保存音量)时的音量
+pub const DEFAULT: u8 = 75;
+// 按键和语音命令每次调整的幅度
+pub const STEP: u8 = 10;
+
+pub fn up(volume: u8) -> u8 {
+    volume.saturating_add(STEP).min(MAX)
+}
+
+pub fn down(volume: u8) -> u8 {
+    volume.min(MAX).saturating_sub(STEP)
+}
+
+// 软件音量, 按 (volume / MAX)^2 缩放 16bit le PCM, 听感上比线性缩放均匀
+// MAX 时数据保持不变
+pub fn apply_gain(pcm: &mut [u8], volume: u8) {
+    let volume = volume.min(MAX) as i32;
+    if volume == MAX as i32 {
+        return;
+    }
+    // Q15 定点数
+    let gain = volume * volume * (1 << 15) / (MAX as i32 * MAX as i32);
+    for b in pcm.chunks_exact_mut(2) {
+        let sample = i16::from_le_bytes([b[0], b[1]]) as i32;
+        let sample = (sample * gain) >> 15;
+        b.copy_from_slice(&(sample as i16).to_le_bytes());
+    }
+}
+
+#[test]
+fn test_volume_step() {
+    assert_eq!(up(75), 85);
+    assert_eq!(up(95), MAX);
+    assert_eq!(up(MAX), MAX);
+    assert_eq!(down(75), 65);
+    assert_eq!(down(5), 0);
+    assert_eq!(down(0), 0);
+    // NVS 里保存了超出范围的值
+    assert_eq!(down(200), 90);
+}
+
+#[test]
+fn test_apply_gain() {
+    let pcm: Vec<u8> = [1000i16, -1000, i16::MAX, i16::MIN]
+        .iter()
+        .flat_map(|s| s.to_le_bytes())
+        .collect();
+    let samples = |volume| {
+        let mut data = pcm.clone();
+        apply_gain(&mut data, volume);
+        data.chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(samples(MAX), [1000, -1000, i16::MAX, i16::MIN]);
+    assert_eq!(samples(50), [250, -250, 8191, -8192]);
+    assert_eq!(samples(0), [0, 0, 0, 0]);
+}
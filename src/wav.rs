@@ -0,0 +1,349 @@
+use crate::resample::Resampler;
+
+// WAV(RIFF) 解析, 把 PCM WAV 转换为播放器使用的 16bit mono PCM
+// 支持 8/16/24/32bit 整数和 32bit 浮点, 声道数和采样率不同时通过 Resampler 转换
+// 数据可以分段输入, 用于 server 分块下发的 hello 音效
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WavFormat {
+    pub float: bool,
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits: u16,
+}
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+// 正常的 fmt chunk 只有 16~40 字节, 超过这个长度认为文件已损坏
+const MAX_FMT_SIZE: usize = 256;
+
+impl WavFormat {
+    fn parse(body: &[u8]) -> anyhow::Result<Self> {
+        if body.len() < 16 {
+            anyhow::bail!("Invalid fmt chunk");
+        }
+        let mut tag = u16::from_le_bytes([body[0], body[1]]);
+        // WAVE_FORMAT_EXTENSIBLE 的实际格式在 SubFormat GUID 的前两个字节
+        if tag == WAVE_FORMAT_EXTENSIBLE && body.len() >= 26 {
+            tag = u16::from_le_bytes([body[24], body[25]]);
+        }
+        let format = Self {
+            float: tag == WAVE_FORMAT_IEEE_FLOAT,
+            channels: u16::from_le_bytes([body[2], body[3]]),
+            sample_rate: u32::from_le_bytes([body[4], body[5], body[6], body[7]]),
+            bits: u16::from_le_bytes([body[14], body[15]]),
+        };
+        let supported = match tag {
+            WAVE_FORMAT_PCM => matches!(format.bits, 8 | 16 | 24 | 32),
+            WAVE_FORMAT_IEEE_FLOAT => format.bits == 32,
+            _ => false,
+        };
+        if !supported || format.channels == 0 || format.channels > 8 || format.sample_rate == 0 {
+            anyhow::bail!("Unsupported WAV format: tag {}, {:?}", tag, format);
+        }
+        Ok(format)
+    }
+
+    fn sample_size(&self) -> usize {
+        self.bits as usize / 8
+    }
+
+    // 一个采样转换为 16bit
+    fn to_i16(self, b: &[u8]) -> i16 {
+        match (self.float, self.bits) {
+            (false, 8) => (b[0] as i16 - 128) << 8,
+            (false, 16) => i16::from_le_bytes([b[0], b[1]]),
+            (false, 24) => i16::from_le_bytes([b[1], b[2]]),
+            (false, _) => i16::from_le_bytes([b[2], b[3]]),
+            (true, _) => {
+                let f = f32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+                (f.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+            }
+        }
+    }
+}
+
+enum State {
+    // 等待 RIFF 头
+    Riff,
+    // 等待下一个 chunk 的头
+    ChunkHeader,
+    // 读取 fmt chunk, 值为 chunk 的长度(含对齐)
+    Fmt(usize),
+    // 跳过不需要的 chunk, 值为剩余的字节数
+    Skip(usize),
+    // data chunk 剩余的字节数, None 表示一直到文件结束(边录边写的 WAV 长度为 0 或 0xffffffff)
+    Data(Option<usize>),
+    // data chunk 之后的内容都忽略
+    Done,
+}
+
+pub struct WavParser {
+    to_rate: u32,
+    state: State,
+    // 还不够解析的数据(头/fmt chunk/不完整的采样)
+    buf: Vec<u8>,
+    format: Option<WavFormat>,
+    resampler: Option<Resampler>,
+}
+
+impl WavParser {
+    pub fn new(to_rate: u32) -> Self {
+        Self {
+            to_rate,
+            state: State::Riff,
+            buf: Vec::new(),
+            format: None,
+            resampler: None,
+        }
+    }
+
+    pub fn format(&self) -> Option<WavFormat> {
+        self.format
+    }
+
+    // 输入一段 WAV 数据, 返回转换好的 PCM(可能为空)
+    // 返回错误后不能继续使用
+    pub fn push(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.buf.extend_from_slice(data);
+        let mut out = vec![];
+        let mut pos = 0;
+        loop {
+            let avail = &self.buf[pos..];
+            match self.state {
+                State::Riff => {
+                    if avail.len() < 12 {
+                        break;
+                    }
+                    if &avail[0..4] != b"RIFF" || &avail[8..12] != b"WAVE" {
+                        anyhow::bail!("Not a WAV file");
+                    }
+                    pos += 12;
+                    self.state = State::ChunkHeader;
+                }
+                State::ChunkHeader => {
+                    if avail.len() < 8 {
+                        break;
+                    }
+                    let size = u32::from_le_bytes([avail[4], avail[5], avail[6], avail[7]]);
+                    // chunk 按 2 字节对齐
+                    let padded = size as usize + (size as usize & 1);
+                    self.state = match &avail[0..4] {
+                        b"fmt " if padded > MAX_FMT_SIZE => {
+                            anyhow::bail!("Invalid fmt chunk size {}", size)
+                        }
+                        b"fmt " => State::Fmt(padded),
+                        b"data" => {
+                            let Some(format) = self.format else {
+                                anyhow::bail!("WAV data chunk before fmt chunk");
+                            };
+                            self.resampler = Some(Resampler::new(
+                                format.sample_rate,
+                                format.channels as u8,
+                                self.to_rate,
+                            )?);
+                            match size {
+                                0 | u32::MAX => State::Data(None),
+                                size => State::Data(Some(size as usize)),
+                            }
+                        }
+                        _ => State::Skip(padded),
+                    };
+                    pos += 8;
+                }
+                State::Fmt(n) => {
+                    if avail.len() < n {
+                        break;
+                    }
+                    self.format = Some(WavFormat::parse(&avail[..n])?);
+                    pos += n;
+                    self.state = State::ChunkHeader;
+                }
+                State::Skip(n) => {
+                    let k = n.min(avail.len());
+                    pos += k;
+                    if k < n {
+                        self.state = State::Skip(n - k);
+                        break;
+                    }
+                    self.state = State::ChunkHeader;
+                }
+                State::Data(remaining) => {
+                    let (Some(format), Some(resampler)) = (self.format, self.resampler.as_mut())
+                    else {
+                        unreachable!("data chunk without format");
+                    };
+                    let size = format.sample_size();
+                    let n = remaining.map_or(avail.len(), |r| r.min(avail.len()));
+                    let n = n - n % size;
+                    let pcm: Vec<u8> = avail[..n]
+                        .chunks_exact(size)
+                        .flat_map(|b| format.to_i16(b).to_le_bytes())
+                        .collect();
+                    out.extend(resampler.process(&pcm));
+                    pos += n;
+                    match remaining {
+                        Some(r) if r - n < size => self.state = State::Done,
+                        Some(r) => {
+                            self.state = State::Data(Some(r - n));
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+                State::Done => {
+                    pos = self.buf.len();
+                    break;
+                }
+            }
+        }
+        self.buf.drain(..pos);
+        Ok(out)
+    }
+
+    // 输入结束, 检查是否读到了 data chunk, 返回重采样剩下的最后一段 PCM
+    pub fn finish(&mut self) -> anyhow::Result<Vec<u8>> {
+        match self.state {
+            State::Data(None) | State::Done => {}
+            State::Data(Some(remaining)) => {
+                log::warn!("WAV data truncated, {} bytes missing", remaining);
+            }
+            _ => anyhow::bail!("No data chunk in WAV file"),
+        }
+        Ok(self.resampler.as_mut().map_or(vec![], Resampler::flush))
+    }
+}
+
+// 一次性转换完整的 WAV 文件
+pub fn decode(wav: &[u8], to_rate: u32) -> anyhow::Result<Vec<u8>> {
+    let mut parser = WavParser::new(to_rate);
+    let mut pcm = parser.push(wav)?;
+    pcm.extend(parser.finish()?);
+    Ok(pcm)
+}
+
+// 构造一个 WAV 文件, data 之前带一个奇数长度的 LIST chunk
+#[cfg(test)]
+fn build_wav(tag: u16, channels: u16, sample_rate: u32, bits: u16, data: &[u8]) -> Vec<u8> {
+    let mut wav = b"RIFF\0\0\0\0WAVE".to_vec();
+    wav.extend_from_slice(b"LIST");
+    wav.extend_from_slice(&3u32.to_le_bytes());
+    wav.extend_from_slice(b"abc\0");
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&tag.to_le_bytes());
+    wav.extend_from_slice(&channels.to_le_bytes());
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    let block_align = channels * bits / 8;
+    wav.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    wav.extend_from_slice(&block_align.to_le_bytes());
+    wav.extend_from_slice(&bits.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    wav.extend_from_slice(data);
+    let riff_size = (wav.len() - 8) as u32;
+    wav[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    wav
+}
+
+#[cfg(test)]
+fn samples(pcm: &[u8]) -> Vec<i16> {
+    pcm.chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect()
+}
+
+#[test]
+fn test_wav_fixtures() {
+    for wav in [
+        &include_bytes!("../assets/hello.wav")[..],
+        &include_bytes!("../assets/hello_beep.wav")[..],
+        &include_bytes!("../assets/welcome.wav")[..],
+    ] {
+        // 16kHz mono 16bit, 只去掉 RIFF 头和 LIST chunk
+        let at = wav.windows(4).position(|w| w == b"data").unwrap() + 8;
+        assert_eq!(decode(wav, 16000).unwrap(), wav[at..]);
+    }
+}
+
+#[test]
+fn test_wav_streaming() {
+    let wav = include_bytes!("../assets/hello.wav");
+    let mut parser = WavParser::new(16000);
+    let mut pcm = vec![];
+    for chunk in wav.chunks(7) {
+        pcm.extend(parser.push(chunk).unwrap());
+    }
+    pcm.extend(parser.finish().unwrap());
+    assert_eq!(
+        parser.format(),
+        Some(WavFormat {
+            float: false,
+            channels: 1,
+            sample_rate: 16000,
+            bits: 16
+        })
+    );
+    assert_eq!(pcm, decode(wav, 16000).unwrap());
+}
+
+#[test]
+fn test_wav_formats() {
+    let wav = build_wav(WAVE_FORMAT_PCM, 1, 16000, 8, &[128, 255, 0]);
+    assert_eq!(samples(&decode(&wav, 16000).unwrap()), [0, 32512, -32768]);
+
+    let wav = build_wav(WAVE_FORMAT_PCM, 1, 16000, 24, &[0xff, 0x34, 0x12]);
+    assert_eq!(samples(&decode(&wav, 16000).unwrap()), [0x1234]);
+
+    let wav = build_wav(WAVE_FORMAT_PCM, 1, 16000, 32, &[0, 0, 0x00, 0x80]);
+    assert_eq!(samples(&decode(&wav, 16000).unwrap()), [i16::MIN]);
+
+    // 立体声混成单声道
+    let data: Vec<u8> = [0.5f32, 0.5, -1.0, 0.0, 0.0, 0.0]
+        .iter()
+        .flat_map(|f| f.to_le_bytes())
+        .collect();
+    let wav = build_wav(WAVE_FORMAT_IEEE_FLOAT, 2, 16000, 32, &data);
+    assert_eq!(samples(&decode(&wav, 16000).unwrap()), [16383, -16383, 0]);
+
+    // 采样率不同时重采样
+    let wav = build_wav(WAVE_FORMAT_PCM, 1, 32000, 16, &[0; 3200]);
+    assert_eq!(decode(&wav, 16000).unwrap().len(), 1600);
+}
+
+#[test]
+fn test_wav_resample_length() {
+    // 重采样和混音不会丢掉最后一个采样, 输出 ceil(n * to / from) 个采样
+    for (rate, channels, n) in [
+        (22050, 1, 1000),
+        (8000, 1, 3),
+        (44100, 2, 441),
+        (48000, 2, 7),
+    ] {
+        let data: Vec<u8> = (0..n * channels)
+            .flat_map(|i| (i as i16 * 10).to_le_bytes())
+            .collect();
+        let wav = build_wav(WAVE_FORMAT_PCM, channels as u16, rate, 16, &data);
+        let pcm = samples(&decode(&wav, 16000).unwrap());
+        let expected = (n as u32 * 16000).div_ceil(rate) as usize;
+        assert_eq!(pcm.len(), expected, "{rate} Hz x{channels}");
+    }
+}
+
+#[test]
+fn test_wav_reject() {
+    assert!(decode(b"ID3\x03 not a wav file", 16000).is_err());
+    // ADPCM
+    let wav = build_wav(2, 1, 16000, 4, &[0; 16]);
+    assert!(decode(&wav, 16000).is_err());
+    let wav = build_wav(WAVE_FORMAT_PCM, 1, 16000, 12, &[0; 16]);
+    assert!(decode(&wav, 16000).is_err());
+    // 没有 data chunk
+    let wav = build_wav(WAVE_FORMAT_PCM, 1, 16000, 16, &[]);
+    assert!(decode(&wav[..wav.len() - 8], 16000).is_err());
+    // data 在 fmt 之前
+    let mut wav = b"RIFF\0\0\0\0WAVEdata\x02\0\0\0\0\0".to_vec();
+    wav.extend_from_slice(&build_wav(WAVE_FORMAT_PCM, 1, 16000, 16, &[])[12..]);
+    assert!(decode(&wav, 16000).is_err());
+}
@@ -1,3 +1,4 @@
+#[cfg(target_os = "espidf")]
 #[allow(unused)]
 fn print_stack_high() {
     let stack_high =
@@ -5,14 +6,70 @@ fn print_stack_high() {
     log::info!("Stack high: {}", stack_high);
 }
 
-use crate::{app::Event, protocol::ServerEvent};
+use crate::{
+    conversation::Event,
+    protocol::{AudioCodec, ClientEvent, ServerEvent, PROTOCOL_VERSION},
+};
 use futures_util::{SinkExt, StreamExt, TryFutureExt};
 use tokio_websockets::Message;
 
+// 连续多次 ping 没有收到 pong, 认为连接已经失效(半开的 TCP 连接)
+#[derive(Debug)]
+pub struct LinkDead {
+    pub missed: u32,
+}
+
+impl std::fmt::Display for LinkDead {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "WS link dead, {} pongs missed", self.missed)
+    }
+}
+
+impl std::error::Error for LinkDead {}
+
+// ping/pong 的往返时间统计
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RttStats {
+    pub pings: u32,
+    pub pongs: u32,
+    pub last: std::time::Duration,
+    pub min: std::time::Duration,
+    pub max: std::time::Duration,
+    // 指数加权平均
+    pub avg: std::time::Duration,
+}
+
+impl RttStats {
+    fn update(&mut self, rtt: std::time::Duration) {
+        if self.pongs == 0 {
+            self.min = rtt;
+            self.max = rtt;
+            self.avg = rtt;
+        } else {
+            self.min = self.min.min(rtt);
+            self.max = self.max.max(rtt);
+            self.avg = (self.avg * 7 + rtt) / 8;
+        }
+        self.last = rtt;
+        self.pongs += 1;
+    }
+}
+
 pub struct Server {
     pub uri: String,
+    // 握手时 server 接受的 codec
+    pub codecs: Vec<AudioCodec>,
     timeout: std::time::Duration,
     ws: tokio_websockets::WebSocketStream<tokio_websockets::MaybeTlsStream<tokio::net::TcpStream>>,
+
+    // keepalive: 每隔 ping_interval 发送一次 ping, 超过 max_missed 次没有 pong 则认为连接失效
+    ping_interval: std::time::Duration,
+    max_missed: u32,
+    next_ping: tokio::time::Instant,
+    // 还没有收到 pong 的 ping (序号, 发送时间)
+    ping_in_flight: Option<(u32, tokio::time::Instant)>,
+    missed: u32,
+    pub rtt: RttStats,
 }
 
 impl Server {
@@ -24,35 +81,322 @@ impl Server {
             .await?;
 
         let timeout = std::time::Duration::from_secs(30);
+        let ping_interval = std::time::Duration::from_secs(5);
+
+        Ok(Self {
+            uri,
+            codecs: vec![],
+            timeout,
+            ws,
+            ping_interval,
+            max_missed: 3,
+            next_ping: tokio::time::Instant::now() + ping_interval,
+            ping_in_flight: None,
+            missed: 0,
+            rtt: RttStats::default(),
+        })
+    }
 
-        Ok(Self { uri, timeout, ws })
+    // 连接后的第一件事: 发送 DeviceInfo, 并等待 server 的 HandshakeAccept/HandshakeReject
+    // 版本或能力不匹配时返回错误, 错误信息可以直接显示在 UI 上
+    pub async fn handshake(&mut self, info: ClientEvent) -> anyhow::Result<()> {
+        self.send(info).await?;
+
+        let evt = tokio::time::timeout(self.timeout, self.recv())
+            .await
+            .map_err(|_| anyhow::anyhow!("Handshake timeout"))??;
+
+        match evt {
+            Event::ServerEvent(ServerEvent::HandshakeAccept {
+                protocol_version,
+                codecs,
+            }) => {
+                log::info!(
+                    "Handshake accepted, protocol version: {}, codecs: {:?}",
+                    protocol_version,
+                    codecs
+                );
+                if protocol_version != PROTOCOL_VERSION {
+                    anyhow::bail!(
+                        "Rejected: protocol version {} != {}",
+                        protocol_version,
+                        PROTOCOL_VERSION
+                    );
+                }
+                if codecs.is_empty() {
+                    anyhow::bail!("Rejected: no common audio codec");
+                }
+                self.codecs = codecs;
+                Ok(())
+            }
+            Event::ServerEvent(ServerEvent::HandshakeReject { reason }) => {
+                log::error!("Handshake rejected: {}", reason);
+                Err(anyhow::anyhow!("Rejected: {}", reason))
+            }
+            evt => Err(anyhow::anyhow!("Unexpected handshake reply: {:?}", evt)),
+        }
     }
 
     pub fn set_timeout(&mut self, timeout: std::time::Duration) {
         self.timeout = timeout;
     }
-    // 用于向 server 发送 msg
-    pub async fn send(&mut self, msg: Message) -> anyhow::Result<()> {
+
+    // 设置 ping 的间隔, 以及最多允许连续丢失多少个 pong
+    pub fn set_keepalive(&mut self, interval: std::time::Duration, max_missed: u32) {
+        self.ping_interval = interval;
+        self.max_missed = max_missed;
+        self.next_ping = tokio::time::Instant::now() + interval;
+    }
+
+    // 到了 ping 的时间: 检查上一个 ping 是否已经收到 pong, 然后发送新的 ping
+    async fn keepalive(&mut self) -> anyhow::Result<()> {
+        let now = tokio::time::Instant::now();
+        self.next_ping = now + self.ping_interval;
+
+        if let Some((seq, _)) = self.ping_in_flight {
+            self.missed += 1;
+            log::warn!("Pong {} missed ({}/{})", seq, self.missed, self.max_missed);
+            if self.missed >= self.max_missed {
+                return Err(LinkDead {
+                    missed: self.missed,
+                }
+                .into());
+            }
+        }
+
+        let seq = self.rtt.pings;
+        self.ping_in_flight = Some((seq, now));
+        self.rtt.pings += 1;
+        let msg = Message::ping(bytes::Bytes::copy_from_slice(&seq.to_be_bytes()));
+        tokio::time::timeout(self.timeout, self.ws.send(msg))
+            .map_err(|_| anyhow::anyhow!("Timeout sending ping"))
+            .await??;
+        Ok(())
+    }
+
+    fn on_pong(&mut self, payload: &[u8]) {
+        let Some((seq, sent_at)) = self.ping_in_flight else {
+            return;
+        };
+        // 只认最新一个 ping 的 pong, 迟到的 pong 说明链路很慢, 但还活着
+        self.missed = 0;
+        if payload == seq.to_be_bytes() {
+            self.ping_in_flight = None;
+            self.rtt.update(sent_at.elapsed());
+            log::debug!("RTT: {:?}", self.rtt);
+        }
+    }
+    // 用于向 server 发送 event, 与 ServerEvent 一样使用 rmp-serde 编码为 binary msg
+    pub async fn send(&mut self, evt: ClientEvent) -> anyhow::Result<()> {
+        let msg = Message::binary(bytes::Bytes::from(evt.to_vec()?));
         tokio::time::timeout(self.timeout, self.ws.send(msg))
             .map_err(|_| anyhow::anyhow!("Timeout sending message"))
             .await??;
         Ok(())
     }
     // 用于接收 server 的事件
+    // 只有连接断开时才返回 Err, 无法解析的消息会被跳过
+    // 等待期间会定时发送 ping, 连续丢失 pong 时返回 LinkDead
     pub async fn recv(&mut self) -> anyhow::Result<Event> {
-        let msg = self
-            .ws
-            .next()
-            .await
-            .ok_or_else(|| anyhow::anyhow!("WS channel closed"))??;
+        loop {
+            let msg = tokio::select! {
+                msg = self.ws.next() => {
+                    msg.ok_or_else(|| anyhow::anyhow!("WS channel closed"))??
+                }
+                _ = tokio::time::sleep_until(self.next_ping) => {
+                    self.keepalive().await?;
+                    continue;
+                }
+            };
 
-        if msg.is_binary() {
-            let payload = msg.into_payload();
-            let evt = rmp_serde::from_slice::<ServerEvent>(&payload)
-                .map_err(|e| anyhow::anyhow!("Failed to deserialize binary data: {}", e))?;
-            Ok(Event::ServerEvent(evt))
-        } else {
-            Err(anyhow::anyhow!("Invalid message type"))
+            if msg.is_binary() {
+                let payload = msg.into_payload();
+                match rmp_serde::from_slice::<ServerEvent>(&payload) {
+                    Ok(evt) => return Ok(Event::ServerEvent(evt)),
+                    Err(e) => log::error!("Failed to deserialize binary data: {}", e),
+                }
+            } else if msg.is_pong() {
+                self.on_pong(&msg.into_payload());
+            } else if msg.is_ping() {
+                // tokio_websockets 会自动回复 pong
+                log::debug!("Received ping");
+            } else if msg.is_close() {
+                return Err(anyhow::anyhow!("WS channel closed by server"));
+            } else {
+                log::warn!("Invalid message type");
+            }
+        }
+    }
+}
+
+// 重连时使用的指数退避, 每次等待 base * 2^n (不超过 max), 并加入随机抖动
+// 避免大量设备在 server 重启后同时重连
+pub struct Backoff {
+    base: std::time::Duration,
+    max: std::time::Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: std::time::Duration, max: std::time::Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+        }
+    }
+
+    // 已经尝试的次数
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    // 返回下一次重连前需要等待的时间, 取值范围是 [delay/2, delay]
+    pub fn next_delay(&mut self) -> std::time::Duration {
+        use rand::Rng;
+
+        let delay = self
+            .base
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(self.max);
+        self.attempt += 1;
+
+        let half = delay / 2;
+        let jitter = rand::thread_rng().gen_range(0..=half.as_millis() as u64);
+        half + std::time::Duration::from_millis(jitter)
+    }
+}
+
+// 对 Server 的封装, 连接断开后可以重新连接并握手, 而不需要重启设备
+pub struct ReconnectingServer {
+    server: Server,
+    device_info: ClientEvent,
+    // 最近一次发送失败的原因, 下一次 recv 时返回给调用者
+    lost: Option<anyhow::Error>,
+    pub backoff: Backoff,
+    pub max_attempts: u32,
+    // 一次重连(建立连接和握手)的超时, 半开的 TCP 连接或者不回复握手的 server 不会卡住重连
+    pub connect_timeout: std::time::Duration,
+}
+
+impl ReconnectingServer {
+    pub fn new(server: Server, device_info: ClientEvent) -> Self {
+        Self {
+            server,
+            device_info,
+            lost: None,
+            backoff: Backoff::new(
+                std::time::Duration::from_millis(500),
+                std::time::Duration::from_secs(30),
+            ),
+            max_attempts: 20,
+            connect_timeout: std::time::Duration::from_secs(10),
+        }
+    }
+
+    pub fn codecs(&self) -> &[AudioCodec] {
+        &self.server.codecs
+    }
+
+    pub fn rtt(&self) -> RttStats {
+        self.server.rtt
+    }
+
+    // 发送失败时不直接返回错误, 而是记录下来由 recv 统一上报
+    // 这样调用者只需要在 recv 处处理断线重连
+    pub async fn send(&mut self, evt: ClientEvent) -> anyhow::Result<()> {
+        if self.lost.is_some() {
+            log::debug!("Server link lost, drop event");
+            return Ok(());
+        }
+        if let Err(e) = self.server.send(evt).await {
+            log::error!("Failed to send event: {:?}", e);
+            self.lost = Some(e);
         }
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> anyhow::Result<Event> {
+        if let Some(e) = self.lost.take() {
+            return Err(e);
+        }
+        self.server.recv().await
     }
+
+    // 重新连接并握手, 成功后重置退避计数, 超时算作一次失败的尝试
+    pub async fn reconnect(&mut self) -> anyhow::Result<()> {
+        let connect = async {
+            let mut server = Server::new(self.server.uri.clone()).await?;
+            server.set_timeout(self.server.timeout);
+            server.set_keepalive(self.server.ping_interval, self.server.max_missed);
+            server.handshake(self.device_info.clone()).await?;
+            anyhow::Ok(server)
+        };
+        let server = tokio::time::timeout(self.connect_timeout, connect)
+            .await
+            .map_err(|_| anyhow::anyhow!("Reconnect timeout after {:?}", self.connect_timeout))??;
+        self.server = server;
+        self.lost = None;
+        self.backoff.reset();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_backoff() {
+    let mut backoff = Backoff::new(
+        std::time::Duration::from_millis(500),
+        std::time::Duration::from_secs(30),
+    );
+    let mut max_delay = std::time::Duration::from_millis(500);
+    for _ in 0..100 {
+        let delay = backoff.next_delay();
+        assert!(delay >= max_delay / 2 && delay <= max_delay);
+        max_delay = (max_delay * 2).min(std::time::Duration::from_secs(30));
+    }
+    assert_eq!(backoff.attempt(), 100);
+
+    backoff.reset();
+    assert_eq!(backoff.attempt(), 0);
+    assert!(backoff.next_delay() <= std::time::Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn test_keepalive_dead_link() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (stop_tx, stop_rx) = tokio::sync::oneshot::channel::<()>();
+
+    // 一个会回复 pong 的 server, 收到 stop 后不再读取数据, 模拟半开的连接
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_websockets::ServerBuilder::new()
+            .accept(stream)
+            .await
+            .unwrap();
+        tokio::select! {
+            _ = async { while let Some(Ok(_)) = ws.next().await {} } => {}
+            _ = stop_rx => {}
+        }
+        std::future::pending::<()>().await;
+    });
+
+    let mut server = Server::new(format!("ws://{}/", addr)).await.unwrap();
+    server.set_keepalive(std::time::Duration::from_millis(50), 3);
+
+    // server 正常回复 pong 时, recv 一直等待
+    let r = tokio::time::timeout(std::time::Duration::from_millis(300), server.recv()).await;
+    assert!(r.is_err());
+    assert!(server.rtt.pongs > 0);
+
+    let _ = stop_tx.send(());
+    let e = tokio::time::timeout(std::time::Duration::from_secs(2), server.recv())
+        .await
+        .expect("dead link not detected")
+        .unwrap_err();
+    assert_eq!(e.downcast_ref::<LinkDead>().unwrap().missed, 3);
 }